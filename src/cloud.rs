@@ -1,30 +1,66 @@
+pub mod download;
 pub mod error;
+pub mod upload;
+pub mod verify;
 
-use std::{fs::{self, File}, io::{self, ErrorKind, Read, Write}, thread, path::{Path, PathBuf}, time::Duration, cell::RefCell};
-use crate::file::{Options as SeparationOptions,file_separation::{EncodeErrors, SeparationFile}, *};
+use std::{collections::HashMap, fs::{self, File}, io::{self, ErrorKind, Read, Seek, SeekFrom, Write}, thread, path::{Path, PathBuf}, time::Duration, cell::RefCell, sync::Arc};
+use crate::file::{Options as SeparationOptions,file_separation::EncodeErrors, *};
+
+use futures::stream::{self, StreamExt};
 
 use self::error::CloudError;
-use crate::CloudBackend;
+use crate::AsyncCloudBackend;
 use crate::vfs::*;
 use crate::vfs::error::VFSError;
 
 
+/// Опции облака
 #[derive(Debug, Clone)]
-struct CloudOptions {
-    work_dir: PathBuf
+pub struct CloudOptions {
+    /// Рабочая папка, в которой создаются и собираются части файлов
+    pub work_dir: PathBuf,
+    /// Максимальное кол-во одновременно загружаемых или скачиваемых частей,
+    /// дополнительно ограничивается возможностями хранилища
+    pub parallelism: usize,
+    /// Опции разделения файлов, *path_for_save* заменяется рабочей папкой.
+    /// Если *part_size* не задан, размер части подбирается по возможностям хранилища
+    pub file_options: SeparationOptions,
+    /// Файл, в котором хранится виртуальная файловая система
+    pub vfs_path: PathBuf,
+}
+
+impl Default for CloudOptions {
+    fn default() -> Self {
+        Self {
+            work_dir: PathBuf::from("./td/file/documents/"),
+            parallelism: 4,
+            file_options: SeparationOptions::default(),
+            vfs_path: PathBuf::from("vfs.json"),
+        }
+    }
 }
 
+/// Облако поверх хранилища *T*.
+/// Асинхронные методы должны выполняться внутри *tokio* runtime
 #[derive(Debug, Clone)]
-pub struct Cloud<T: CloudBackend> {
+pub struct Cloud<T: AsyncCloudBackend> {
     fs: RefCell<VirtualFileSystem>,
-    backend: T,
+    backend: Arc<T>,
     option: CloudOptions,
 }
 
-impl<T: CloudBackend> Cloud<T> {
+impl<T: AsyncCloudBackend> Cloud<T> {
     pub fn new() -> Self {
+        Self::with_backend(
+            T::create(io::stdin(), io::stdout()),
+            CloudOptions::default()
+        )
+    }
 
-        let try_open_vfs = File::open("vfs.json");
+    /// Создает облако поверх уже созданного хранилища
+    pub fn with_backend(backend: T, option: CloudOptions) -> Self {
+
+        let try_open_vfs = File::open(&option.vfs_path);
 
         let vfs_from_backup =
             match try_open_vfs {
@@ -36,15 +72,79 @@ impl<T: CloudBackend> Cloud<T> {
                 }
             };
 
+        // Реплицируемое хранилище узнает, на каких репликах лежат файлы
+        for file_path in vfs_from_backup.file_paths() {
+            if let Ok(v_file) = vfs_from_backup.get_file(&file_path) {
+                for (file_name, replicas) in &v_file.replicas {
+                    backend.restore_replicas(file_name, replicas);
+                }
+            }
+        }
+
         Cloud {
             fs: RefCell::new(vfs_from_backup),
-            backend: T::create(io::stdin(), io::stdout()),
-            option: CloudOptions {
-                work_dir: PathBuf::from("./td/file/documents/")
-            },
+            backend: Arc::new(backend),
+            option,
         }
     }
 
+    /// Хранилище, поверх которого работает облако
+    pub fn backend(&self) -> &T {
+        &self.backend
+    }
+
+    /// Сохраняет виртуальную файловую систему в *vfs_path*
+    fn save_vfs(&self) -> io::Result<()> {
+        self.fs.borrow().save_vfs_to(&self.option.vfs_path)
+    }
+
+    /// Кол-во одновременных операций с хранилищем
+    fn parallelism(&self) -> usize {
+        self.option.parallelism
+            .min(self.backend.capabilities().max_concurrent_transfers)
+            .max(1)
+    }
+
+    /// Подбирает опции разделения под ограничения хранилища.
+    /// Часть вместе с хешем и заголовком не превышает максимальный размер файла в хранилище,
+    /// а если частей не хватает для всего файла, размер части увеличивается
+    fn separation_options(&self, file_path: &Path) -> Result<SeparationOptions, CloudError> {
+        let capabilities = self.backend.capabilities();
+        let file_options = &self.option.file_options;
+
+        let max_part_size = capabilities.max_object_size.saturating_sub(PART_HASH_LEN + part_header::HEADER_LEN);
+
+        // При разбиении по содержимому части бывают в 16 раз меньше *part_size*
+        let min_part_ratio = match file_options.content_defined_chunking {
+            Some(true) => 16,
+            _ => 1,
+        };
+
+        let max_count_parts = file_options.max_count_parts().max(1);
+
+        let file_len = fs::metadata(file_path)?.len();
+        let min_part_size = file_len.div_ceil(max_count_parts as u64) * min_part_ratio;
+
+        let part_size = file_options.part_size
+            .map(|part_size| part_size as u64)
+            .unwrap_or(capabilities.preferred_part_size)
+            .max(min_part_size)
+            .min(max_part_size)
+            .max(1);
+
+        let count_parts = file_len.div_ceil(part_size) as usize;
+
+        if count_parts > max_count_parts {
+            return Err(EncodeErrors::TooManyParts { count_parts, max_count_parts }.into());
+        }
+
+        Ok(SeparationOptions {
+            path_for_save: Some(self.option.work_dir.clone()),
+            part_size: Some(part_size as usize),
+            ..file_options.clone()
+        })
+    }
+
     /// Получить файл из виртуальной файловой системы, *CloudError* в обратном случае
     pub fn get_file(&self, path: &Path) -> Result<VFSFile, CloudError> {
         self.fs
@@ -63,28 +163,40 @@ impl<T: CloudBackend> Cloud<T> {
             .map_err(|err| err.into())
     }
 
-    /// Добавляет файл в вирутальную файловую систему
-    fn add_file(&self, separation_file: &SeparationFile, virtual_path: &Path) -> Result<(), VFSError> {
-        let parts_name = separation_file.parts
+    /// Реплики хранилища, на которых лежат части и сборочный файл
+    fn collect_replicas(&self, parts_name: &[String], metafile_name: &String) -> HashMap<String, Vec<String>> {
+        parts_name
             .iter()
-            .map(|part| part.part_file_name.clone())
-            .collect::<Vec<String>>();
+            .chain([metafile_name])
+            .map(|file_name| (file_name.clone(), self.backend.replicas_of(file_name)))
+            .filter(|(_, replicas)| !replicas.is_empty())
+            .collect()
+    }
 
-        let metafile_name = separation_file.metafile.clone();
+    /// Восстанавливает части и сборочный файл на отстающих репликах хранилища
+    pub async fn repair_replicas(&self, virtual_path: &Path) -> Result<(), CloudError> {
 
-        let v_file = VFSFile {
-            name: separation_file.filename.clone(),
-            extension: separation_file.file_extension.clone(),
-            build_metafile: metafile_name,
-            parts_name,
-            metadata: Default::default(),
-        };
+        let v_file = self.get_file(virtual_path)?;
+
+        fs::create_dir_all(&self.option.work_dir)?;
 
-        let res = self.fs.borrow_mut().add_file(virtual_path, v_file);
+        let all_parts_name = unique_chunks(&[v_file.chunks.as_slice(), v_file.parity_parts_name.as_slice(), v_file.manifest_part.as_slice()].concat())
+            .into_iter()
+            .cloned()
+            .collect::<Vec<String>>();
 
-        self.fs.borrow().save_vfs().unwrap();
+        let repair_results = stream::iter(all_parts_name.iter().chain([&v_file.build_metafile]))
+            .map(|file_name| self.backend.clone().repair_file(self.option.work_dir.join(file_name)))
+            .buffer_unordered(self.parallelism())
+            .collect::<Vec<_>>()
+            .await;
 
-        return res;
+        let replicas = self.collect_replicas(&all_parts_name, &v_file.build_metafile);
+
+        self.fs.borrow_mut().get_mut_file(virtual_path)?.replicas = replicas;
+        self.save_vfs()?;
+
+        repair_results.into_iter().collect()
     }
 
     /// Добавляет папку в вирутальную файловую систему
@@ -108,7 +220,7 @@ impl<T: CloudBackend> Cloud<T> {
             .remove_node(path_file)
             .map_err(|e| e.into());
 
-        self.fs.borrow().save_vfs().unwrap();
+        self.save_vfs().unwrap();
 
         return res;
     }
@@ -120,71 +232,186 @@ impl<T: CloudBackend> Cloud<T> {
             .remove_node(path_file)
             .map_err(|e| e.into());
 
-        self.fs.borrow().save_vfs().unwrap();
+        self.save_vfs().unwrap();
 
         return res;
     }
 
-    /// Загружает файл в облако
+    /// Загружает файл в облако. Ход загрузки записывается в журнал в рабочей папке,
+    /// прерванную загрузку продолжает *resume_uploads*
     pub async fn async_upload_file(&self, file_path: &PathBuf, virtual_path: &Path) -> Result<(), CloudError> {
 
-        let options = SeparationOptions {
-            path_for_save: Some(self.option.work_dir.clone()),
-            count_parts: None,
-            part_size: None,
-            compressed: None,
-        };
+        fs::create_dir_all(&self.option.work_dir)?;
+
+        let options = self.separation_options(file_path)?;
 
         let separation_file =
             dbg!(file_separation::encode_file(dbg!(file_path), options)?);
 
-        self.add_file(&separation_file, virtual_path)?;
+        let journal = upload::UploadJournal::new(&separation_file, file_path, virtual_path);
 
-        for part_file in &separation_file.parts {
+        // Проверка до загрузки частей, чтобы не загружать файл, который не попадет в VFS
+        self.get_folder(virtual_path)?.check_new_file(&journal.to_vfs_file())?;
 
-            let mut part_path = self.option.work_dir.clone();
-            part_path.push(&part_file.part_file_name);
+        self.complete_upload(journal).await
+    }
 
-            self.backend.upload_file(&part_path)?;
-        }
+    /// Удаляет из облака уже загруженные части неудавшейся загрузки
+    async fn rollback_upload(&self, uploaded_files: Vec<PathBuf>) {
+        stream::iter(uploaded_files)
+            .for_each_concurrent(self.parallelism(), |file_path| async move {
+                if let Err(e) = self.backend.clone().remove_file(file_path.clone()).await {
+                    println!("Не удалось удалить {} после ошибки загрузки: {:?}", file_path.display(), e);
+                }
+            })
+            .await;
+    }
 
-        let mut metafile_path = self.option.work_dir.clone();
-        metafile_path.push(&separation_file.metafile);
+    /// Скачивает файл из облака. Части, которые уже лежат в рабочей папке и проходят проверку, не скачиваются,
+    /// а недокачанные части докачиваются, если хранилище отдает диапазоны. Файл собирается по мере скачивания частей
+    pub async fn async_download_file(&self, virtual_path: &Path) -> Result<PathBuf, CloudError> {
+
+        let v_file = self.get_file(virtual_path)?;
+
+        fs::create_dir_all(&self.option.work_dir)?;
+
+        let composite_file = self.fetch_metafile(&v_file.build_metafile).await?;
 
-        self.backend.upload_file(&metafile_path)?;
+        let output_path = self.option.work_dir.join(v_file.os_file_name());
 
-        self.fs.borrow().save_vfs().unwrap();
-        return Ok(());
+        self.download_and_assemble(&v_file, &composite_file, &output_path).await?;
+
+        Ok(output_path)
     }
 
-    /// Скачивает файл из облака
-    pub async fn async_download_file(&self, virtual_path: &Path) -> Result<PathBuf, CloudError> {
+    /// Читает *len* байтов файла начиная с *offset*, скачивая только части, в которые попадает диапазон.
+    /// Если хранилище отдает диапазоны, у несжатых частей скачиваются только нужные байты.
+    /// Потерянные части при чтении диапазона не восстанавливаются, а файлы, загруженные
+    /// до появления длин частей в сборочном файле, скачиваются и собираются целиком
+    pub async fn read_range(&self, virtual_path: &Path, offset: u64, len: u64) -> Result<Vec<u8>, CloudError> {
 
-        let v_fs = self.fs.borrow();
-        let v_file = v_fs.get_file(virtual_path)?;
+        let v_file = self.get_file(virtual_path)?;
 
-        for part in &v_file.parts_name {
+        fs::create_dir_all(&self.option.work_dir)?;
 
-            let part_path = format!("{}{}", self.option.work_dir.display(), part);
-            let _ = self.backend.download_file(Path::new(&part_path)).unwrap();
-        }
-        let _ = self.backend.download_file(Path::new(&v_file.build_metafile)).unwrap();
+        let metafile_path = self.option.work_dir.join(&v_file.build_metafile);
+        self.backend.clone().download_file(metafile_path.clone()).await?;
+
+        let composite_file = file_assembly::read_metafile(
+            &metafile_path,
+            self.option.file_options.passphrase.as_deref()
+        )?;
 
-        let metafile_path = format!("{}{}", self.option.work_dir.display(), v_file.build_metafile);
+        let Some(ranges) = file_assembly::part_ranges(&composite_file, offset, len) else {
+            let file_path = self.async_download_file(virtual_path).await?;
 
-        //let metafile_path = format!("{}{}", self.option.work_dir.display(), v_file.build_metafile);
-        let output_file = file_assembly::decode_file(
-            &PathBuf::from(&metafile_path),
-            PathBuf::from(&self.option.work_dir)
-        ).unwrap();
+            let mut file = File::open(file_path)?;
+            file.seek(SeekFrom::Start(offset))?;
 
-        self.fs.borrow().save_vfs().unwrap();
+            let mut bytes = vec![];
+            file.take(len).read_to_end(&mut bytes)?;
 
-        Ok(PathBuf::from(format!(
-            "{}{}.{}",
-            self.option.work_dir.display(),
-            v_file.name,
-            v_file.extension
-        )))
+            return Ok(bytes);
+        };
+
+        let ranged_reads = self.backend.capabilities().ranged_reads;
+        let composite_file = &composite_file;
+
+        // Диапазоны скачиваются параллельно, но собираются в порядке частей
+        let range_bytes = stream::iter(ranges)
+            .map(|range| {
+                let backend = self.backend.clone();
+                let part_path = self.option.work_dir.join(&composite_file.parts[range.part_ind].part_file_name);
+
+                async move {
+                    let mut bytes = Vec::with_capacity(range.len as usize);
+
+                    match file_assembly::stored_range(composite_file, &range).filter(|_| ranged_reads) {
+                        Some((stored_offset, stored_len)) => {
+                            let stored_bytes = backend.download_file_range(part_path, stored_offset, stored_len).await?;
+                            file_assembly::decode_stored_range(&stored_bytes, composite_file, &range, &mut bytes)?;
+                        },
+                        None => {
+                            backend.download_file(part_path).await?;
+                            file_assembly::decode_part_range(&self.option.work_dir, composite_file, &range, &mut bytes)?;
+                        },
+                    }
+
+                    Ok::<Vec<u8>, CloudError>(bytes)
+                }
+            })
+            .buffered(self.parallelism())
+            .collect::<Vec<_>>()
+            .await;
+
+        range_bytes
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .map(|range_bytes| range_bytes.concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BackendCapabilities;
+    use crate::memory_backend::InMemoryBackend;
+    use crate::test_utils::{block_on, cloud_options, compressible_data, temp_dir, test_data};
+
+    const FILE_LEN: usize = 300_000;
+
+    /// Диапазоны внутри сегмента шифрования, через границы сегментов и частей, за концом файла и пустые
+    const RANGES: [(u64, u64); 10] = [
+        (1_000, 5_000),
+        (65_000, 2_000),
+        (130_000, 3_000),
+        (100_000, 200_000),
+        (299_000, 5_000),
+        (FILE_LEN as u64, 10),
+        (400_000, 10),
+        (10_000, 0),
+        (0, u64::MAX),
+        (u64::MAX, 1),
+    ];
+
+    #[test]
+    fn read_range_matches_original_bytes() {
+        for (passphrase, compressed) in [(None, false), (Some("passphrase"), false), (None, true), (Some("passphrase"), true)] {
+            let data = if compressed { compressible_data(FILE_LEN) } else { test_data(FILE_LEN) };
+
+            for ranged_reads in [false, true] {
+                let dir = temp_dir("read_range");
+                fs::write(dir.join("data.bin"), &data).unwrap();
+
+                let backend = InMemoryBackend::new();
+                backend.set_capabilities(BackendCapabilities { ranged_reads, ..Default::default() });
+
+                let cloud = Cloud::with_backend(backend, cloud_options(&dir, SeparationOptions {
+                    part_size: Some(131_072),
+                    passphrase: passphrase.map(str::to_string),
+                    compressed: Some(compressed),
+                    ..Default::default()
+                }));
+
+                block_on(async {
+                    cloud.async_upload_file(&dir.join("data.bin"), Path::new("fs:")).await.unwrap();
+
+                    for (offset, len) in RANGES {
+                        let bytes = cloud.read_range(Path::new("fs:/data.bin"), offset, len).await.unwrap();
+
+                        let start = offset.min(FILE_LEN as u64) as usize;
+                        let end = offset.saturating_add(len).min(FILE_LEN as u64) as usize;
+
+                        assert!(
+                            bytes == data[start..end],
+                            "Диапазон {}+{} прочитан с ошибкой: шифрование {}, сжатие {}, диапазоны {}",
+                            offset, len, passphrase.is_some(), compressed, ranged_reads
+                        );
+                    }
+                });
+
+                fs::remove_dir_all(dir).unwrap();
+            }
+        }
     }
 }
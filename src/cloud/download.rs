@@ -0,0 +1,391 @@
+use std::{collections::HashSet, fs::{self, File}, io::{BufWriter, Write}, path::Path};
+
+use futures::stream::{self, StreamExt};
+
+use super::Cloud;
+use super::error::CloudError;
+use crate::AsyncCloudBackend;
+use crate::file::{CompositeFile, FilePart, Options as SeparationOptions, metadata, file_assembly::{self, PartAssembler}};
+use crate::vfs::VFSFile;
+
+/// Размер диапазона, которым докачиваются части из хранилища с диапазонами.
+/// При обрыве теряется не больше одного диапазона
+const DOWNLOAD_SEGMENT_SIZE: u64 = 8_388_608;
+
+impl<T: AsyncCloudBackend> Cloud<T> {
+
+    /// Сборочный файл из рабочей папки, если он цел и читается, иначе скачанный заново
+    pub(super) async fn fetch_metafile(&self, metafile_name: &str) -> Result<CompositeFile, CloudError> {
+
+        let metafile_path = self.option.work_dir.join(metafile_name);
+        let passphrase = self.option.file_options.passphrase.as_deref();
+
+        if file_assembly::check_metafile(&metafile_path) {
+            if let Ok(composite_file) = file_assembly::read_metafile(&metafile_path, passphrase) {
+                return Ok(composite_file);
+            }
+        }
+
+        self.backend.clone().download_file(metafile_path.clone()).await?;
+
+        Ok(file_assembly::read_metafile(&metafile_path, passphrase)?)
+    }
+
+    /// Скачивание частей, которых нет в рабочей папке, и сборка файла в *output_path* по мере скачивания.
+    /// Если часть не скачалась или испорчена, файл собирается после скачивания паритетных частей
+    pub(super) async fn download_and_assemble(
+        &self,
+        v_file: &VFSFile,
+        composite_file: &CompositeFile,
+        output_path: &Path
+    ) -> Result<(), CloudError> {
+
+        let parts_folder = &self.option.work_dir;
+
+        // Недособранный файл не должен выглядеть как собранный
+        let partial_path = parts_folder.join(format!("{}.partial", v_file.build_metafile));
+        let mut assembler = Some(PartAssembler::new(parts_folder, composite_file, BufWriter::new(File::create(&partial_path)?)));
+
+        let unique_parts = unique_parts(&composite_file.parts);
+
+        let mut downloads = stream::iter(unique_parts)
+            .map(|part| async move { (part, self.fetch_part(composite_file, part).await) })
+            .buffered(self.parallelism());
+
+        let mut ready_parts = HashSet::new();
+        let mut download_errors = vec![];
+
+        while let Some((part, res)) = downloads.next().await {
+            match res {
+                Ok(()) => {
+                    ready_parts.insert(part.part_file_name.as_str());
+                },
+                Err(e) => download_errors.push(e),
+            }
+
+            // Части записываются по порядку, как только скачаны все предыдущие
+            if let Some(part_assembler) = assembler.as_mut() {
+                if let Err(e) = append_ready_parts(part_assembler, &ready_parts) {
+                    println!("Часть не прошла проверку, файл будет собран после восстановления => {:?}", e);
+                    assembler = None;
+                }
+            }
+        }
+
+        drop(downloads);
+
+        if let Some(part_assembler) = assembler.filter(|part_assembler| part_assembler.next_part().is_none()) {
+            let res = part_assembler
+                .finish()
+                .map_err(CloudError::from)
+                .and_then(|output| output.into_inner().map(drop).map_err(|e| e.into_error().into()));
+
+            if let Err(e) = res {
+                let _ = fs::remove_file(&partial_path);
+                return Err(e);
+            }
+
+            metadata::remove_previous_output(output_path)?;
+            fs::rename(&partial_path, output_path)?;
+
+            if let Some(metadata) = &composite_file.metadata {
+                metadata.restore(output_path, self.option.file_options.restore_ownership.unwrap_or(true))?;
+            }
+
+            return Ok(());
+        }
+
+        let _ = fs::remove_file(&partial_path);
+
+        // Недостающие части восстанавливаются по паритетным частям при сборке
+        if download_errors.len() > composite_file.parity_parts.len() {
+            return Err(download_errors.remove(0));
+        }
+
+        let parity_results = stream::iter(&composite_file.parity_parts)
+            .map(|part| self.fetch_part(composite_file, part))
+            .buffer_unordered(self.parallelism())
+            .collect::<Vec<_>>()
+            .await;
+
+        for e in parity_results.into_iter().filter_map(Result::err) {
+            println!("Не удалось скачать паритетную часть: {:?}", e);
+        }
+
+        file_assembly::decode_file_with_options(
+            &parts_folder.join(&v_file.build_metafile),
+            SeparationOptions {
+                path_for_save: Some(parts_folder.clone()),
+                ..self.option.file_options.clone()
+            }
+        )?;
+
+        Ok(())
+    }
+
+    /// Скачивание части, если в рабочей папке ее нет или она не проходит проверку.
+    /// Хранилище с диапазонами докачивает часть с конца уже скачанных байтов
+    async fn fetch_part(&self, composite_file: &CompositeFile, part: &FilePart) -> Result<(), CloudError> {
+
+        let parts_folder = &self.option.work_dir;
+        let part_path = parts_folder.join(&part.part_file_name);
+
+        // Без хешей содержимого скачанную часть не проверить, поэтому она скачивается заново
+        let verifiable = composite_file.hash_algorithm.is_some();
+
+        if verifiable && file_assembly::check_part(parts_folder, composite_file, part) {
+            println!("Часть уже скачана => {}", part.part_file_name);
+            return Ok(());
+        }
+
+        if !self.backend.capabilities().ranged_reads {
+            return self.backend.clone().download_file(part_path).await;
+        }
+
+        if !verifiable && part_path.exists() {
+            fs::remove_file(&part_path)?;
+        }
+
+        self.download_part_ranges(&part_path).await?;
+
+        if !verifiable || file_assembly::check_part(parts_folder, composite_file, part) {
+            return Ok(());
+        }
+
+        // Ранее скачанные байты были испорчены, часть скачивается заново целиком
+        fs::remove_file(&part_path)?;
+        self.download_part_ranges(&part_path).await
+    }
+
+    /// Докачивание файла диапазонами с конца уже скачанных байтов до конца файла в хранилище
+    async fn download_part_ranges(&self, part_path: &Path) -> Result<(), CloudError> {
+
+        let mut part_file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(part_path)?;
+
+        let mut offset = part_file.metadata()?.len();
+
+        loop {
+            let bytes = self.backend.clone()
+                .download_file_range(part_path.to_path_buf(), offset, DOWNLOAD_SEGMENT_SIZE)
+                .await?;
+
+            part_file.write_all(&bytes)?;
+            offset += bytes.len() as u64;
+
+            if (bytes.len() as u64) < DOWNLOAD_SEGMENT_SIZE {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Части файла без повторов в порядке первого появления
+fn unique_parts(parts: &[FilePart]) -> Vec<&FilePart> {
+    let mut part_names = HashSet::new();
+
+    parts
+        .iter()
+        .filter(|part| part_names.insert(part.part_file_name.as_str()))
+        .collect()
+}
+
+/// Запись всех частей подряд, которые уже скачаны
+fn append_ready_parts<W: Write>(part_assembler: &mut PartAssembler<W>, ready_parts: &HashSet<&str>) -> Result<(), CloudError> {
+    while let Some(part) = part_assembler.next_part() {
+        if !ready_parts.contains(part.part_file_name.as_str()) {
+            break;
+        }
+
+        part_assembler.append_part()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BackendCapabilities, CloudBackend};
+    use crate::file::{encryption, file_assembly::DecodeErrors};
+    use crate::cloud::CloudOptions;
+    use crate::memory_backend::{FaultOptions, InMemoryBackend};
+    use crate::test_utils::{block_on, cloud_options, temp_dir, test_data};
+
+    fn memory_cloud(ranged_reads: bool, options: CloudOptions) -> Cloud<InMemoryBackend> {
+        let backend = InMemoryBackend::new();
+        backend.set_capabilities(BackendCapabilities { ranged_reads, ..Default::default() });
+
+        Cloud::with_backend(backend, options)
+    }
+
+    #[test]
+    fn interrupted_download_resumes_from_downloaded_bytes() {
+        let dir = temp_dir("resume_download");
+        let work_dir = dir.join("work");
+        let data = test_data(18 * 1024 * 1024);
+
+        let cloud = memory_cloud(true, CloudOptions {
+            parallelism: 1,
+            ..cloud_options(&dir, SeparationOptions { part_size: Some(12 * 1024 * 1024), ..Default::default() })
+        });
+        fs::write(dir.join("data.bin"), &data).unwrap();
+
+        block_on(async {
+            cloud.async_upload_file(&dir.join("data.bin"), Path::new("fs:")).await.unwrap();
+
+            let v_file = cloud.get_file(Path::new("fs:/data.bin")).unwrap();
+            assert_eq!(v_file.chunks.len(), 2);
+
+            fs::remove_dir_all(&work_dir).unwrap();
+
+            // Скачиваются сборочный файл и первый диапазон первой части, второй диапазон обрывается
+            cloud.backend().set_faults(FaultOptions {
+                fail_download: Some(cloud.backend().download_count() + 3),
+                ..Default::default()
+            });
+
+            assert!(cloud.async_download_file(Path::new("fs:/data.bin")).await.is_err());
+
+            let first_part = work_dir.join(&v_file.chunks[0]);
+            assert_eq!(fs::metadata(&first_part).unwrap().len(), DOWNLOAD_SEGMENT_SIZE);
+
+            // Недособранный файл, оставшийся после падения процесса
+            let partial_path = work_dir.join(format!("{}.partial", v_file.build_metafile));
+            fs::write(&partial_path, b"stale").unwrap();
+
+            cloud.backend().set_faults(FaultOptions::default());
+            let download_count = cloud.backend().download_count();
+            let downloaded_bytes = cloud.backend().downloaded_bytes();
+
+            let output_path = cloud.async_download_file(Path::new("fs:/data.bin")).await.unwrap();
+
+            assert!(fs::read(&output_path).unwrap() == data);
+            assert!(!partial_path.exists());
+
+            // Сборочный файл и вторая часть уже проверены, первая часть докачивается одним диапазоном
+            assert_eq!(cloud.backend().download_count() - download_count, 1);
+            assert_eq!(
+                cloud.backend().downloaded_bytes() - downloaded_bytes,
+                fs::metadata(&first_part).unwrap().len() - DOWNLOAD_SEGMENT_SIZE
+            );
+        });
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_part_is_restored_from_parity() {
+        for ranged_reads in [false, true] {
+            let dir = temp_dir("parity_download");
+            let work_dir = dir.join("work");
+            let data = test_data(100_000);
+
+            let cloud = memory_cloud(ranged_reads, cloud_options(&dir, SeparationOptions {
+                part_size: Some(16_384),
+                parity_parts: Some(1),
+                ..Default::default()
+            }));
+            fs::write(dir.join("data.bin"), &data).unwrap();
+
+            block_on(async {
+                cloud.async_upload_file(&dir.join("data.bin"), Path::new("fs:")).await.unwrap();
+
+                let v_file = cloud.get_file(Path::new("fs:/data.bin")).unwrap();
+
+                // Испорченная копия заменяет часть в хранилище
+                let damaged_part = work_dir.join(&v_file.chunks[2]);
+                let mut part_bytes = fs::read(&damaged_part).unwrap();
+                *part_bytes.last_mut().unwrap() ^= 0xFF;
+                fs::write(&damaged_part, part_bytes).unwrap();
+
+                CloudBackend::upload_file(cloud.backend(), &damaged_part).unwrap();
+
+                fs::remove_dir_all(&work_dir).unwrap();
+
+                let output_path = cloud.async_download_file(Path::new("fs:/data.bin")).await.unwrap();
+                assert!(fs::read(&output_path).unwrap() == data, "ranged_reads = {}", ranged_reads);
+
+                let composite_file = file_assembly::read_metafile(&work_dir.join(&v_file.build_metafile), None).unwrap();
+                assert!(composite_file.parts.iter().all(|part| file_assembly::check_part(&work_dir, &composite_file, part)));
+            });
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_only_output_is_downloaded_again() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("read_only_download");
+        let data = test_data(100_000);
+        let source_path = dir.join("data.bin");
+
+        let cloud = memory_cloud(true, cloud_options(&dir, SeparationOptions { part_size: Some(16_384), ..Default::default() }));
+        fs::write(&source_path, &data).unwrap();
+        fs::set_permissions(&source_path, fs::Permissions::from_mode(0o444)).unwrap();
+
+        block_on(async {
+            cloud.async_upload_file(&source_path, Path::new("fs:")).await.unwrap();
+
+            // Второе скачивание собирает файл поверх первого, ставшего доступным только для чтения
+            for _ in 0..2 {
+                let output_path = cloud.async_download_file(Path::new("fs:/data.bin")).await.unwrap();
+
+                assert!(fs::read(&output_path).unwrap() == data);
+                assert_eq!(fs::metadata(&output_path).unwrap().permissions().mode() & 0o7777, 0o444);
+            }
+        });
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tampered_encrypted_part_leaves_no_output() {
+        let dir = temp_dir("tampered_download");
+        let work_dir = dir.join("work");
+        let data = test_data(100_000);
+
+        let cloud = memory_cloud(true, cloud_options(&dir, SeparationOptions {
+            part_size: Some(40_000),
+            passphrase: Some("passphrase".to_string()),
+            ..Default::default()
+        }));
+        fs::write(dir.join("data.bin"), &data).unwrap();
+
+        block_on(async {
+            cloud.async_upload_file(&dir.join("data.bin"), Path::new("fs:")).await.unwrap();
+
+            let v_file = cloud.get_file(Path::new("fs:/data.bin")).unwrap();
+
+            // Измененный байт шифротекста второй части в хранилище
+            let tampered_part = work_dir.join(&v_file.chunks[1]);
+            let mut part_bytes = fs::read(&tampered_part).unwrap();
+            let data_offset = part_bytes.len() - encryption::encrypted_len(40_000) as usize;
+            part_bytes[data_offset + 10] ^= 0x01;
+            fs::write(&tampered_part, part_bytes).unwrap();
+
+            CloudBackend::upload_file(cloud.backend(), &tampered_part).unwrap();
+
+            fs::remove_dir_all(&work_dir).unwrap();
+
+            let res = cloud.async_download_file(Path::new("fs:/data.bin")).await;
+            assert!(matches!(res, Err(CloudError::PartHashMismatch { part_number: 2, .. })), "{:?}", res);
+
+            assert!(!work_dir.join("data.bin").exists());
+            assert!(!work_dir.join(format!("{}.partial", v_file.build_metafile)).exists());
+
+            // Диапазон второй части скачивается без остальной части и проверяется при расшифровке
+            let res = cloud.read_range(Path::new("fs:/data.bin"), 45_000, 1_000).await;
+            assert!(matches!(res, Err(CloudError::DecodeError(DecodeErrors::PartDecryptionFailed { part_number: 2, .. }))), "{:?}", res);
+
+            assert_eq!(cloud.read_range(Path::new("fs:/data.bin"), 1_000, 1_000).await.unwrap(), data[1_000..2_000]);
+        });
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,4 +1,6 @@
 use std::io;
+use std::path::PathBuf;
+use crate::file::file_assembly::DecodeErrors;
 use crate::file::file_separation::EncodeErrors;
 use crate::vfs::error::VFSError;
 
@@ -6,7 +8,56 @@ use crate::vfs::error::VFSError;
 pub enum CloudError {
     IOError(io::Error),
     EncodeError(EncodeErrors),
+    DecodeError(DecodeErrors),
     VFSError(VFSError),
+    FileNotFound {
+        file_name: String,
+    },
+    BackendError {
+        message: String,
+    },
+    /// Разделяемый путь не является файлом
+    NotAFile {
+        path: PathBuf,
+    },
+    /// Сборочный файл не найден
+    MetafileNotFound {
+        path: PathBuf,
+    },
+    /// Часть не найдена среди скачанных частей
+    PartNotFound {
+        part_number: usize,
+        path: PathBuf,
+    },
+    /// Часть принадлежит другому файлу или повреждена
+    PartNameHashMismatch {
+        part_number: usize,
+        path: PathBuf,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+    /// Содержимое части не совпадает с хешем из сборочного файла
+    PartHashMismatch {
+        part_number: usize,
+        part_file_name: String,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+    /// Сборочный файл содержит меньше хешей частей, чем частей
+    PartCountMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    /// Хранилище подтвердило загрузку, но файла в нем нет
+    UploadNotStored {
+        file_name: String,
+    },
+}
+
+impl From<io::Error> for CloudError {
+    fn from(value: io::Error) -> Self {
+        Self::IOError(value)
+    }
 }
 
 impl From<VFSError> for CloudError {
@@ -17,6 +68,24 @@ impl From<VFSError> for CloudError {
 
 impl From<EncodeErrors> for CloudError {
     fn from(value: EncodeErrors) -> Self {
-        Self::EncodeError(value)
+        match value {
+            EncodeErrors::NotAFile { path } => Self::NotAFile { path },
+            value => Self::EncodeError(value),
+        }
+    }
+}
+
+impl From<DecodeErrors> for CloudError {
+    fn from(value: DecodeErrors) -> Self {
+        match value {
+            DecodeErrors::MetafileNotFound { path } => Self::MetafileNotFound { path },
+            DecodeErrors::PartNotFound { part_number, path } => Self::PartNotFound { part_number, path },
+            DecodeErrors::PartNameHashMismatch { part_number, path, expected, actual } =>
+                Self::PartNameHashMismatch { part_number, path, expected, actual },
+            DecodeErrors::PartHashMismatch { part_number, part_file_name, expected, actual } =>
+                Self::PartHashMismatch { part_number, part_file_name, expected, actual },
+            DecodeErrors::PartCountMismatch { expected, actual } => Self::PartCountMismatch { expected, actual },
+            value => Self::DecodeError(value),
+        }
     }
 }
\ No newline at end of file
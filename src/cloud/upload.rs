@@ -0,0 +1,375 @@
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}};
+
+use futures::stream::{self, StreamExt};
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use uuid::Uuid;
+
+use super::Cloud;
+use super::error::CloudError;
+use crate::AsyncCloudBackend;
+use crate::file::{file_separation::SeparationFile, os_str_bytes, os_string_from_bytes};
+use crate::vfs::{Metadata, VFSFile, unique_chunks};
+
+/// Папка журналов незавершенных загрузок в рабочей папке
+const JOURNAL_DIR: &str = "journal";
+
+/// Состояние загрузки части или сборочного файла
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferState {
+    Pending,
+    Uploaded,
+    /// Часть уже лежала в облаке по индексу частей и не загружалась
+    AlreadyInCloud,
+}
+
+/// Часть или сборочный файл в журнале загрузки
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransferEntry {
+    pub file_name: String,
+    pub state: TransferState,
+    /// Реплики хранилища, на которые загружен файл
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replicas: Vec<String>,
+}
+
+impl TransferEntry {
+    fn pending(file_name: String) -> Self {
+        Self { file_name, state: TransferState::Pending, replicas: vec![] }
+    }
+}
+
+/// Журнал загрузки файла, сохраняемый в рабочей папке после каждой загруженной части.
+/// Хранит результат разделения и состояние загрузки каждой части, поэтому прерванная загрузка
+/// продолжается с первой незагруженной части. Файл попадает в VFS только после загрузки всех частей
+/// и сборочного файла, после чего журнал удаляется
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadJournal {
+    pub upload_id: String,
+    /// Загружаемый файл на диске
+    #[serde(serialize_with = "serialize_os_path", deserialize_with = "deserialize_os_path")]
+    pub file_path: PathBuf,
+    /// Папка VFS, в которую добавляется файл
+    pub virtual_path: PathBuf,
+    pub name: String,
+    pub extension: String,
+    pub file_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_name_bytes: Option<Vec<u8>>,
+    /// Имена частей по порядку, одна часть может встречаться несколько раз
+    pub chunks: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parity_parts_name: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest_part: Option<String>,
+    /// Части, паритетные части и часть-опись без повторов в порядке загрузки
+    pub parts: Vec<TransferEntry>,
+    /// Сборочный файл загружается последним
+    pub metafile: TransferEntry,
+    #[serde(default)]
+    pub metadata: Metadata,
+}
+
+impl UploadJournal {
+
+    pub fn new(separation_file: &SeparationFile, file_path: &Path, virtual_path: &Path) -> Self {
+        let chunks = separation_file.parts
+            .iter()
+            .map(|part| part.part_file_name.clone())
+            .collect::<Vec<String>>();
+
+        let parity_parts_name = separation_file.parity_parts
+            .iter()
+            .map(|part| part.part_file_name.clone())
+            .collect::<Vec<String>>();
+
+        let parts = unique_chunks(&[chunks.as_slice(), parity_parts_name.as_slice(), separation_file.manifest_part.as_slice()].concat())
+            .into_iter()
+            .map(|part_name| TransferEntry::pending(part_name.clone()))
+            .collect();
+
+        Self {
+            upload_id: Uuid::new_v4().to_string(),
+            file_path: file_path.to_path_buf(),
+            virtual_path: virtual_path.to_path_buf(),
+            name: separation_file.filename.clone(),
+            extension: separation_file.file_extension.clone(),
+            file_name: separation_file.file_name.to_string_lossy().to_string(),
+            file_name_bytes: separation_file.file_name
+                .to_str()
+                .is_none()
+                .then(|| os_str_bytes(&separation_file.file_name)),
+            chunks,
+            parity_parts_name,
+            manifest_part: separation_file.manifest_part.clone(),
+            parts,
+            metafile: TransferEntry::pending(separation_file.metafile.clone()),
+            metadata: separation_file.metadata
+                .as_ref()
+                .map(Metadata::from)
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Все части загружены или уже были в облаке
+    pub fn parts_uploaded(&self) -> bool {
+        self.parts.iter().all(|part| part.state != TransferState::Pending)
+    }
+
+    /// Файл VFS с частями и репликами из журнала
+    pub fn to_vfs_file(&self) -> VFSFile {
+        let replicas = self.parts
+            .iter()
+            .chain([&self.metafile])
+            .filter(|entry| !entry.replicas.is_empty())
+            .map(|entry| (entry.file_name.clone(), entry.replicas.clone()))
+            .collect::<HashMap<_, _>>();
+
+        VFSFile {
+            name: self.name.clone(),
+            extension: self.extension.clone(),
+            file_name: self.file_name.clone(),
+            file_name_bytes: self.file_name_bytes.clone(),
+            build_metafile: self.metafile.file_name.clone(),
+            chunks: self.chunks.clone(),
+            parity_parts_name: self.parity_parts_name.clone(),
+            manifest_part: self.manifest_part.clone(),
+            metadata: self.metadata.clone(),
+            replicas,
+        }
+    }
+
+    fn journal_path(&self, journal_dir: &Path) -> PathBuf {
+        journal_dir.join(format!("{}.json", self.upload_id))
+    }
+
+    /// Запись журнала через временный файл, чтобы прерванная запись не испортила журнал
+    fn save(&self, journal_dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(journal_dir)?;
+
+        let journal_path = self.journal_path(journal_dir);
+        let tmp_path = journal_path.with_extension("json.tmp");
+
+        fs::write(&tmp_path, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(tmp_path, journal_path)
+    }
+
+    fn remove(&self, journal_dir: &Path) -> io::Result<()> {
+        fs::remove_file(self.journal_path(journal_dir))
+    }
+}
+
+/// Путь на диске в журнале: строкой, а путь не в UTF-8 массивом байтов
+fn serialize_os_path<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    match path.to_str() {
+        Some(path) => serializer.serialize_str(path),
+        None => serializer.serialize_bytes(&os_str_bytes(path.as_os_str())),
+    }
+}
+
+fn deserialize_os_path<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StoredPath {
+        Utf8(PathBuf),
+        Bytes(Vec<u8>),
+    }
+
+    Ok(match StoredPath::deserialize(deserializer)? {
+        StoredPath::Utf8(path) => path,
+        StoredPath::Bytes(bytes) => os_string_from_bytes(bytes).into(),
+    })
+}
+
+impl<T: AsyncCloudBackend> Cloud<T> {
+
+    /// Папка журналов загрузок
+    fn journal_dir(&self) -> PathBuf {
+        self.option.work_dir.join(JOURNAL_DIR)
+    }
+
+    /// Журналы незавершенных загрузок. Испорченные журналы пропускаются
+    pub fn pending_uploads(&self) -> Result<Vec<UploadJournal>, CloudError> {
+        let journal_dir = self.journal_dir();
+
+        if !journal_dir.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut journals = vec![];
+
+        for entry in fs::read_dir(&journal_dir)? {
+            let journal_path = entry?.path();
+
+            if journal_path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+
+            match serde_json::from_slice::<UploadJournal>(&fs::read(&journal_path)?) {
+                Ok(journal) => journals.push(journal),
+                Err(e) => println!("Пропущен испорченный журнал загрузки {} => {:?}", journal_path.display(), e),
+            }
+        }
+
+        journals.sort_by(|a, b| a.upload_id.cmp(&b.upload_id));
+
+        Ok(journals)
+    }
+
+    /// Продолжает прерванные загрузки по журналам. Если незагруженных частей уже нет в рабочей папке,
+    /// загруженные части удаляются из облака и файл разделяется и загружается заново.
+    /// Возвращает папку VFS и итог каждой загрузки
+    pub async fn resume_uploads(&self) -> Result<Vec<(PathBuf, Result<(), CloudError>)>, CloudError> {
+
+        let mut results = vec![];
+
+        for journal in self.pending_uploads()? {
+            let local_parts_lost = journal.parts
+                .iter()
+                .chain([&journal.metafile])
+                .filter(|entry| entry.state != TransferState::Uploaded)
+                .any(|entry| !self.option.work_dir.join(&entry.file_name).is_file());
+
+            let res = match local_parts_lost {
+                false => self.complete_upload(journal.clone()).await,
+                true => {
+                    println!("Части загрузки {} потеряны в рабочей папке, файл загружается заново", journal.upload_id);
+
+                    self.abort_upload(&journal).await;
+                    self.async_upload_file(&journal.file_path, &journal.virtual_path).await
+                },
+            };
+
+            results.push((journal.virtual_path, res));
+        }
+
+        Ok(results)
+    }
+
+    /// Загрузка частей, отмеченных в журнале как незагруженные, затем сборочного файла и добавление файла в VFS.
+    /// При ошибке загрузки журнал и загруженные части остаются, чтобы загрузку можно было продолжить
+    pub(super) async fn complete_upload(&self, mut journal: UploadJournal) -> Result<(), CloudError> {
+
+        let journal_dir = self.journal_dir();
+
+        // Часть, которая была в облаке, могла быть удалена вместе с последним ссылавшимся на нее файлом
+        for entry in journal.parts.iter_mut() {
+            let in_cloud = self.fs.borrow().has_chunk(&entry.file_name);
+
+            entry.state = match (entry.state, in_cloud) {
+                (TransferState::Pending, true) => {
+                    println!("Часть уже есть в облаке, загрузка пропущена => {}", entry.file_name);
+                    TransferState::AlreadyInCloud
+                },
+                (TransferState::AlreadyInCloud, false) => TransferState::Pending,
+                (state, _) => state,
+            };
+        }
+
+        journal.save(&journal_dir)?;
+
+        let pending_parts = journal.parts
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.state == TransferState::Pending)
+            .map(|(part_ind, entry)| (part_ind, self.option.work_dir.join(&entry.file_name)))
+            .collect::<Vec<_>>();
+
+        let mut uploads = stream::iter(pending_parts)
+            .map(|(part_ind, part_path)| async move { (part_ind, self.upload_checked(part_path).await) })
+            .buffered(self.parallelism());
+
+        let mut upload_res = Ok(());
+
+        while let Some((part_ind, res)) = uploads.next().await {
+            match res {
+                Ok(()) => {
+                    let entry = &mut journal.parts[part_ind];
+                    entry.state = TransferState::Uploaded;
+                    entry.replicas = self.backend.replicas_of(&entry.file_name);
+
+                    journal.save(&journal_dir)?;
+                },
+                Err(e) => if upload_res.is_ok() {
+                    upload_res = Err(e);
+                },
+            }
+        }
+
+        drop(uploads);
+
+        // Сборочный файл загружается последним, только если все части уже в облаке
+        if upload_res.is_ok() && journal.metafile.state == TransferState::Pending {
+            upload_res = self.upload_checked(self.option.work_dir.join(&journal.metafile.file_name)).await;
+
+            if upload_res.is_ok() {
+                journal.metafile.state = TransferState::Uploaded;
+                journal.metafile.replicas = self.backend.replicas_of(&journal.metafile.file_name);
+
+                journal.save(&journal_dir)?;
+            }
+        }
+
+        if let Err(e) = upload_res {
+            println!("Загрузка {} прервана, ее можно продолжить через resume_uploads => {:?}", journal.upload_id, e);
+            return Err(e);
+        }
+
+        // Файл добавляется в VFS только после загрузки всех частей
+        let add_res = self.fs.borrow_mut().add_file(&journal.virtual_path, journal.to_vfs_file());
+
+        if let Err(e) = add_res {
+            self.abort_upload(&journal).await;
+            return Err(e.into());
+        }
+
+        self.save_vfs()?;
+
+        journal.remove(&journal_dir)?;
+
+        Ok(())
+    }
+
+    /// Загрузка файла с проверкой, что хранилище его действительно сохранило
+    async fn upload_checked(&self, file_path: PathBuf) -> Result<(), CloudError> {
+        let file_name = file_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        self.backend.clone().upload_file(file_path).await?;
+
+        match self.backend.clone().check_file(file_name.clone()).await {
+            true => Ok(()),
+            false => Err(CloudError::UploadNotStored { file_name }),
+        }
+    }
+
+    /// Отменяет незавершенную загрузку: загруженные части удаляются из облака, журнал удаляется
+    pub async fn cancel_upload(&self, upload_id: &str) -> Result<(), CloudError> {
+        let journal = self.pending_uploads()?
+            .into_iter()
+            .find(|journal| journal.upload_id == upload_id)
+            .ok_or(CloudError::FileNotFound { file_name: format!("{}.json", upload_id) })?;
+
+        self.abort_upload(&journal).await;
+
+        Ok(())
+    }
+
+    /// Удаляет из облака загруженные части незавершенной загрузки и ее журнал.
+    /// Части, на которые уже ссылаются файлы VFS, остаются
+    async fn abort_upload(&self, journal: &UploadJournal) {
+        let uploaded_files = journal.parts
+            .iter()
+            .chain([&journal.metafile])
+            .filter(|entry| entry.state == TransferState::Uploaded && !self.fs.borrow().has_chunk(&entry.file_name))
+            .map(|entry| self.option.work_dir.join(&entry.file_name))
+            .collect::<Vec<PathBuf>>();
+
+        self.rollback_upload(uploaded_files).await;
+
+        if let Err(e) = journal.remove(&self.journal_dir()) {
+            println!("Не удалось удалить журнал загрузки {} => {:?}", journal.upload_id, e);
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use std::{collections::HashSet, fs, io::Read, path::{Path, PathBuf}};
+
+use futures::stream::{self, StreamExt};
+use uuid::Uuid;
+
+use super::Cloud;
+use super::error::CloudError;
+use super::upload::TransferState;
+use crate::AsyncCloudBackend;
+use crate::file::{CompositeFile, PART_HASH_LEN, file_assembly};
+use crate::vfs::{VFSFile, unique_chunks};
+
+/// Опции проверки файлов в облаке
+#[derive(Debug, Clone, Default)]
+pub struct VerifyOptions {
+    /// Скачивать части и сверять хеши, по умолчанию проверяется только наличие в хранилище
+    pub deep: Option<bool>,
+    /// Заново загружать потерянные и испорченные части из локальной копии в рабочей папке
+    /// или восстановленные по паритетным частям
+    pub repair: Option<bool>,
+    /// Удалять из хранилища части и сборочные файлы, на которые не ссылается VFS. Используется в *scrub*
+    pub remove_orphans: Option<bool>,
+}
+
+/// Итог проверки файла
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    pub virtual_path: PathBuf,
+    /// Части и сборочный файл, которых нет в хранилище
+    pub missing: Vec<String>,
+    /// Части и сборочный файл, не прошедшие проверку хешей
+    pub corrupt: Vec<String>,
+    /// Потерянные и испорченные части, заново загруженные в хранилище
+    pub repaired: Vec<String>,
+}
+
+impl VerifyReport {
+
+    /// Все части на месте или восстановлены
+    pub fn is_healthy(&self) -> bool {
+        self.missing
+            .iter()
+            .chain(&self.corrupt)
+            .all(|file_name| self.repaired.contains(file_name))
+    }
+}
+
+/// Итог проверки всего облака
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScrubReport {
+    pub files: Vec<VerifyReport>,
+    /// Части и сборочные файлы в хранилище, на которые не ссылается VFS.
+    /// *None*, если хранилище не умеет перечислять файлы
+    pub orphaned: Option<Vec<String>>,
+    /// Удаленные из хранилища части и сборочные файлы без ссылок
+    pub removed_orphans: Vec<String>,
+}
+
+impl<T: AsyncCloudBackend> Cloud<T> {
+
+    /// Проверяет, что части и сборочный файл лежат в хранилище, при *deep* скачивает их и сверяет хеши.
+    /// При *repair* потерянные и испорченные части загружаются заново из локальной копии
+    /// или восстанавливаются по паритетным частям. Отстающие реплики восстанавливает *repair_replicas*
+    pub async fn verify(&self, virtual_path: &Path, options: &VerifyOptions) -> Result<VerifyReport, CloudError> {
+
+        let v_file = self.get_file(virtual_path)?;
+
+        let mut report = VerifyReport {
+            virtual_path: virtual_path.to_path_buf(),
+            ..Default::default()
+        };
+
+        let stored_names = stored_file_names(&v_file);
+
+        let existence = stream::iter(&stored_names)
+            .map(|file_name| async move {
+                (file_name, self.backend.clone().check_file(file_name.clone()).await)
+            })
+            .buffered(self.parallelism())
+            .collect::<Vec<_>>()
+            .await;
+
+        report.missing = existence
+            .into_iter()
+            .filter(|(_, exists)| !exists)
+            .map(|(file_name, _)| file_name.clone())
+            .collect();
+
+        let deep = options.deep.unwrap_or(false);
+        let repair = options.repair.unwrap_or(false) && (!report.missing.is_empty() || deep);
+
+        if !deep && !repair {
+            return Ok(report);
+        }
+
+        // Части скачиваются в отдельную папку, чтобы не затереть локальные копии в рабочей папке.
+        // Папка своя у каждой проверки, поэтому одновременные проверки не мешают друг другу
+        let verify_dir = self.option.work_dir.join(format!("verify_{}", Uuid::new_v4()));
+        fs::create_dir_all(&verify_dir)?;
+
+        let res = self.check_and_repair(&v_file, &stored_names, &verify_dir, repair, &mut report).await;
+
+        let _ = fs::remove_dir_all(&verify_dir);
+
+        res.map(|_| report)
+    }
+
+    /// Проверяет все файлы VFS и ищет в хранилище части, на которые не ссылается ни один файл
+    pub async fn scrub(&self, options: &VerifyOptions) -> Result<ScrubReport, CloudError> {
+
+        let file_paths = self.fs.borrow().file_paths();
+
+        let mut report = ScrubReport::default();
+        let mut referenced_names = HashSet::new();
+
+        for file_path in file_paths {
+            referenced_names.extend(stored_file_names(&self.get_file(&file_path)?));
+            report.files.push(self.verify(&file_path, options).await?);
+        }
+
+        // Загруженные части прерванных загрузок нужны, чтобы продолжить загрузку
+        for journal in self.pending_uploads()? {
+            referenced_names.extend(
+                journal.parts
+                    .into_iter()
+                    .chain([journal.metafile])
+                    .filter(|entry| entry.state == TransferState::Uploaded)
+                    .map(|entry| entry.file_name)
+            );
+        }
+
+        let stored_names = match self.backend.clone().list_files().await {
+            Ok(stored_names) => stored_names,
+            Err(e) => {
+                println!("Хранилище не перечислило файлы, части без ссылок не проверены => {:?}", e);
+                return Ok(report);
+            },
+        };
+
+        let mut orphaned = stored_names
+            .into_iter()
+            .filter(|file_name| is_cloud_file_name(file_name) && !referenced_names.contains(file_name))
+            .collect::<Vec<_>>();
+        orphaned.sort();
+
+        if options.remove_orphans.unwrap_or(false) {
+            for file_name in &orphaned {
+                match self.backend.clone().remove_file(self.option.work_dir.join(file_name)).await {
+                    Ok(()) => report.removed_orphans.push(file_name.clone()),
+                    Err(e) => println!("Не удалось удалить часть без ссылок {} => {:?}", file_name, e),
+                }
+            }
+        }
+
+        report.orphaned = Some(orphaned);
+
+        Ok(report)
+    }
+
+    /// Скачивание имеющихся частей в *verify_dir*, сверка хешей и восстановление
+    async fn check_and_repair(
+        &self,
+        v_file: &VFSFile,
+        stored_names: &[String],
+        verify_dir: &Path,
+        repair: bool,
+        report: &mut VerifyReport
+    ) -> Result<(), CloudError> {
+
+        let downloads = stream::iter(stored_names.iter().filter(|file_name| !report.missing.contains(file_name)))
+            .map(|file_name| async move {
+                (file_name, self.backend.clone().download_file(verify_dir.join(file_name)).await)
+            })
+            .buffered(self.parallelism())
+            .collect::<Vec<_>>()
+            .await;
+
+        for (file_name, res) in downloads {
+            if let Err(e) = res {
+                println!("Не удалось скачать {} для проверки => {:?}", file_name, e);
+                report.missing.push(file_name.clone());
+            }
+        }
+
+        let metafile_path = verify_dir.join(&v_file.build_metafile);
+
+        if !report.missing.contains(&v_file.build_metafile) && !file_assembly::check_metafile(&metafile_path) {
+            report.corrupt.push(v_file.build_metafile.clone());
+        }
+
+        let composite_file = self.read_verified_metafile(&metafile_path);
+
+        for file_name in stored_names {
+            if file_name == &v_file.build_metafile || report.missing.contains(file_name) {
+                continue;
+            }
+
+            if !check_part_file(verify_dir, file_name, composite_file.as_ref()) {
+                report.corrupt.push(file_name.clone());
+            }
+        }
+
+        if !repair {
+            return Ok(());
+        }
+
+        let damaged_names = report.missing
+            .iter()
+            .chain(&report.corrupt)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if damaged_names.is_empty() {
+            return Ok(());
+        }
+
+        let mut restored_names = vec![];
+
+        // Локальные копии из рабочей папки, прошедшие проверку
+        for file_name in &damaged_names {
+            let local_path = self.option.work_dir.join(file_name);
+
+            let valid = match file_name == &v_file.build_metafile {
+                true => local_path.is_file() && file_assembly::check_metafile(&local_path),
+                false => check_part_file(&self.option.work_dir, file_name, composite_file.as_ref()),
+            };
+
+            if valid {
+                fs::copy(&local_path, verify_dir.join(file_name))?;
+                restored_names.push(file_name.clone());
+            }
+        }
+
+        // Без целого сборочного файла части не восстановить по паритетным частям
+        let composite_file = match composite_file {
+            Some(composite_file) => Some(composite_file),
+            None => self.read_verified_metafile(&metafile_path),
+        };
+
+        if let Some(composite_file) = composite_file {
+            match file_assembly::repair_parts(verify_dir, &composite_file) {
+                Ok(repaired_names) => restored_names.extend(repaired_names),
+                Err(e) => println!("Не удалось восстановить части по паритетным частям => {:?}", e),
+            }
+        }
+
+        restored_names.sort();
+        restored_names.dedup();
+
+        for file_name in restored_names.into_iter().filter(|file_name| damaged_names.contains(file_name)) {
+            match self.backend.clone().upload_file(verify_dir.join(&file_name)).await {
+                Ok(()) => report.repaired.push(file_name),
+                Err(e) => println!("Не удалось загрузить восстановленную часть {} => {:?}", file_name, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Сборочный файл с целой контрольной суммой. Зашифрованный сборочный файл без парольной фразы
+    /// не читается, тогда части проверяются только по хешу имени
+    fn read_verified_metafile(&self, metafile_path: &Path) -> Option<CompositeFile> {
+        if !file_assembly::check_metafile(metafile_path) {
+            return None;
+        }
+
+        file_assembly::read_metafile(metafile_path, self.option.file_options.passphrase.as_deref()).ok()
+    }
+}
+
+/// Части, паритетные части и сборочный файл без повторов
+fn stored_file_names(v_file: &VFSFile) -> Vec<String> {
+    let file_names = [
+        v_file.chunks.as_slice(),
+        v_file.parity_parts_name.as_slice(),
+        v_file.manifest_part.as_slice(),
+        std::slice::from_ref(&v_file.build_metafile),
+    ].concat();
+
+    unique_chunks(&file_names)
+        .into_iter()
+        .cloned()
+        .collect()
+}
+
+/// Проверка части в *parts_folder*: по хешам из сборочного файла, а без него только по хешу имени
+fn check_part_file(parts_folder: &Path, file_name: &str, composite_file: Option<&CompositeFile>) -> bool {
+
+    if let Some(composite_file) = composite_file.filter(|composite_file| composite_file.manifest_part_name().as_deref() == Some(file_name)) {
+        return file_assembly::check_manifest_part(parts_folder, composite_file);
+    }
+
+    let part = composite_file.and_then(|composite_file| composite_file.parts
+        .iter()
+        .chain(&composite_file.parity_parts)
+        .find(|part| part.part_file_name == file_name)
+        .map(|part| (part, composite_file))
+    );
+
+    if let Some((part, composite_file)) = part {
+        return file_assembly::check_part(parts_folder, composite_file, part);
+    }
+
+    let Ok(part_file) = fs::File::open(parts_folder.join(file_name)) else {
+        return false;
+    };
+
+    let mut hash_bytes = vec![];
+
+    part_file.take(PART_HASH_LEN).read_to_end(&mut hash_bytes).is_ok()
+        && hash_bytes == md5::compute(file_name).0
+}
+
+/// Файлы, которые облако кладет в хранилище: части и сборочные файлы
+fn is_cloud_file_name(file_name: &str) -> bool {
+    [".part", ".chunk", ".meta"]
+        .iter()
+        .any(|suffix| file_name.ends_with(suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CloudBackend;
+    use crate::file::Options as SeparationOptions;
+    use crate::memory_backend::{FaultOptions, InMemoryBackend};
+    use crate::test_utils::{block_on, cloud_options, temp_dir, test_data};
+
+    #[test]
+    fn verify_lists_and_repairs_missing_and_corrupt_parts() {
+        let dir = temp_dir("verify");
+        let work_dir = dir.join("work");
+        let data = test_data(10_000);
+
+        let cloud = Cloud::with_backend(InMemoryBackend::new(), cloud_options(&dir, SeparationOptions {
+            part_size: Some(2_048),
+            parity_parts: Some(2),
+            ..Default::default()
+        }));
+        fs::write(dir.join("data.bin"), &data).unwrap();
+
+        block_on(async {
+            cloud.async_upload_file(&dir.join("data.bin"), Path::new("fs:")).await.unwrap();
+
+            let virtual_path = Path::new("fs:/data.bin");
+            let v_file = cloud.get_file(virtual_path).unwrap();
+            let missing_name = v_file.chunks[1].clone();
+            let corrupt_name = v_file.chunks[3].clone();
+
+            // Одна часть пропадает из хранилища, другая заменяется испорченной копией
+            CloudBackend::remove_file(cloud.backend(), &work_dir.join(&missing_name)).unwrap();
+
+            let corrupt_part = work_dir.join(&corrupt_name);
+            let mut part_bytes = fs::read(&corrupt_part).unwrap();
+            *part_bytes.last_mut().unwrap() ^= 0xFF;
+            fs::write(&corrupt_part, part_bytes).unwrap();
+            CloudBackend::upload_file(cloud.backend(), &corrupt_part).unwrap();
+
+            // Без локальных копий части восстанавливаются только по паритетным частям
+            fs::remove_dir_all(&work_dir).unwrap();
+
+            let report = cloud.verify(virtual_path, &VerifyOptions::default()).await.unwrap();
+            assert_eq!(report.missing, vec![missing_name.clone()]);
+            assert!(report.corrupt.is_empty());
+
+            let deep = VerifyOptions { deep: Some(true), ..Default::default() };
+
+            let report = cloud.verify(virtual_path, &deep).await.unwrap();
+            assert_eq!(report.missing, vec![missing_name.clone()]);
+            assert_eq!(report.corrupt, vec![corrupt_name.clone()]);
+            assert!(report.repaired.is_empty());
+            assert!(!report.is_healthy());
+
+            let upload_count = cloud.backend().upload_count();
+
+            let report = cloud.verify(virtual_path, &VerifyOptions { repair: Some(true), ..deep.clone() }).await.unwrap();
+            let mut expected_repaired = vec![missing_name, corrupt_name];
+            expected_repaired.sort();
+
+            assert_eq!(report.repaired, expected_repaired);
+            assert!(report.is_healthy());
+            assert_eq!(cloud.backend().upload_count() - upload_count, 2);
+
+            let report = cloud.verify(virtual_path, &deep).await.unwrap();
+            assert!(report.missing.is_empty() && report.corrupt.is_empty());
+
+            let output_path = cloud.async_download_file(virtual_path).await.unwrap();
+            assert!(fs::read(output_path).unwrap() == data);
+        });
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn scrub_removes_only_unreferenced_cloud_files() {
+        let dir = temp_dir("scrub");
+        let orphans_dir = dir.join("orphans");
+        fs::create_dir_all(&orphans_dir).unwrap();
+
+        let cloud = Cloud::with_backend(InMemoryBackend::new(), cloud_options(&dir, SeparationOptions {
+            part_size: Some(2_048),
+            ..Default::default()
+        }));
+        fs::write(dir.join("data.bin"), test_data(10_000)).unwrap();
+        fs::write(dir.join("interrupted.bin"), test_data(12_000)).unwrap();
+
+        block_on(async {
+            cloud.async_upload_file(&dir.join("data.bin"), Path::new("fs:")).await.unwrap();
+
+            // Прерванная загрузка оставляет журнал и часть уже загруженных частей
+            cloud.backend().set_faults(FaultOptions {
+                fail_upload: Some(cloud.backend().upload_count() + 2),
+                ..Default::default()
+            });
+            assert!(cloud.async_upload_file(&dir.join("interrupted.bin"), Path::new("fs:")).await.is_err());
+            cloud.backend().set_faults(FaultOptions::default());
+
+            let journal_names = cloud.pending_uploads().unwrap()
+                .into_iter()
+                .flat_map(|journal| journal.parts.into_iter().chain([journal.metafile]))
+                .filter(|entry| entry.state == TransferState::Uploaded)
+                .map(|entry| entry.file_name)
+                .collect::<Vec<_>>();
+            assert!(!journal_names.is_empty());
+
+            let orphan_names = ["orphan.chunk", "orphan.meta", "orphan.part"];
+
+            for file_name in orphan_names.iter().chain(&["notes.txt"]) {
+                fs::write(orphans_dir.join(file_name), b"orphan").unwrap();
+                CloudBackend::upload_file(cloud.backend(), &orphans_dir.join(file_name)).unwrap();
+            }
+
+            let referenced_names = stored_file_names(&cloud.get_file(Path::new("fs:/data.bin")).unwrap());
+
+            let report = cloud.scrub(&VerifyOptions { remove_orphans: Some(true), ..Default::default() }).await.unwrap();
+
+            assert_eq!(report.orphaned, Some(orphan_names.map(str::to_string).to_vec()));
+            assert_eq!(report.removed_orphans, orphan_names.map(str::to_string).to_vec());
+            assert!(report.files.iter().all(VerifyReport::is_healthy));
+
+            let stored_names = cloud.backend().file_names();
+
+            assert!(orphan_names.iter().all(|file_name| !stored_names.contains(&file_name.to_string())));
+            assert!(stored_names.contains(&"notes.txt".to_string()));
+            assert!(referenced_names.iter().chain(&journal_names).all(|file_name| stored_names.contains(file_name)));
+
+            // Прерванная загрузка продолжается с сохраненными частями
+            let results = cloud.resume_uploads().await.unwrap();
+            assert!(results.iter().all(|(_, res)| res.is_ok()));
+            assert!(cloud.get_file(Path::new("fs:/interrupted.bin")).is_ok());
+        });
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -3,6 +3,8 @@ use std::io;
 #[derive(Debug)]
 pub enum TDAppError {
     IOError(io::Error),
+    /// Поток обновлений закончился раньше, чем клиент сообщил о закрытии
+    ClientNotClosed,
 }
 
 impl From<io::Error> for TDAppError {
@@ -365,7 +365,9 @@ impl TDApp {
         }
     }
 
-    pub fn upload_file(&self, file_path: &Path, chat_id: i64) -> (i64, Value) {
+    /// Отправляет файл документом в чат.
+    /// Возвращает id загруженного файла и отправленное сообщение
+    pub fn upload_file(&self, file_path: &Path, chat_id: i64) -> Result<(i64, Value), ()> {
 
         self.send_query(&json!({
                     "@type": "sendMessage",
@@ -393,16 +395,25 @@ impl TDApp {
                 }
                 "updateMessageSendSucceeded" => {
 
-                    //let file_id = json_update["message"]["content"]["document"]["id"].as_i64().unwrap();
-
                     println!("FULFILE: {}\n", json_update);
-                    return (2, json_update)
+
+                    let message = json_update["message"].clone();
+                    let file_id = message["content"]["document"]["document"]["id"]
+                        .as_i64()
+                        .ok_or(())?;
+
+                    return Ok((file_id, message))
+                }
+
+                "updateMessageSendFailed" => {
+                    println!("Не удалось загрузить файл: {}\n", json_update);
+                    return Err(())
                 }
 
                 _ => {}
             }
         }
-        panic!("Не удалось загрузить файл")
+        Err(())
     }
 
     pub fn download_file(&self, file_id: i64) -> Result<Value, ()> {
@@ -447,6 +458,26 @@ impl TDApp {
         Err(())
     }
 
+    /// Закрывает TDLib клиент и дожидается завершения его работы
+    pub fn close(&self) -> Result<(), TDAppError> {
+        self.send_query(&json!({
+            "@type": "close"
+        }).to_string()).expect("Строка содержала нулебой байт");
+
+        while let Some(json_update) = self.next_update_json() {
+
+            if
+                json_update["@type"] == "updateAuthorizationState" &&
+                json_update["authorization_state"]["@type"] == "authorizationStateClosed"
+            {
+                println!("|==|==|==> TDLib client is closed <==|==|==|");
+                return Ok(())
+            }
+        }
+
+        Err(TDAppError::ClientNotClosed)
+    }
+
     pub fn delete_message(&self, chat_id: i64, vec_message_id: &[i64]) -> Result<(), ()> {
         self.send_query(&json!({
             "@type": "deleteMessages",
@@ -455,6 +486,15 @@ impl TDApp {
             "revoke": true
         }).to_string()).unwrap();
 
-        Ok(())
+        // TDLib отвечает ok после удаления сообщений или error
+        while let Some(json_response) = self.next_update_json() {
+            match json_response["@type"].as_str() {
+                Some("ok") => return Ok(()),
+                Some("error") => return Err(()),
+                _ => {}
+            }
+        }
+
+        Err(())
     }
 }
@@ -0,0 +1,170 @@
+use std::io::{self, BufRead, Read, Write};
+
+/// Минимальный размер части при разбиении по содержимому
+const MIN_CHUNK_SIZE: usize = 64;
+
+/// Таблица *Gear* хеша. Генерируется детерминированно, так как от нее зависят границы частей,
+/// и ее изменение лишит дедупликации все ранее загруженные части
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0_u64; 256];
+    let mut state = 0x5265_436c_6f75_6400_u64;
+    let mut ind = 0;
+
+    // splitmix64
+    while ind < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[ind] = z ^ (z >> 31);
+        ind += 1;
+    }
+
+    table
+}
+
+/// Разбиение потока на части фиксированного размера или с границами по содержимому.
+/// Данные частей копируются через буфер *R*, поэтому память не зависит от размера части
+pub struct PartSplitter<R: BufRead> {
+    reader: R,
+    mode: SplitMode,
+}
+
+enum SplitMode {
+    Fixed {
+        part_size: u64,
+    },
+    ContentDefined(CutPoints),
+}
+
+impl<R: BufRead> PartSplitter<R> {
+
+    pub fn fixed(reader: R, part_size: usize) -> Self {
+        Self {
+            reader,
+            mode: SplitMode::Fixed { part_size: part_size.max(1) as u64 },
+        }
+    }
+
+    /// Части получаются от *max_size / 16* до *max_size*, в среднем *max_size / 4*
+    pub fn content_defined(reader: R, max_size: usize) -> Self {
+        Self {
+            reader,
+            mode: SplitMode::ContentDefined(CutPoints::new(max_size)),
+        }
+    }
+
+    pub fn has_data_left(&mut self) -> io::Result<bool> {
+        self.reader.has_data_left()
+    }
+
+    /// Копирует следующую часть в *output*, возвращает длину части
+    pub fn copy_part(&mut self, output: &mut impl Write) -> io::Result<u64> {
+        match &self.mode {
+            SplitMode::Fixed { part_size } => io::copy(&mut (&mut self.reader).take(*part_size), output),
+
+            SplitMode::ContentDefined(cut_points) => {
+                let mut cut_state = CutState::default();
+
+                loop {
+                    let buffer = self.reader.fill_buf()?;
+
+                    if buffer.is_empty() {
+                        break;
+                    }
+
+                    let (len, cut_found) = cut_state.scan(cut_points, buffer);
+
+                    output.write_all(&buffer[..len])?;
+                    self.reader.consume(len);
+
+                    if cut_found {
+                        break;
+                    }
+                }
+
+                Ok(cut_state.len)
+            },
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Разбиение по содержимому в духе *FastCDC*: граница ставится там, где *Gear* хеш
+/// последних байтов удовлетворяет маске, поэтому вставка данных в начало файла
+/// сдвигает лишь соседние границы, а остальные части остаются прежними
+struct CutPoints {
+    min_size: u64,
+    avg_size: u64,
+    max_size: u64,
+    /// Маска до среднего размера, сложнее выполняется, чтобы части не были слишком короткими
+    mask_small: u64,
+    /// Маска после среднего размера, легче выполняется, чтобы части не были слишком длинными
+    mask_large: u64,
+}
+
+impl CutPoints {
+    fn new(max_size: usize) -> Self {
+        let max_size = max_size.max(MIN_CHUNK_SIZE * 16) as u64;
+        let avg_size = max_size / 4;
+        let bits = avg_size.ilog2();
+
+        Self {
+            min_size: max_size / 16,
+            avg_size,
+            max_size,
+            mask_small: high_bits_mask(bits + 1),
+            mask_large: high_bits_mask(bits - 1),
+        }
+    }
+}
+
+/// Состояние поиска границы текущей части
+#[derive(Default)]
+struct CutState {
+    hash: u64,
+    len: u64,
+}
+
+impl CutState {
+
+    /// Просматривает очередные байты части.
+    /// Возвращает кол-во байтов, относящихся к части, и найдена ли граница
+    fn scan(&mut self, cut_points: &CutPoints, data: &[u8]) -> (usize, bool) {
+        for (ind, &byte) in data.iter().enumerate() {
+            let position = self.len;
+            self.len += 1;
+
+            // Байты до минимального размера не участвуют в хеше
+            if position < cut_points.min_size {
+                continue;
+            }
+
+            self.hash = (self.hash << 1).wrapping_add(GEAR[byte as usize]);
+
+            let mask = match position < cut_points.avg_size {
+                true => cut_points.mask_small,
+                false => cut_points.mask_large,
+            };
+
+            if self.hash & mask == 0 || self.len == cut_points.max_size {
+                return (ind + 1, true);
+            }
+        }
+
+        (data.len(), false)
+    }
+}
+
+/// Маска из *bits* старших битов: при сдвиге влево в старших битах накапливается влияние большего числа байтов
+fn high_bits_mask(bits: u32) -> u64 {
+    match bits {
+        0 => 0,
+        bits => u64::MAX << (64 - bits.min(64)),
+    }
+}
@@ -0,0 +1,180 @@
+use std::io::{self, Write};
+
+use flate2::{Compression, write::{GzDecoder, GzEncoder}};
+
+/// Уровень сжатия *zstd* по умолчанию
+const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// Уровень сжатия *gzip* по умолчанию
+const DEFAULT_GZIP_LEVEL: i32 = 6;
+
+/// Размер начала файла, по которому оценивается его энтропия
+pub const ENTROPY_SAMPLE_SIZE: usize = 65_536;
+
+/// Энтропия в битах на байт, начиная с которой файл считается уже сжатым
+const COMPRESSED_ENTROPY: f64 = 7.5;
+
+/// Расширения форматов, которые уже сжаты и не уменьшаются при повторном сжатии
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "zip", "gz", "tgz", "bz2", "xz", "zst", "7z", "rar", "lz4", "br",
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "avif",
+    "mp3", "aac", "ogg", "opus", "flac", "m4a",
+    "mp4", "mkv", "avi", "mov", "webm",
+    "docx", "xlsx", "pptx", "odt", "ods", "epub", "apk", "jar",
+];
+
+/// Алгоритм сжатия частей
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CompressionAlgorithm {
+    #[default]
+    Zstd,
+    Gzip,
+}
+
+impl CompressionAlgorithm {
+
+    /// Идентификатор алгоритма в сборочном файле
+    pub fn id(self) -> u8 {
+        match self {
+            CompressionAlgorithm::Zstd => 1,
+            CompressionAlgorithm::Gzip => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(CompressionAlgorithm::Zstd),
+            2 => Some(CompressionAlgorithm::Gzip),
+            _ => None,
+        }
+    }
+
+    pub fn default_level(self) -> i32 {
+        match self {
+            CompressionAlgorithm::Zstd => DEFAULT_ZSTD_LEVEL,
+            CompressionAlgorithm::Gzip => DEFAULT_GZIP_LEVEL,
+        }
+    }
+}
+
+/// Потоковое сжатие, записывающее сжатые данные в *W*
+pub enum CompressWriter<W: Write> {
+    Plain(W),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    Gzip(GzEncoder<W>),
+}
+
+impl<W: Write> CompressWriter<W> {
+
+    /// Без алгоритма данные записываются как есть
+    pub fn new(compression: Option<(CompressionAlgorithm, i32)>, inner: W) -> io::Result<Self> {
+        Ok(match compression {
+            None => CompressWriter::Plain(inner),
+            Some((CompressionAlgorithm::Zstd, level)) =>
+                CompressWriter::Zstd(zstd::stream::write::Encoder::new(inner, level)?),
+            Some((CompressionAlgorithm::Gzip, level)) =>
+                CompressWriter::Gzip(GzEncoder::new(inner, Compression::new(level.clamp(0, 9) as u32))),
+        })
+    }
+
+    /// Дописывает конец сжатого потока и возвращает вложенную запись
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            CompressWriter::Plain(inner) => Ok(inner),
+            CompressWriter::Zstd(encoder) => encoder.finish(),
+            CompressWriter::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for CompressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            CompressWriter::Plain(inner) => inner.write(buf),
+            CompressWriter::Zstd(encoder) => encoder.write(buf),
+            CompressWriter::Gzip(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            CompressWriter::Plain(inner) => inner.flush(),
+            CompressWriter::Zstd(encoder) => encoder.flush(),
+            CompressWriter::Gzip(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Потоковая распаковка, записывающая распакованные данные в *W*
+pub enum DecompressWriter<W: Write> {
+    Plain(W),
+    Zstd(zstd::stream::write::Decoder<'static, W>),
+    Gzip(GzDecoder<W>),
+}
+
+impl<W: Write> DecompressWriter<W> {
+
+    pub fn new(compression: Option<CompressionAlgorithm>, inner: W) -> io::Result<Self> {
+        Ok(match compression {
+            None => DecompressWriter::Plain(inner),
+            Some(CompressionAlgorithm::Zstd) => DecompressWriter::Zstd(zstd::stream::write::Decoder::new(inner)?),
+            Some(CompressionAlgorithm::Gzip) => DecompressWriter::Gzip(GzDecoder::new(inner)),
+        })
+    }
+
+    /// Дописывает остаток распакованных данных и возвращает вложенную запись
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            DecompressWriter::Plain(inner) => Ok(inner),
+            DecompressWriter::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            },
+            DecompressWriter::Gzip(decoder) => decoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for DecompressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            DecompressWriter::Plain(inner) => inner.write(buf),
+            DecompressWriter::Zstd(decoder) => decoder.write(buf),
+            DecompressWriter::Gzip(decoder) => decoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            DecompressWriter::Plain(inner) => inner.flush(),
+            DecompressWriter::Zstd(decoder) => decoder.flush(),
+            DecompressWriter::Gzip(decoder) => decoder.flush(),
+        }
+    }
+}
+
+/// Файл уже сжат: определяется по расширению или по энтропии начала файла
+pub fn is_compressed_input(file_extension: &str, sample: &[u8]) -> bool {
+    let compressed_extension = COMPRESSED_EXTENSIONS.contains(&file_extension.to_lowercase().as_str());
+
+    compressed_extension || entropy(sample) > COMPRESSED_ENTROPY
+}
+
+/// Энтропия Шеннона в битах на байт
+fn entropy(sample: &[u8]) -> f64 {
+    if sample.is_empty() {
+        return 0.0;
+    }
+
+    let mut counts = [0_usize; 256];
+    sample.iter().for_each(|&byte| counts[byte as usize] += 1);
+
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let probability = count as f64 / sample.len() as f64;
+            -probability * probability.log2()
+        })
+        .sum()
+}
@@ -0,0 +1,323 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, OsRng, Payload, rand_core::RngCore};
+use chacha20poly1305::{ChaCha20Poly1305, Key, KeyInit, Nonce};
+
+/// Размер открытого сегмента, каждый сегмент части шифруется отдельно
+pub const SEGMENT_SIZE: usize = 65_536;
+
+/// Длина тега аутентификации сегмента
+pub const TAG_LEN: usize = 16;
+
+pub const SALT_LEN: usize = 16;
+
+pub const NONCE_LEN: usize = 12;
+
+/// Идентификатор *ChaCha20-Poly1305* в сборочном файле
+pub const CHACHA20_POLY1305: u8 = 1;
+
+/// Номер части, зарезервированный под зашифрованные сведения о файле
+const METADATA_PART_NUMBER: u32 = u32::MAX;
+
+/// Номера сегментов зашифрованных сведений о файле, у каждого сегмента свой nonce
+pub const METADATA_FILE_NAMES: u32 = 0;
+pub const METADATA_FILE_ATTRIBUTES: u32 = 1;
+pub const METADATA_FULL_FILE_NAME: u32 = 2;
+pub const METADATA_PART_SIZES: u32 = 3;
+pub const METADATA_PART_HEADER_FILE_NAME: u32 = 4;
+
+/// Параметры шифрования файла, записываемые в сборочный файл открыто
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptionParams {
+    pub salt: [u8; SALT_LEN],
+    /// Основа nonce, уникальная для файла
+    pub base_nonce: [u8; NONCE_LEN],
+    /// Параметры *Argon2id*
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl EncryptionParams {
+
+    /// Новые случайные соль и основа nonce с параметрами *Argon2id* по умолчанию
+    pub fn generate() -> Self {
+        let mut salt = [0_u8; SALT_LEN];
+        let mut base_nonce = [0_u8; NONCE_LEN];
+
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut base_nonce);
+
+        Self {
+            salt,
+            base_nonce,
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+
+    /// Вывод ключа файла из парольной фразы
+    pub fn derive_cipher(&self, passphrase: &str) -> Result<FileCipher, argon2::Error> {
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))?;
+
+        let mut key = [0_u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &self.salt, &mut key)?;
+
+        Ok(FileCipher {
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            hash_key: blake3::derive_key("recloud file content hash", &key),
+            params: self.clone(),
+        })
+    }
+}
+
+/// Шифрование частей ключом, выведенным из парольной фразы.
+/// Сегмент аутентифицируется вместе с номером части, номером сегмента и признаком последнего сегмента,
+/// поэтому перестановка или обрезка сегментов обнаруживается при расшифровке
+#[derive(Clone)]
+pub struct FileCipher {
+    cipher: ChaCha20Poly1305,
+    hash_key: [u8; 32],
+    pub params: EncryptionParams,
+}
+
+impl fmt::Debug for FileCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileCipher")
+            .field("params", &self.params)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FileCipher {
+
+    /// Ключ хеша исходного файла, чтобы хеш в сборочном файле не раскрывал содержимое
+    pub fn hash_key(&self) -> &[u8; 32] {
+        &self.hash_key
+    }
+
+    /// Шифрование сведений о файле, хранящихся в сборочном файле
+    pub fn seal_metadata(&self, metadata_ind: u32, data: &[u8]) -> Vec<u8> {
+        self.seal(METADATA_PART_NUMBER, metadata_ind, true, data)
+    }
+
+    pub fn open_metadata(&self, metadata_ind: u32, data: &[u8]) -> Option<Vec<u8>> {
+        self.open(METADATA_PART_NUMBER, metadata_ind, true, data)
+    }
+
+    /// Расшифровка отдельного сегмента части, используется при чтении диапазона без скачивания всей части
+    pub fn open_segment(&self, part_number: u32, segment_ind: u32, is_last: bool, data: &[u8]) -> Option<Vec<u8>> {
+        self.open(part_number, segment_ind, is_last, data)
+    }
+
+    fn seal(&self, part_number: u32, segment_ind: u32, is_last: bool, data: &[u8]) -> Vec<u8> {
+        let aad = associated_data(part_number, segment_ind, is_last);
+
+        self.cipher
+            .encrypt(&self.nonce(part_number, segment_ind), Payload { msg: data, aad: &aad })
+            .expect("Шифрование ChaCha20-Poly1305 не ограничено по размеру сегмента")
+    }
+
+    fn open(&self, part_number: u32, segment_ind: u32, is_last: bool, data: &[u8]) -> Option<Vec<u8>> {
+        let aad = associated_data(part_number, segment_ind, is_last);
+
+        self.cipher
+            .decrypt(&self.nonce(part_number, segment_ind), Payload { msg: data, aad: &aad })
+            .ok()
+    }
+
+    /// Nonce сегмента: основа nonce файла, смешанная с номером части и номером сегмента
+    fn nonce(&self, part_number: u32, segment_ind: u32) -> Nonce {
+        let mut nonce = self.params.base_nonce;
+
+        let counter = [part_number.to_be_bytes(), segment_ind.to_be_bytes()].concat();
+
+        for (nonce_byte, byte) in nonce.iter_mut().zip(counter) {
+            *nonce_byte ^= byte;
+        }
+
+        *Nonce::from_slice(&nonce)
+    }
+}
+
+/// Ошибка расшифровки сегмента: неверный ключ или измененные данные
+#[derive(Debug)]
+pub struct DecryptionFailed;
+
+impl fmt::Display for DecryptionFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Сегмент части не расшифровывается")
+    }
+}
+
+impl Error for DecryptionFailed {}
+
+/// Потоковое шифрование части по сегментам.
+/// Сегмент шифруется, когда становится известно, последний ли он, поэтому в памяти лежит не больше одного сегмента.
+/// Без шифра данные записываются как есть
+pub struct EncryptWriter<'a, W: Write> {
+    cipher: Option<&'a FileCipher>,
+    part_number: u32,
+    segment_ind: u32,
+    segment: Vec<u8>,
+    inner: W,
+}
+
+impl<'a, W: Write> EncryptWriter<'a, W> {
+
+    pub fn new(cipher: Option<&'a FileCipher>, part_number: u32, inner: W) -> Self {
+        Self {
+            cipher,
+            part_number,
+            segment_ind: 0,
+            segment: Vec::new(),
+            inner,
+        }
+    }
+
+    /// Шифрует последний сегмент и возвращает вложенную запись.
+    /// У пустой части шифруется один пустой сегмент, чтобы обрезка части обнаруживалась
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.cipher.is_some() {
+            self.seal_segment(true)?;
+        }
+
+        Ok(self.inner)
+    }
+
+    fn seal_segment(&mut self, is_last: bool) -> io::Result<()> {
+        let Some(cipher) = self.cipher else {
+            return Ok(());
+        };
+
+        self.inner.write_all(&cipher.seal(self.part_number, self.segment_ind, is_last, &self.segment))?;
+        self.segment.clear();
+        self.segment_ind += 1;
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.cipher.is_none() {
+            return self.inner.write(buf);
+        }
+
+        let mut written = 0;
+
+        while written < buf.len() {
+            if self.segment.len() == SEGMENT_SIZE {
+                self.seal_segment(false)?;
+            }
+
+            let take = (SEGMENT_SIZE - self.segment.len()).min(buf.len() - written);
+            self.segment.extend_from_slice(&buf[written..written + take]);
+            written += take;
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Потоковая расшифровка части по сегментам, ошибка расшифровки возвращается как *DecryptionFailed*.
+/// Без шифра данные записываются как есть
+pub struct DecryptWriter<'a, W: Write> {
+    cipher: Option<&'a FileCipher>,
+    part_number: u32,
+    segment_ind: u32,
+    segment: Vec<u8>,
+    inner: W,
+}
+
+impl<'a, W: Write> DecryptWriter<'a, W> {
+
+    pub fn new(cipher: Option<&'a FileCipher>, part_number: u32, inner: W) -> Self {
+        Self {
+            cipher,
+            part_number,
+            segment_ind: 0,
+            segment: Vec::new(),
+            inner,
+        }
+    }
+
+    /// Расшифровывает последний сегмент и возвращает вложенную запись
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.cipher.is_some() {
+            self.open_segment(true)?;
+        }
+
+        Ok(self.inner)
+    }
+
+    fn open_segment(&mut self, is_last: bool) -> io::Result<()> {
+        let Some(cipher) = self.cipher else {
+            return Ok(());
+        };
+
+        let data = cipher
+            .open(self.part_number, self.segment_ind, is_last, &self.segment)
+            .ok_or(io::Error::new(io::ErrorKind::InvalidData, DecryptionFailed))?;
+
+        self.inner.write_all(&data)?;
+        self.segment.clear();
+        self.segment_ind += 1;
+
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for DecryptWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.cipher.is_none() {
+            return self.inner.write(buf);
+        }
+
+        let mut written = 0;
+
+        while written < buf.len() {
+            if self.segment.len() == SEGMENT_SIZE + TAG_LEN {
+                self.open_segment(false)?;
+            }
+
+            let take = (SEGMENT_SIZE + TAG_LEN - self.segment.len()).min(buf.len() - written);
+            self.segment.extend_from_slice(&buf[written..written + take]);
+            written += take;
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Ошибка *io* вызвана неудачной расшифровкой сегмента
+pub fn is_decryption_failed(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<DecryptionFailed>())
+}
+
+/// Длина зашифрованной части по длине открытых данных
+pub fn encrypted_len(plain_len: u64) -> u64 {
+    let segments_count = plain_len.div_ceil(SEGMENT_SIZE as u64).max(1);
+    plain_len + segments_count * TAG_LEN as u64
+}
+
+fn associated_data(part_number: u32, segment_ind: u32, is_last: bool) -> [u8; 9] {
+    let mut aad = [0_u8; 9];
+    aad[..4].copy_from_slice(&part_number.to_be_bytes());
+    aad[4..8].copy_from_slice(&segment_ind.to_be_bytes());
+    aad[8] = is_last as u8;
+    aad
+}
@@ -1,13 +1,21 @@
 use std::{
     fs::{self, File},
-    io::{self, Read, Write, BufReader, Error},
-    path::{self, PathBuf},
-    borrow::Cow
+    io::{self, Read, Write, BufReader, BufWriter, Error, Seek, SeekFrom},
+    path::{self, Path, PathBuf},
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    thread,
 };
 use std::string::FromUtf8Error;
 
 
-use super::{CompositeFile, FilePart};
+use super::{CompositeFile, FilePart, Options, metafile_section, parity, chunk_file_name, CHUNK_NAMES_CONTENT_HASH, PART_HASH_LEN, metafile_format, metafile_flags, os_string_from_bytes, split_file_name};
+use super::compression::{CompressionAlgorithm, DecompressWriter};
+use super::encryption::{self, DecryptWriter, EncryptionParams, SALT_LEN, NONCE_LEN};
+use super::hash::{ContentHasher, HashAlgorithm, HashWriter};
+use super::metadata::{self, FileMetadata};
+use super::part_header::{self, PartHeader};
+use super::file_separation;
 
 #[derive(Debug)]
 pub struct FilePartDecode {
@@ -17,10 +25,6 @@ pub struct FilePartDecode {
 }
 
 
-struct MetaFile {
-
-}
-
 #[derive(Debug)]
 pub enum DecodeErrors {
     IOError(::std::io::Error),
@@ -28,6 +32,76 @@ pub enum DecodeErrors {
     IterationError,
     DecodePart(usize),
     PathParseError,
+    NotEnoughParityParts {
+        damaged_parts: usize,
+        parity_parts: usize,
+    },
+    UnknownHashAlgorithm(u8),
+    UnknownCompressionAlgorithm(u8),
+    UnknownEncryptionAlgorithm(u8),
+    KeyDerivationError(argon2::Error),
+    /// Файл зашифрован, но парольная фраза не передана
+    PassphraseRequired,
+    /// Неверная парольная фраза или измененный сборочный файл
+    WrongPassphrase,
+    /// Часть не прошла проверку подлинности при расшифровке
+    PartDecryptionFailed {
+        part_number: usize,
+        part_file_name: String,
+    },
+    /// Содержимое части не совпадает с хешем из сборочного файла
+    PartHashMismatch {
+        part_number: usize,
+        part_file_name: String,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+    /// Собранный файл не совпадает с хешем исходного файла
+    FileHashMismatch {
+        file_name: String,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+    /// Сборочный файл не найден или не является файлом
+    MetafileNotFound {
+        path: PathBuf,
+    },
+    /// Часть не найдена рядом со сборочным файлом
+    PartNotFound {
+        part_number: usize,
+        path: PathBuf,
+    },
+    /// Хеш имени в начале части не совпадает со сборочным файлом: часть от другого файла или повреждена
+    PartNameHashMismatch {
+        part_number: usize,
+        path: PathBuf,
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+    /// Кол-во хешей частей меньше записанного кол-ва частей
+    PartCountMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    /// Сборочный файл записан более новой версией
+    UnsupportedMetafileVersion(u16),
+    /// Сборочный файл использует неизвестные возможности
+    UnsupportedMetafileFlags(u32),
+    /// Сборочный файл обрезан или изменен
+    MetafileChecksumMismatch,
+    /// В папке нет частей с заголовком файла *uuid_parts*
+    PartSetNotFound {
+        uuid_parts: String,
+    },
+    /// Без этих частей файла сборочный файл не восстановить
+    IncompletePartSet {
+        uuid_parts: String,
+        missing_parts: Vec<usize>,
+    },
+    /// Содержимое части-описи не совпадает с хешем из ее заголовка или не разбирается
+    CorruptManifest {
+        part_file_name: String,
+    },
 }
 
 impl From<std::io::Error> for DecodeErrors {
@@ -48,26 +122,43 @@ pub struct PayLoadPart {
 }
 
 
-#[derive(Debug)]
-struct HashPart([u8;16]);
-
-impl HashPart {
-    fn read(src: &mut impl Read) -> Self {
+pub trait DecodeType: Sized {
+    fn decode_from_iter(iter: &mut impl Iterator<Item=u8>) -> Result<Self, DecodeErrors>;
+}
 
-        let mut bytes = [0_u8;16];
-        src.read(&mut bytes).unwrap();
+impl DecodeType for u8 {
+    fn decode_from_iter(iter: &mut impl Iterator<Item=u8>) -> Result<Self, DecodeErrors> {
+        iter.next().ok_or(DecodeErrors::IterationError)
+    }
+}
 
-        HashPart(bytes)
+impl DecodeType for u16 {
+    fn decode_from_iter(iter: &mut impl Iterator<Item=u8>) -> Result<Self, DecodeErrors> {
+        let mut buffer_bytes = [0_u8;2];
+        for byte in buffer_bytes.iter_mut() {
+            *byte = iter.next().ok_or(DecodeErrors::IterationError)?;
+        }
+        Ok(<u16>::from_be_bytes(buffer_bytes))
     }
 }
 
-pub trait DecodeType: Sized {
-    fn decode_from_iter(iter: &mut impl Iterator<Item=u8>) -> Result<Self, DecodeErrors>;
+impl DecodeType for u32 {
+    fn decode_from_iter(iter: &mut impl Iterator<Item=u8>) -> Result<Self, DecodeErrors> {
+        let mut buffer_bytes = [0_u8;4];
+        for byte in buffer_bytes.iter_mut() {
+            *byte = iter.next().ok_or(DecodeErrors::IterationError)?;
+        }
+        Ok(<u32>::from_be_bytes(buffer_bytes))
+    }
 }
 
-impl DecodeType for u8 {
+impl DecodeType for u64 {
     fn decode_from_iter(iter: &mut impl Iterator<Item=u8>) -> Result<Self, DecodeErrors> {
-        iter.next().ok_or(DecodeErrors::IterationError)
+        let mut buffer_bytes = [0_u8;8];
+        for byte in buffer_bytes.iter_mut() {
+            *byte = iter.next().ok_or(DecodeErrors::IterationError)?;
+        }
+        Ok(<u64>::from_be_bytes(buffer_bytes))
     }
 }
 
@@ -81,158 +172,1816 @@ impl DecodeType for usize {
     }
 }
 
-fn decode_str<T: DecodeType + Into<usize>>(iter: &mut impl Iterator<Item=u8>) -> Result<String, DecodeErrors> {
-    let len_str = T::decode_from_iter(iter)?.into();
+fn decode_str<T: DecodeType + TryInto<usize>>(iter: &mut impl Iterator<Item=u8>) -> Result<String, DecodeErrors> {
+    Ok(String::from_utf8(decode_bytes::<T>(iter)?)?)
+}
 
-    let mut output_str_bytes = Vec::with_capacity(len_str);
+fn decode_bytes<T: DecodeType + TryInto<usize>>(iter: &mut impl Iterator<Item=u8>) -> Result<Vec<u8>, DecodeErrors> {
+    let len_bytes = T::decode_from_iter(iter)?
+        .try_into()
+        .map_err(|_| DecodeErrors::IterationError)?;
 
-    for _ in 0..len_str {
-        output_str_bytes.push(
-            iter.next().ok_or(DecodeErrors::IterationError)?
-        );
+    let bytes = iter.take(len_bytes).collect::<Vec<u8>>();
+
+    if bytes.len() != len_bytes {
+        return Err(DecodeErrors::IterationError);
     }
 
-    Ok(String::from_utf8(output_str_bytes)?)
+    Ok(bytes)
 }
 
 pub fn decode_file(metafile_path: &PathBuf, path_for_save: PathBuf) -> Result<(), DecodeErrors> {
+    decode_file_with_options(metafile_path, Options {
+        path_for_save: Some(path_for_save),
+        ..Default::default()
+    })
+}
 
-    if !metafile_path.is_file() {
-        println!(
-            "\n!!!!!!!!!!!!!\nПредоставьте путь к файлу сборки\n!!!!!!!!!!!!!\n"
-        );
-        std::process::exit(2)
-    }
+/// Сборка файла, *path_for_save* обязателен, *passphrase* нужен для зашифрованных файлов
+pub fn decode_file_with_options(metafile_path: &Path, options: Options) -> Result<(), DecodeErrors> {
+
+    let path_for_save = options.path_for_save.clone().ok_or(DecodeErrors::PathParseError)?;
 
     if !path_for_save.is_dir() {
         return Err(DecodeErrors::PathParseError)
     }
 
-    let mut parts_folder = metafile_path.clone();
+    let (parts_folder, composite_file) = read_composite_file(metafile_path, options.passphrase.as_deref())?;
+
+    let output_path = path_for_save.join(composite_file.output_file_name());
+    metadata::remove_previous_output(&output_path)?;
+
+    let mut output_file = BufWriter::new(File::create(&output_path)?);
+
+    let res = assemble_parts(&parts_folder, &composite_file, &mut output_file, options.threads());
+    drop(output_file);
+
+    // Файл, не прошедший проверку, не должен выглядеть как успешно собранный
+    if res.is_err() {
+        let _ = fs::remove_file(&output_path);
+        return res;
+    }
+
+    if let Some(metadata) = &composite_file.metadata {
+        metadata.restore(&output_path, options.restore_ownership.unwrap_or(true))?;
+    }
+
+    Ok(())
+}
+
+/// Сборка файла в любой приемник (файл, *stdout*, канал), части ищутся рядом со сборочным файлом.
+/// Части проверяются по хешу до записи, но при ошибке расшифровки или несовпадении хеша файла
+/// в *output* могут остаться уже записанные данные
+pub fn decode_to_writer(metafile_path: &Path, output: &mut impl Write, options: Options) -> Result<(), DecodeErrors> {
+    let (parts_folder, composite_file) = read_composite_file(metafile_path, options.passphrase.as_deref())?;
+
+    assemble_parts(&parts_folder, &composite_file, output, options.threads())
+}
+
+/// Чтение сборочного файла и восстановление испорченных частей по паритетным частям
+fn read_composite_file(metafile_path: &Path, passphrase: Option<&str>) -> Result<(PathBuf, CompositeFile), DecodeErrors> {
+
+    if !metafile_path.is_file() {
+        return Err(DecodeErrors::MetafileNotFound { path: metafile_path.to_path_buf() });
+    }
+
+    let mut parts_folder = metafile_path.to_path_buf();
     parts_folder.pop();
 
     let mut metafile_bytes = vec![];
     File::open(&metafile_path)?.read_to_end(&mut metafile_bytes)?;
 
-    let mut metafile_bytes_iter = metafile_bytes.into_iter();
+    let composite_file = decode_metafile(metafile_bytes, passphrase)?;
+
+    if !composite_file.parity_parts.is_empty() {
+        restore_damaged_parts(&parts_folder, &composite_file)?;
+    }
+
+    Ok((parts_folder, composite_file))
+}
+
+/// Потоковая запись данных частей в *output*: в один поток расшифровка, распаковка и хеш файла идут через буфер,
+/// поэтому расход памяти не зависит от размера части. В несколько потоков части расшифровываются
+/// и распаковываются параллельно в память и записываются по порядку, одновременно в памяти до *threads* частей.
+/// Хеш содержимого части сверяется до записи ее данных
+fn assemble_parts(parts_folder: &Path, composite_file: &CompositeFile, output: &mut impl Write, threads: usize) -> Result<(), DecodeErrors> {
+
+    let mut output = HashWriter::new(output, file_hasher(composite_file));
+
+    if threads <= 1 {
+        for part_ind in 0..composite_file.parts.len() {
+            decode_part_data(parts_folder, composite_file, part_ind, &mut output)?;
+            // Создание евента для frontend
+        }
+    } else {
+        thread::scope(|scope| {
+            let mut in_progress = VecDeque::with_capacity(threads);
+
+            for part_ind in 0..composite_file.parts.len() {
+                in_progress.push_back(scope.spawn(move || {
+                    let mut part_data = vec![];
+                    decode_part_data(parts_folder, composite_file, part_ind, &mut part_data).map(|_| part_data)
+                }));
+
+                // Части записываются по порядку, следующая часть расшифровывается, пока пишется текущая
+                if in_progress.len() >= threads {
+                    let part_data = in_progress.pop_front().unwrap().join().expect("Поток сборки части завершился паникой")?;
+                    output.write_all(&part_data)?;
+                }
+            }
 
-    let source_filename = decode_str::<u8>(&mut metafile_bytes_iter)?;
-    let source_format = decode_str::<u8>(&mut metafile_bytes_iter)?;
-    let parts_uuid = decode_str::<u8>(&mut metafile_bytes_iter)?;
+            while let Some(handle) = in_progress.pop_front() {
+                let part_data = handle.join().expect("Поток сборки части завершился паникой")?;
+                output.write_all(&part_data)?;
+            }
+
+            Ok::<(), DecodeErrors>(())
+        })?;
+    }
+
+    let (output, file_hash) = output.finish();
+
+    check_file_hash(composite_file, file_hash)?;
+
+    output.flush()?;
+
+    Ok(())
+}
+
+/// Хеш собираемого файла, у зашифрованного файла с ключом.
+/// У старых сборочных файлов хеша нет, тогда он считается, но не сверяется
+fn file_hasher(composite_file: &CompositeFile) -> ContentHasher {
+    let hash_algorithm = composite_file.hash_algorithm.unwrap_or_default();
+
+    match &composite_file.encryption {
+        Some(cipher) => hash_algorithm.keyed_hasher(cipher.hash_key()),
+        None => hash_algorithm.hasher(),
+    }
+}
+
+fn check_file_hash(composite_file: &CompositeFile, file_hash: Vec<u8>) -> Result<(), DecodeErrors> {
+    if composite_file.hash_algorithm.is_some() && file_hash != composite_file.file_hash {
+        return Err(DecodeErrors::FileHashMismatch {
+            file_name: composite_file.output_file_name().to_string_lossy().to_string(),
+            expected: composite_file.file_hash.clone(),
+            actual: file_hash,
+        });
+    }
+
+    Ok(())
+}
+
+/// Сборка файла по одной части по порядку, пока следующие части еще скачиваются.
+/// Хеш каждой части сверяется до записи ее данных, хеш всего файла при *finish*
+pub struct PartAssembler<'a, W: Write> {
+    parts_folder: &'a PathBuf,
+    composite_file: &'a CompositeFile,
+    output: HashWriter<W>,
+    next_part: usize,
+}
+
+impl<'a, W: Write> PartAssembler<'a, W> {
+
+    pub fn new(parts_folder: &'a PathBuf, composite_file: &'a CompositeFile, output: W) -> Self {
+        Self {
+            parts_folder,
+            composite_file,
+            output: HashWriter::new(output, file_hasher(composite_file)),
+            next_part: 0,
+        }
+    }
+
+    /// Часть, которая будет записана следующей, *None* когда записаны все части
+    pub fn next_part(&self) -> Option<&'a FilePart> {
+        self.composite_file.parts.get(self.next_part)
+    }
+
+    /// Запись следующей части из *parts_folder*
+    pub fn append_part(&mut self) -> Result<(), DecodeErrors> {
+        decode_part_data(self.parts_folder, self.composite_file, self.next_part, &mut self.output)?;
+        self.next_part += 1;
+
+        Ok(())
+    }
+
+    /// Сверка хеша собранного файла, все части должны быть записаны
+    pub fn finish(self) -> Result<W, DecodeErrors> {
+        if self.next_part != self.composite_file.parts.len() {
+            return Err(DecodeErrors::PartNotFound {
+                part_number: self.next_part + 1,
+                path: self.parts_folder.join(&self.composite_file.parts[self.next_part].part_file_name),
+            });
+        }
 
-    let mut output_file = File::create(
-        format!("{}{}.{}",path_for_save.display(), source_filename, source_format)
+        let (mut output, file_hash) = self.output.finish();
+
+        check_file_hash(self.composite_file, file_hash)?;
+
+        output.flush()?;
+
+        Ok(output)
+    }
+}
+
+/// Запись исходных данных одной части в *output*, хеш содержимого части сверяется до записи
+fn decode_part_data(parts_folder: &Path, composite_file: &CompositeFile, part_ind: usize, output: &mut impl Write) -> Result<(), DecodeErrors> {
+
+    let file_part = &composite_file.parts[part_ind];
+    let mut part = decode_part(
+        &parts_folder,
+        &file_part.part_file_name,
+        part_ind+1,
+        &file_part.hash_bytes
     )?;
 
-    // Остаточные байты представляют из себя массив хешей,
-    // где кол-во хешей берется из контекста
-    let count_parts = <usize>::decode_from_iter(&mut metafile_bytes_iter)?;
-    let parts_hashes = metafile_bytes_iter.collect::<Vec<u8>>();
+    part.file.seek(SeekFrom::Start(composite_file.part_data_offset()))?;
 
-    if count_parts != parts_hashes.len()/16 {
-        println!(
-            "\n!!!!!!!!!!!!!\nОшибка кол-ва частей в сборочном файле.\n!!!!!!!!!!!!!\n"
-        );
-        std::process::exit(2)
+    if let Some(hash_algorithm) = composite_file.hash_algorithm {
+        let mut hasher = hash_algorithm.hasher();
+        io::copy(&mut BufReader::new(&mut part.file), &mut hasher)?;
+
+        let content_hash = hasher.finalize();
+
+        if content_hash != file_part.content_hash {
+            return Err(DecodeErrors::PartHashMismatch {
+                part_number: part_ind + 1,
+                part_file_name: part.part_file_name,
+                expected: file_part.content_hash.clone(),
+                actual: content_hash,
+            });
+        }
+
+        part.file.seek(SeekFrom::Start(composite_file.part_data_offset()))?;
     }
 
-    parts_hashes
-        .chunks(16)
-        .enumerate()
-        .map(|(part_ind, part_hash)| {
-            let mut part = decode_part(
-                &parts_folder,
-                &parts_uuid,
-                part_ind+1,
-                part_hash
-            );
+    let compression = composite_file.compression
+        .filter(|_| file_part.compressed)
+        .map(|(compression_algorithm, _)| compression_algorithm);
 
-            let mut bytes_part = vec![];
-            // При большой чатси уходит много ОЗУ
+    let decompress_writer = DecompressWriter::new(compression, output)?;
+    let mut decrypt_writer = DecryptWriter::new(
+        composite_file.encryption.as_ref(),
+        (part_ind + 1) as u32,
+        decompress_writer
+    );
 
-            let mut parts_folder_cl = parts_folder.clone();
-            parts_folder_cl.push(part.part_file_name);
+    let decryption_error = |e: io::Error| match encryption::is_decryption_failed(&e) {
+        true => DecodeErrors::PartDecryptionFailed {
+            part_number: part_ind + 1,
+            part_file_name: file_part.part_file_name.clone(),
+        },
+        false => DecodeErrors::IOError(e),
+    };
 
-            part.file.read_to_end(&mut bytes_part)?;
+    io::copy(&mut BufReader::new(&mut part.file), &mut decrypt_writer).map_err(decryption_error)?;
+    decrypt_writer.finish().map_err(decryption_error)?.finish()?;
 
-            output_file.write(&bytes_part)?;
-            // Создание евента для frontend
-            Ok::<(), DecodeErrors>(())
-        })
-        .all(|res|
-            if res.is_ok() {
-                true
-            } else {
-                println!(
-                    "\n!!!!!!!!!!!!!\nНекорректное декодирование части с ошибкой {:?}\n!!!!!!!!!!!!!\n",
-                    res.err().unwrap()
-                );
-                std::process::exit(2)
-            }
-        );
+    Ok(())
+}
+
+/// Часть, пересекающаяся с читаемым диапазоном файла
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartRange {
+    pub part_ind: usize,
+    /// Смещение диапазона в исходных данных части
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// Чтение сборочного файла без сборки, чтобы узнать имена и границы частей
+pub fn read_metafile(metafile_path: &Path, passphrase: Option<&str>) -> Result<CompositeFile, DecodeErrors> {
+
+    if !metafile_path.is_file() {
+        return Err(DecodeErrors::MetafileNotFound { path: metafile_path.to_path_buf() });
+    }
+
+    decode_metafile(fs::read(metafile_path)?, passphrase)
+}
+
+/// Части, в которых лежат *len* байтов исходного файла начиная с *offset*.
+/// Диапазон за концом файла обрезается.
+/// *None*, если сборочный файл записан до появления длин частей
+pub fn part_ranges(composite_file: &CompositeFile, offset: u64, len: u64) -> Option<Vec<PartRange>> {
+
+    let end = offset.saturating_add(len);
+    let mut part_start = 0_u64;
+    let mut ranges = vec![];
+
+    for (part_ind, part) in composite_file.parts.iter().enumerate() {
+        let part_end = part_start + part.data_len?;
+        let range_start = offset.max(part_start);
+        let range_end = end.min(part_end);
+
+        if range_start < range_end {
+            ranges.push(PartRange {
+                part_ind,
+                offset: range_start - part_start,
+                len: range_end - range_start,
+            });
+        }
+
+        part_start = part_end;
+    }
+
+    Some(ranges)
+}
+
+/// Положение байтов диапазона в файле части: смещение от начала файла и длина.
+/// Сжатую часть можно прочитать только целиком, тогда возвращается *None*.
+/// У зашифрованной части берутся все сегменты, в которые попадает диапазон
+pub fn stored_range(composite_file: &CompositeFile, range: &PartRange) -> Option<(u64, u64)> {
+
+    let part = &composite_file.parts[range.part_ind];
+
+    if part.compressed || range.len == 0 {
+        return None;
+    }
+
+    let data_offset = composite_file.part_data_offset();
+
+    if composite_file.encryption.is_none() {
+        return Some((data_offset + range.offset, range.len));
+    }
+
+    let segment_size = encryption::SEGMENT_SIZE as u64;
+    let stored_segment_size = segment_size + encryption::TAG_LEN as u64;
+
+    let first_segment = range.offset / segment_size;
+    let last_segment = (range.offset + range.len - 1) / segment_size;
+
+    let start = first_segment * stored_segment_size;
+    let end = ((last_segment + 1) * stored_segment_size).min(encryption::encrypted_len(part.data_len?));
+
+    Some((data_offset + start, end - start))
+}
+
+/// Запись диапазона из части, скачанной целиком. Хеш содержимого части сверяется до записи
+pub fn decode_part_range(parts_folder: &Path, composite_file: &CompositeFile, range: &PartRange, output: &mut impl Write) -> Result<(), DecodeErrors> {
+
+    let mut range_writer = RangeWriter {
+        inner: output,
+        skip: range.offset,
+        len: range.len,
+    };
+
+    decode_part_data(parts_folder, composite_file, range.part_ind, &mut range_writer)?;
+
+    // Часть короче записанной в сборочном файле длины
+    if range_writer.len != 0 {
+        return Err(DecodeErrors::DecodePart(range.part_ind + 1));
+    }
 
     Ok(())
 }
 
-fn decode_input_file_extension(src: &mut impl Read) -> String {
-    let mut extension_bytes_len = [0_u8;8];
-    src.read( &mut extension_bytes_len).unwrap();
-    let mut extension_len =<usize>::from_be_bytes(extension_bytes_len);
+/// Запись диапазона из байтов *stored_range*, скачанных без остальной части.
+/// Хеш содержимого всей части сверить нельзя: зашифрованные сегменты проверяются при расшифровке,
+/// а данные незашифрованной части записываются как есть
+pub fn decode_stored_range(stored_bytes: &[u8], composite_file: &CompositeFile, range: &PartRange, output: &mut impl Write) -> Result<(), DecodeErrors> {
+
+    let part = &composite_file.parts[range.part_ind];
+
+    let Some(cipher) = &composite_file.encryption else {
+        if stored_bytes.len() as u64 != range.len {
+            return Err(DecodeErrors::DecodePart(range.part_ind + 1));
+        }
+
+        output.write_all(stored_bytes)?;
+        return Ok(());
+    };
+
+    let data_len = part.data_len.ok_or(DecodeErrors::DecodePart(range.part_ind + 1))?;
+    let segment_size = encryption::SEGMENT_SIZE as u64;
+    let first_segment = range.offset / segment_size;
+    let last_part_segment = data_len.div_ceil(segment_size).max(1) - 1;
+
+    let mut range_writer = RangeWriter {
+        inner: output,
+        skip: range.offset - first_segment * segment_size,
+        len: range.len,
+    };
+
+    for (ind, segment) in stored_bytes.chunks(encryption::SEGMENT_SIZE + encryption::TAG_LEN).enumerate() {
+        let segment_ind = first_segment + ind as u64;
+
+        let data = cipher
+            .open_segment((range.part_ind + 1) as u32, segment_ind as u32, segment_ind == last_part_segment, segment)
+            .ok_or(DecodeErrors::PartDecryptionFailed {
+                part_number: range.part_ind + 1,
+                part_file_name: part.part_file_name.clone(),
+            })?;
+
+        range_writer.write_all(&data)?;
+    }
 
-    let mut extension_bytes = Vec::with_capacity(extension_len);
-    unsafe {
-        extension_bytes.set_len(extension_len);
+    if range_writer.len != 0 {
+        return Err(DecodeErrors::DecodePart(range.part_ind + 1));
     }
 
-    src.read(&mut extension_bytes).unwrap();
+    Ok(())
+}
 
-    String::from_utf8(extension_bytes).unwrap()
+/// Запись, пропускающая первые *skip* байтов и отбрасывающая все после следующих *len* байтов
+struct RangeWriter<W: Write> {
+    inner: W,
+    skip: u64,
+    len: u64,
 }
 
-fn decode_metafile() -> MetaFile {
-    MetaFile {
+impl<W: Write> Write for RangeWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let skipped = self.skip.min(buf.len() as u64) as usize;
+        self.skip -= skipped as u64;
+
+        let data = &buf[skipped..];
+        let taken = self.len.min(data.len() as u64) as usize;
+        self.inner.write_all(&data[..taken])?;
+        self.len -= taken as u64;
 
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
 
-fn decode_part(parts_folder: &PathBuf, part_uuid: &str, part_number: usize, part_hash: &[u8]) -> FilePartDecode {
+/// Ширина длин строк и кол-ва частей в сборочном файле
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MetafileLayout {
+    /// Длины строк *u8*, кол-во частей *usize*
+    Narrow,
+    /// Длины строк *u32*, кол-во частей *u64*
+    Wide,
+}
 
-    let part_file_name = format!("{}_{}.part", part_uuid, part_number);
-    let mut part_path = parts_folder.clone();
-    part_path.push(part_file_name.clone());
-    println!("ДЕЮАГ ИНФА: {:?}", &part_path);
+impl MetafileLayout {
+    fn decode_str(self, iter: &mut impl Iterator<Item=u8>) -> Result<String, DecodeErrors> {
+        match self {
+            MetafileLayout::Narrow => decode_str::<u8>(iter),
+            MetafileLayout::Wide => decode_str::<u32>(iter),
+        }
+    }
+}
 
-    let mut part_file = if let Ok(f) = File::open(&part_path) {
-        f
-    } else {
-        println!(
-            "\n!!!!!!!!!!!!!\nНеудается найти {} часть в текущей папке.\n!!!!!!!!!!!!!\n",
-            part_number
-        );
-        std::process::exit(2)
+/// Проверка заголовка и контрольной суммы сборочного файла.
+/// Возвращает версию и данные сборочного файла без заголовка и контрольной суммы
+fn read_metafile_header(mut metafile_bytes: Vec<u8>) -> Result<(u16, Vec<u8>), DecodeErrors> {
+
+    // Сборочные файлы, записанные до появления заголовка
+    if !metafile_bytes.starts_with(&metafile_format::MAGIC) {
+        return Ok(match metafile_bytes.starts_with(&metafile_format::WIDE_MARKER) {
+            true => (metafile_format::VERSION_WIDE, metafile_bytes.split_off(metafile_format::WIDE_MARKER.len())),
+            false => (metafile_format::VERSION_NARROW, metafile_bytes),
+        });
+    }
+
+    if metafile_bytes.len() < metafile_format::HEADER_LEN + metafile_format::CHECKSUM_LEN {
+        return Err(DecodeErrors::MetafileChecksumMismatch);
+    }
+
+    let mut header_iter = metafile_bytes[metafile_format::MAGIC.len()..metafile_format::HEADER_LEN]
+        .iter()
+        .copied();
+    let version = <u16>::decode_from_iter(&mut header_iter)?;
+    let flags = <u32>::decode_from_iter(&mut header_iter)?;
+
+    // Версия проверяется до контрольной суммы: в новых версиях она может считаться иначе
+    if version != metafile_format::VERSION_CURRENT {
+        return Err(DecodeErrors::UnsupportedMetafileVersion(version));
+    }
+
+    let checksum = metafile_bytes.split_off(metafile_bytes.len() - metafile_format::CHECKSUM_LEN);
+
+    if md5::compute(&metafile_bytes).0[..] != checksum[..] {
+        return Err(DecodeErrors::MetafileChecksumMismatch);
+    }
+
+    if flags & !metafile_flags::ALL != 0 {
+        return Err(DecodeErrors::UnsupportedMetafileFlags(flags & !metafile_flags::ALL));
+    }
+
+    Ok((version, metafile_bytes.split_off(metafile_format::HEADER_LEN)))
+}
+
+/// Разбор сборочного файла
+fn decode_metafile(metafile_bytes: Vec<u8>, passphrase: Option<&str>) -> Result<CompositeFile, DecodeErrors> {
+
+    let (version, metafile_bytes) = read_metafile_header(metafile_bytes)?;
+
+    let layout = match version {
+        metafile_format::VERSION_NARROW => MetafileLayout::Narrow,
+        _ => MetafileLayout::Wide,
     };
 
-    let mut tmp_hash = [0_u8;16];
-    part_file.read(&mut tmp_hash).unwrap();
+    let mut metafile_bytes_iter = metafile_bytes.into_iter();
 
-    let hash_bytes = tmp_hash
-        .into_iter()
-        .enumerate()
-        // Сравнение хеша, полученного из сброчного файла и хеша в файле
-        .filter_map(|(index, byte)| if byte == part_hash[index] {Some(byte)} else {None} )
-        .collect::<Vec<_>>();
+    let source_filename = layout.decode_str(&mut metafile_bytes_iter)?;
+    let source_format = layout.decode_str(&mut metafile_bytes_iter)?;
+    let parts_uuid = layout.decode_str(&mut metafile_bytes_iter)?;
 
-    if hash_bytes.len() != 16 {
-        println!(
-            "\n!!!!!!!!!!!!!\nХеш {} части не совпадает.\n!!!!!!!!!!!!!\n",
-            part_number
-        );
-        std::process::exit(2)
+    // Далее идет массив хешей, где кол-во хешей берется из контекста
+    let count_parts = match layout {
+        MetafileLayout::Narrow => <usize>::decode_from_iter(&mut metafile_bytes_iter)?,
+        MetafileLayout::Wide => usize::try_from(<u64>::decode_from_iter(&mut metafile_bytes_iter)?)
+            .map_err(|_| DecodeErrors::IterationError)?,
+    };
+    let parts_hashes = metafile_bytes_iter
+        .by_ref()
+        .take(count_parts.saturating_mul(16))
+        .collect::<Vec<u8>>();
+
+    if count_parts != parts_hashes.len()/16 {
+        return Err(DecodeErrors::PartCountMismatch {
+            expected: count_parts,
+            actual: parts_hashes.len()/16,
+        });
     }
 
-    FilePartDecode {
-        file: part_file,
-        hash_bytes,
-        part_file_name,
+    let mut composite_file = CompositeFile {
+        filename: source_filename,
+        file_extension: source_format,
+        file_len: 0,
+        parts: parts_hashes
+            .chunks(16)
+            .enumerate()
+            .map(|(part_ind, part_hash)| FilePart {
+                hash_bytes: part_hash.to_vec(),
+                part_file_name: format!("{}_{}.part", parts_uuid, part_ind + 1),
+                part_len: 0,
+                content_hash: vec![],
+                compressed: false,
+                data_len: None,
+            })
+            .collect(),
+        parity_parts: vec![],
+        uuid_parts: parts_uuid,
+        hash_algorithm: None,
+        file_hash: vec![],
+        compression: None,
+        encryption: None,
+        content_defined_chunks: false,
+        metadata: None,
+        file_name: vec![],
+        part_header_len: 0,
+    };
+
+    let mut sections = vec![];
+
+    // Остаточные байты представляют из себя дополнительные секции
+    while let Some(tag) = metafile_bytes_iter.next() {
+        let section_len = <u64>::decode_from_iter(&mut metafile_bytes_iter)? as usize;
+        let section = metafile_bytes_iter
+            .by_ref()
+            .take(section_len)
+            .collect::<Vec<u8>>();
+
+        if section.len() != section_len {
+            return Err(DecodeErrors::IterationError);
+        }
+
+        sections.push((tag, section));
+    }
+
+    // Секции разбираются по возрастанию тега, так как поздние секции ссылаются на ранние
+    sections.sort_by_key(|(tag, _)| *tag);
+
+    for (tag, section) in sections {
+        match tag {
+            metafile_section::PARITY => decode_parity_section(&mut composite_file, section)?,
+            metafile_section::HASHES => decode_hashes_section(&mut composite_file, section)?,
+            metafile_section::COMPRESSION => decode_compression_section(&mut composite_file, section)?,
+            metafile_section::ENCRYPTION => decode_encryption_section(&mut composite_file, section, passphrase, layout)?,
+            metafile_section::CHUNKS => decode_chunks_section(&mut composite_file, section)?,
+            metafile_section::METADATA => decode_metadata_section(&mut composite_file, section)?,
+            metafile_section::FILE_NAME => decode_file_name_section(&mut composite_file, section)?,
+            metafile_section::PART_SIZES => decode_part_sizes_section(&mut composite_file, section)?,
+            metafile_section::PART_HEADERS => decode_part_headers_section(&mut composite_file, section)?,
+            _ => println!("Пропущена неизвестная секция сборочного файла {}", tag),
+        }
+    }
+
+    Ok(composite_file)
+}
+
+/// Разбор секции паритетных частей
+fn decode_parity_section(composite_file: &mut CompositeFile, section: Vec<u8>) -> Result<(), DecodeErrors> {
+
+    let mut section_iter = section.into_iter();
+
+    let count_parity_parts = <u8>::decode_from_iter(&mut section_iter)? as usize;
+
+    for part in composite_file.parts.iter_mut() {
+        part.part_len = <u64>::decode_from_iter(&mut section_iter)?;
+    }
+
+    let parity_len = composite_file.parts
+        .iter()
+        .map(|part| part.part_len)
+        .max()
+        .unwrap_or(0);
+
+    for parity_ind in 0..count_parity_parts {
+        let hash_bytes = section_iter.by_ref().take(16).collect::<Vec<u8>>();
+
+        if hash_bytes.len() != 16 {
+            return Err(DecodeErrors::IterationError);
+        }
+
+        composite_file.parity_parts.push(FilePart {
+            hash_bytes,
+            part_file_name: format!(
+                "{}_{}.part",
+                composite_file.uuid_parts,
+                composite_file.parts.len() + parity_ind + 1
+            ),
+            part_len: parity_len,
+            content_hash: vec![],
+            compressed: false,
+            data_len: None,
+        });
+    }
+
+    Ok(())
+}
+
+/// Разбор секции хешей содержимого
+fn decode_hashes_section(composite_file: &mut CompositeFile, section: Vec<u8>) -> Result<(), DecodeErrors> {
+
+    let mut section_iter = section.into_iter();
+
+    let algorithm_id = <u8>::decode_from_iter(&mut section_iter)?;
+    let hash_algorithm = HashAlgorithm::from_id(algorithm_id)
+        .ok_or(DecodeErrors::UnknownHashAlgorithm(algorithm_id))?;
+    let hash_len = <u8>::decode_from_iter(&mut section_iter)? as usize;
+
+    let mut read_hash = || {
+        let hash = section_iter.by_ref().take(hash_len).collect::<Vec<u8>>();
+
+        match hash.len() == hash_len {
+            true => Ok(hash),
+            false => Err(DecodeErrors::IterationError),
+        }
+    };
+
+    composite_file.file_hash = read_hash()?;
+
+    for part in composite_file.parts.iter_mut().chain(composite_file.parity_parts.iter_mut()) {
+        part.content_hash = read_hash()?;
+    }
+
+    composite_file.hash_algorithm = Some(hash_algorithm);
+
+    Ok(())
+}
+
+/// Разбор секции сжатия частей
+fn decode_compression_section(composite_file: &mut CompositeFile, section: Vec<u8>) -> Result<(), DecodeErrors> {
+
+    let mut section_iter = section.into_iter();
+
+    let algorithm_id = <u8>::decode_from_iter(&mut section_iter)?;
+    let compression_algorithm = CompressionAlgorithm::from_id(algorithm_id)
+        .ok_or(DecodeErrors::UnknownCompressionAlgorithm(algorithm_id))?;
+
+    let mut level_bytes = [0_u8; 4];
+    for byte in level_bytes.iter_mut() {
+        *byte = <u8>::decode_from_iter(&mut section_iter)?;
+    }
+
+    for part in composite_file.parts.iter_mut() {
+        part.compressed = <u8>::decode_from_iter(&mut section_iter)? != 0;
+    }
+
+    composite_file.compression = Some((compression_algorithm, i32::from_be_bytes(level_bytes)));
+
+    Ok(())
+}
+
+/// Разбор секции частей, названных по хешу содержимого.
+/// Хеши содержимого к этому моменту уже прочитаны из секции хешей
+fn decode_chunks_section(composite_file: &mut CompositeFile, section: Vec<u8>) -> Result<(), DecodeErrors> {
+
+    if section.first() != Some(&CHUNK_NAMES_CONTENT_HASH) || composite_file.hash_algorithm.is_none() {
+        return Err(DecodeErrors::IterationError);
+    }
+
+    for part in composite_file.parts.iter_mut() {
+        part.part_file_name = chunk_file_name(&part.content_hash);
+    }
+
+    composite_file.content_defined_chunks = true;
+
+    Ok(())
+}
+
+/// Разбор секции сведений об исходном файле.
+/// Секция шифрования к этому моменту уже разобрана, поэтому зашифрованная секция расшифровывается ее ключом
+fn decode_metadata_section(composite_file: &mut CompositeFile, section: Vec<u8>) -> Result<(), DecodeErrors> {
+
+    let section = match &composite_file.encryption {
+        Some(cipher) => cipher
+            .open_metadata(encryption::METADATA_FILE_ATTRIBUTES, &section)
+            .ok_or(DecodeErrors::WrongPassphrase)?,
+        None => section,
+    };
+
+    let mut section_iter = section.into_iter();
+
+    let mut metadata = FileMetadata {
+        size: <u64>::decode_from_iter(&mut section_iter)?,
+        ..Default::default()
+    };
+
+    let fields = <u8>::decode_from_iter(&mut section_iter)?;
+
+    if fields & 1 != 0 {
+        let secs = <u64>::decode_from_iter(&mut section_iter)? as i64;
+        let nanos = <u32>::decode_from_iter(&mut section_iter)?;
+        metadata.modified = Some(metadata::from_unix_time(secs, nanos));
+    }
+
+    for (ind, field) in [&mut metadata.mode, &mut metadata.uid, &mut metadata.gid].into_iter().enumerate() {
+        if fields & (1 << (ind + 1)) != 0 {
+            *field = Some(<u32>::decode_from_iter(&mut section_iter)?);
+        }
+    }
+
+    let count_xattrs = <u32>::decode_from_iter(&mut section_iter)?;
+
+    for _ in 0..count_xattrs {
+        metadata.xattrs.push((
+            decode_bytes::<u32>(&mut section_iter)?,
+            decode_bytes::<u32>(&mut section_iter)?,
+        ));
+    }
+
+    composite_file.metadata = Some(metadata);
+
+    Ok(())
+}
+
+/// Разбор секции полного имени исходного файла
+fn decode_file_name_section(composite_file: &mut CompositeFile, section: Vec<u8>) -> Result<(), DecodeErrors> {
+
+    composite_file.file_name = match &composite_file.encryption {
+        Some(cipher) => cipher
+            .open_metadata(encryption::METADATA_FULL_FILE_NAME, &section)
+            .ok_or(DecodeErrors::WrongPassphrase)?,
+        None => section,
+    };
+
+    // Имя из нескольких компонентов пути вывело бы собранный файл за пределы папки сохранения
+    if composite_file.file_name.contains(&b'/') || matches!(composite_file.file_name.as_slice(), b"." | b"..") {
+        return Err(DecodeErrors::PathParseError);
+    }
+
+    Ok(())
+}
+
+/// Разбор секции длин исходных данных частей
+fn decode_part_sizes_section(composite_file: &mut CompositeFile, section: Vec<u8>) -> Result<(), DecodeErrors> {
+
+    let section = match &composite_file.encryption {
+        Some(cipher) => cipher
+            .open_metadata(encryption::METADATA_PART_SIZES, &section)
+            .ok_or(DecodeErrors::WrongPassphrase)?,
+        None => section,
+    };
+
+    let mut section_iter = section.into_iter();
+
+    for part in composite_file.parts.iter_mut() {
+        part.data_len = Some(<u64>::decode_from_iter(&mut section_iter)?);
+    }
+
+    Ok(())
+}
+
+/// Разбор секции заголовков частей
+fn decode_part_headers_section(composite_file: &mut CompositeFile, section: Vec<u8>) -> Result<(), DecodeErrors> {
+    composite_file.part_header_len = <u64>::decode_from_iter(&mut section.into_iter())?;
+
+    Ok(())
+}
+
+/// Разбор секции шифрования, имя и расширение файла расшифровываются ключом из парольной фразы
+fn decode_encryption_section(
+    composite_file: &mut CompositeFile,
+    section: Vec<u8>,
+    passphrase: Option<&str>,
+    layout: MetafileLayout
+) -> Result<(), DecodeErrors> {
+
+    let mut section_iter = section.into_iter();
+
+    let algorithm_id = <u8>::decode_from_iter(&mut section_iter)?;
+    if algorithm_id != encryption::CHACHA20_POLY1305 {
+        return Err(DecodeErrors::UnknownEncryptionAlgorithm(algorithm_id));
+    }
+
+    let m_cost = <u32>::decode_from_iter(&mut section_iter)?;
+    let t_cost = <u32>::decode_from_iter(&mut section_iter)?;
+    let p_cost = <u32>::decode_from_iter(&mut section_iter)?;
+
+    let mut salt = [0_u8; SALT_LEN];
+    let mut base_nonce = [0_u8; NONCE_LEN];
+    for byte in salt.iter_mut().chain(base_nonce.iter_mut()) {
+        *byte = <u8>::decode_from_iter(&mut section_iter)?;
+    }
+
+    let sealed_file_names = section_iter.collect::<Vec<u8>>();
+
+    let passphrase = passphrase.ok_or(DecodeErrors::PassphraseRequired)?;
+
+    let cipher = EncryptionParams { salt, base_nonce, m_cost, t_cost, p_cost }
+        .derive_cipher(passphrase)
+        .map_err(DecodeErrors::KeyDerivationError)?;
+
+    let file_names = cipher
+        .open_metadata(encryption::METADATA_FILE_NAMES, &sealed_file_names)
+        .ok_or(DecodeErrors::WrongPassphrase)?;
+
+    let mut file_names_iter = file_names.into_iter();
+    composite_file.filename = layout.decode_str(&mut file_names_iter)?;
+    composite_file.file_extension = layout.decode_str(&mut file_names_iter)?;
+    composite_file.encryption = Some(cipher);
+
+    Ok(())
+}
+
+/// Часть на месте, ее хеш совпадает со сборочным файлом, а длина с записанной при разделении.
+/// Если известен хеш содержимого, сверяется и он. Длина частей известна только при паритетных частях
+pub fn check_part(parts_folder: &Path, composite_file: &CompositeFile, part: &FilePart) -> bool {
+
+    let Ok(mut part_file) = File::open(parts_folder.join(&part.part_file_name)) else {
+        return false;
+    };
+
+    let expected_len = composite_file.part_data_offset() + part.part_len;
+
+    if part.part_len != 0 && part_file.metadata().map(|metadata| metadata.len()).ok() != Some(expected_len) {
+        return false;
+    }
+
+    let mut hash_bytes = vec![0_u8; part.hash_bytes.len()];
+
+    if part_file.read_exact(&mut hash_bytes).is_err() || hash_bytes != part.hash_bytes {
+        return false;
+    }
+
+    if part_file.seek(SeekFrom::Start(composite_file.part_data_offset())).is_err() {
+        return false;
+    }
+
+    match composite_file.hash_algorithm {
+        Some(hash_algorithm) => {
+            let mut hasher: ContentHasher = hash_algorithm.hasher();
+
+            io::copy(&mut BufReader::new(part_file), &mut hasher).is_ok()
+                && hasher.finalize() == part.content_hash
+        },
+        None => true,
+    }
+}
+
+/// Часть-опись в *parts_folder* совпадает с описью по сборочному файлу
+pub fn check_manifest_part(parts_folder: &Path, composite_file: &CompositeFile) -> bool {
+    match part_header::manifest_part(composite_file) {
+        Some((part_file_name, part_bytes)) => fs::read(parts_folder.join(part_file_name)).is_ok_and(|bytes| bytes == part_bytes),
+        None => false,
+    }
+}
+
+/// Сборочный файл не обрезан и не изменен. Проверяется только контрольная сумма,
+/// поэтому парольная фраза не нужна. У старых сборочных файлов без контрольной суммы проверяется только наличие
+pub fn check_metafile(metafile_path: &Path) -> bool {
+    fs::read(metafile_path).is_ok_and(|metafile_bytes| read_metafile_header(metafile_bytes).is_ok())
+}
+
+/// Восстанавливает по паритетным частям испорченные части файла и пересчитывает испорченные паритетные части.
+/// Испорченная часть-опись записывается заново по сборочному файлу.
+/// Остальные части должны лежать в *parts_folder*. Возвращает имена перезаписанных частей
+pub fn repair_parts(parts_folder: &Path, composite_file: &CompositeFile) -> Result<Vec<String>, DecodeErrors> {
+
+    let mut repaired_names = vec![];
+
+    if let Some((part_file_name, part_bytes)) = part_header::manifest_part(composite_file) {
+        if !check_manifest_part(parts_folder, composite_file) {
+            fs::write(parts_folder.join(&part_file_name), part_bytes)?;
+            repaired_names.push(part_file_name);
+        }
+    }
+
+    if composite_file.parity_parts.is_empty() {
+        return Ok(repaired_names);
+    }
+
+    let damaged = |part: &&FilePart| !check_part(parts_folder, composite_file, part);
+
+    let damaged_parts = composite_file.parts
+        .iter()
+        .filter(damaged)
+        .map(|part| part.part_file_name.clone())
+        .collect::<Vec<_>>();
+
+    let damaged_parity_parts = composite_file.parity_parts
+        .iter()
+        .map(|part| damaged(&part))
+        .collect::<Vec<_>>();
+
+    if !damaged_parts.is_empty() {
+        restore_damaged_parts(parts_folder, composite_file)?;
+        repaired_names.extend(damaged_parts);
+    }
+
+    // Паритетные части пересчитываются по уже целым частям файла, исправные паритетные части не перезаписываются
+    if damaged_parity_parts.contains(&true) {
+        rewrite_parity_parts(parts_folder, composite_file, &damaged_parity_parts)?;
+
+        repaired_names.extend(
+            composite_file.parity_parts
+                .iter()
+                .zip(&damaged_parity_parts)
+                .filter(|(_, &damaged)| damaged)
+                .map(|(part, _)| part.part_file_name.clone())
+        );
+    }
+
+    repaired_names.sort();
+    repaired_names.dedup();
+
+    Ok(repaired_names)
+}
+
+/// Пересчет отмеченных в *rewrite* паритетных частей по частям файла. Возвращает хеши содержимого
+/// пересчитанных частей, у остальных хеши пустые
+fn rewrite_parity_parts(parts_folder: &Path, composite_file: &CompositeFile, rewrite: &[bool]) -> io::Result<Vec<Vec<u8>>> {
+
+    let mut data_parts = composite_file.parts
+        .iter()
+        .map(|part| open_part_data(parts_folder, composite_file, part))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let data_lens = composite_file.parts
+        .iter()
+        .map(|part| part.part_len)
+        .collect::<Vec<_>>();
+
+    let hash_algorithm = composite_file.hash_algorithm.unwrap_or_default();
+
+    let mut parity_files = composite_file.parity_parts
+        .iter()
+        .enumerate()
+        .zip(rewrite)
+        .map(|((parity_ind, part), &rewrite)| -> io::Result<HashWriter<Box<dyn Write>>> {
+            if !rewrite {
+                return Ok(HashWriter::new(Box::new(io::sink()), hash_algorithm.hasher()));
+            }
+
+            let mut part_file = File::create(parts_folder.join(&part.part_file_name))?;
+            write_part_start(&mut part_file, composite_file, composite_file.parts.len() + parity_ind)?;
+
+            Ok(HashWriter::new(Box::new(BufWriter::new(part_file)), hash_algorithm.hasher()))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    parity::encode_parity(&mut data_parts, &data_lens, &mut parity_files)?;
+
+    parity_files
+        .into_iter()
+        .zip(rewrite)
+        .map(|(parity_file, &rewrite)| {
+            let (mut parity_file, content_hash) = parity_file.finish();
+            parity_file.flush()?;
+
+            Ok(match rewrite {
+                true => content_hash,
+                false => vec![],
+            })
+        })
+        .collect()
+}
+
+/// Открытие части, позиционированной на начало данных
+fn open_part_data(parts_folder: &Path, composite_file: &CompositeFile, part: &FilePart) -> io::Result<BufReader<File>> {
+    let mut part_file = File::open(parts_folder.join(&part.part_file_name))?;
+    part_file.seek(SeekFrom::Start(composite_file.part_data_offset()))?;
+
+    Ok(BufReader::new(part_file))
+}
+
+/// Запись начала части перед данными: хеша имени и заголовка, если он есть у файла.
+/// *part_ind* считается по частям файла, затем по паритетным частям
+fn write_part_start(part_file: &mut impl Write, composite_file: &CompositeFile, part_ind: usize) -> io::Result<()> {
+    let part = composite_file.parts
+        .iter()
+        .chain(&composite_file.parity_parts)
+        .nth(part_ind)
+        .expect("Номер части за пределами файла");
+
+    part_file.write_all(&part.hash_bytes)?;
+
+    if composite_file.part_header_len != 0 {
+        part_file.write_all(&PartHeader::for_part(composite_file, part_ind).encode())?;
+    }
+
+    Ok(())
+}
+
+/// Восстанавливает потерянные и испорченные части по паритетным частям
+fn restore_damaged_parts(parts_folder: &Path, composite_file: &CompositeFile) -> Result<(), DecodeErrors> {
+
+    let damaged_parts = composite_file.parts
+        .iter()
+        .enumerate()
+        .filter(|(_, part)| !check_part(parts_folder, composite_file, part))
+        .map(|(part_ind, _)| part_ind)
+        .collect::<Vec<_>>();
+
+    if damaged_parts.is_empty() {
+        return Ok(());
+    }
+
+    let mut data_parts = composite_file.parts
+        .iter()
+        .enumerate()
+        .map(|(part_ind, part)| match damaged_parts.contains(&part_ind) {
+            true => Ok(None),
+            false => open_part_data(parts_folder, composite_file, part).map(Some),
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut parity_parts = composite_file.parity_parts
+        .iter()
+        .map(|part| match check_part(parts_folder, composite_file, part) {
+            true => open_part_data(parts_folder, composite_file, part).map(Some),
+            false => Ok(None),
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let available_parity_parts = parity_parts.iter().filter(|part| part.is_some()).count();
+
+    if damaged_parts.len() > available_parity_parts {
+        return Err(DecodeErrors::NotEnoughParityParts {
+            damaged_parts: damaged_parts.len(),
+            parity_parts: available_parity_parts,
+        });
+    }
+
+    let data_lens = composite_file.parts
+        .iter()
+        .map(|part| part.part_len)
+        .collect::<Vec<_>>();
+
+    // Восстановленные части перезаписывают испорченные.
+    // Одинаковые части с границами по содержимому хранятся в одном файле и восстанавливаются один раз
+    let mut restored_names = vec![];
+    let mut restored_parts = damaged_parts
+        .iter()
+        .filter(|&&part_ind| {
+            let part_file_name = &composite_file.parts[part_ind].part_file_name;
+            let first_time = !restored_names.contains(part_file_name);
+            restored_names.push(part_file_name.clone());
+            first_time
+        })
+        .map(|&part_ind| {
+            let part = &composite_file.parts[part_ind];
+            let mut part_file = File::create(parts_folder.join(&part.part_file_name))?;
+            write_part_start(&mut part_file, composite_file, part_ind)?;
+
+            Ok((part_ind, BufWriter::new(part_file)))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    parity::reconstruct(&mut data_parts, &data_lens, &mut parity_parts, &mut restored_parts)?;
+
+    for (_, mut part_file) in restored_parts {
+        part_file.flush()?;
+    }
+
+    println!("Восстановлено частей по паритетным частям => {}", damaged_parts.len());
+
+    Ok(())
+}
+
+/// Заголовок части, если хеш в начале файла совпадает с хешем имени.
+/// *None* у частей без заголовка, переименованных и посторонних файлов
+pub fn read_part_header(part_path: &Path) -> Option<PartHeader> {
+    let part_file_name = part_path.file_name()?.to_str()?;
+
+    let mut part_start = vec![0_u8; (PART_HASH_LEN + part_header::HEADER_LEN) as usize];
+    File::open(part_path).ok()?.read_exact(&mut part_start).ok()?;
+
+    let (hash_bytes, header) = part_start.split_at(PART_HASH_LEN as usize);
+
+    match hash_bytes == md5::compute(part_file_name).0 {
+        true => PartHeader::decode(header),
+        false => None,
+    }
+}
+
+/// Идентификаторы файлов, части которых с заголовками лежат в *parts_folder*
+pub fn find_part_sets(parts_folder: &Path) -> Result<Vec<String>, DecodeErrors> {
+    let mut uuids = vec![];
+
+    for entry in fs::read_dir(parts_folder)? {
+        // Части с границами по содержимому не принадлежат одному файлу
+        if let Some(header) = read_part_header(&entry?.path()).filter(|header| !header.uuid_parts.is_empty()) {
+            if !uuids.contains(&header.uuid_parts) {
+                uuids.push(header.uuid_parts);
+            }
+        }
+    }
+
+    uuids.sort();
+
+    Ok(uuids)
+}
+
+/// Восстанавливает сборочный файл по заголовкам частей из *parts_folder* и записывает его туда же.
+/// Нужны все части файла, а у файла с границами частей по содержимому еще и часть-опись.
+/// Потерянные паритетные части пересчитываются заново.
+/// Сведения об исходном файле в заголовках не хранятся, а длины частей зашифрованного файла не записываются,
+/// поэтому их в восстановленном сборочном файле нет. Возвращает путь к сборочному файлу
+pub fn rebuild_metafile(parts_folder: &Path, uuid_parts: &str, passphrase: Option<&str>) -> Result<PathBuf, DecodeErrors> {
+
+    let mut headers = HashMap::new();
+    let mut manifest = None;
+
+    for entry in fs::read_dir(parts_folder)? {
+        let part_path = entry?.path();
+
+        let Some(header) = read_part_header(&part_path).filter(|header| header.uuid_parts == uuid_parts) else {
+            continue;
+        };
+
+        let Some(part_file_name) = part_path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+
+        match header.manifest {
+            true => manifest = Some((part_file_name.to_string(), header)),
+            false => {
+                headers.insert(header.part_number, (part_file_name.to_string(), header));
+            },
+        }
+    }
+
+    // Сведения о файле берутся из части-описи, а без нее из части с наименьшим номером
+    let (_, reference) = manifest
+        .clone()
+        .or_else(|| headers.values().min_by_key(|(_, header)| header.part_number).cloned())
+        .ok_or(DecodeErrors::PartSetNotFound { uuid_parts: uuid_parts.to_string() })?;
+
+    let count_parts = reference.count_parts as usize;
+    let count_parity_parts = reference.count_parity_parts as usize;
+
+    // Части с границами по содержимому перечислены в описи по порядку, остальные части ищутся по номеру
+    let data_parts = match &manifest {
+        Some((manifest_name, manifest_header)) => read_manifest(parts_folder, manifest_name, manifest_header)?
+            .into_iter()
+            .map(|part_file_name| {
+                read_part_header(&parts_folder.join(&part_file_name))
+                    .filter(|header| header.content_defined_chunk && header.uuid_parts.is_empty())
+                    .map(|header| (part_file_name, header))
+            })
+            .collect::<Vec<_>>(),
+        None => (1..=count_parts as u32)
+            .map(|part_number| headers.get(&part_number).cloned())
+            .collect(),
+    };
+
+    let missing_parts = data_parts
+        .iter()
+        .enumerate()
+        .filter(|(_, part)| part.is_none())
+        .map(|(part_ind, _)| part_ind + 1)
+        .collect::<Vec<_>>();
+
+    if !missing_parts.is_empty() {
+        return Err(DecodeErrors::IncompletePartSet {
+            uuid_parts: uuid_parts.to_string(),
+            missing_parts,
+        });
+    }
+
+    let encryption = match &reference.encryption {
+        Some(params) => Some(
+            params
+                .derive_cipher(passphrase.ok_or(DecodeErrors::PassphraseRequired)?)
+                .map_err(DecodeErrors::KeyDerivationError)?
+        ),
+        None => None,
+    };
+
+    let file_name = match &encryption {
+        Some(cipher) => cipher
+            .open_metadata(encryption::METADATA_PART_HEADER_FILE_NAME, &reference.file_name)
+            .ok_or(DecodeErrors::WrongPassphrase)?,
+        None => reference.file_name.clone(),
+    };
+    let (filename, file_extension) = split_file_name(&os_string_from_bytes(file_name.clone()));
+
+    let to_file_part = |(part_file_name, header): &(String, PartHeader)| FilePart {
+        hash_bytes: md5::compute(part_file_name).0.to_vec(),
+        part_file_name: part_file_name.clone(),
+        part_len: header.part_len,
+        content_hash: header.content_hash.clone(),
+        compressed: header.compressed,
+        data_len: header.data_len,
+    };
+
+    let parts = data_parts
+        .iter()
+        .flatten()
+        .map(to_file_part)
+        .collect::<Vec<_>>();
+
+    let parity_len = parts.iter().map(|part| part.part_len).max().unwrap_or(0);
+
+    // Потерянная паритетная часть называется по номеру, а ее хеш содержимого считается при пересчете
+    let missing_parity_parts = (count_parts + 1..=count_parts + count_parity_parts)
+        .map(|part_number| !headers.contains_key(&(part_number as u32)))
+        .collect::<Vec<_>>();
+
+    let parity_parts = (count_parts + 1..=count_parts + count_parity_parts)
+        .map(|part_number| match headers.get(&(part_number as u32)) {
+            Some(part) => to_file_part(part),
+            None => {
+                let part_file_name = format!("{}_{}.part", uuid_parts, part_number);
+
+                FilePart {
+                    hash_bytes: md5::compute(&part_file_name).0.to_vec(),
+                    part_file_name,
+                    part_len: parity_len,
+                    content_hash: vec![],
+                    compressed: false,
+                    data_len: None,
+                }
+            },
+        })
+        .collect::<Vec<_>>();
+
+    let mut composite_file = CompositeFile {
+        filename,
+        file_extension,
+        file_name,
+        file_len: 0,
+        parts,
+        parity_parts,
+        uuid_parts: uuid_parts.to_string(),
+        hash_algorithm: Some(reference.hash_algorithm),
+        file_hash: reference.file_hash.clone(),
+        compression: reference.compression,
+        encryption,
+        content_defined_chunks: reference.content_defined_chunk,
+        metadata: None,
+        part_header_len: part_header::HEADER_LEN,
+    };
+
+    if missing_parity_parts.contains(&true) {
+        let content_hashes = rewrite_parity_parts(parts_folder, &composite_file, &missing_parity_parts)?;
+
+        for (parity_ind, content_hash) in content_hashes.into_iter().enumerate() {
+            if !missing_parity_parts[parity_ind] {
+                continue;
+            }
+
+            composite_file.parity_parts[parity_ind].content_hash = content_hash;
+
+            // Хеш содержимого в заголовке известен только после пересчета части
+            let mut part_file = fs::OpenOptions::new()
+                .write(true)
+                .open(parts_folder.join(&composite_file.parity_parts[parity_ind].part_file_name))?;
+            write_part_start(&mut part_file, &composite_file, count_parts + parity_ind)?;
+        }
+
+        println!("Пересчитано потерянных паритетных частей => {}", missing_parity_parts.iter().filter(|&&missing| missing).count());
+    }
+
+    let metafile_name = file_separation::encode_metafile(&composite_file, parts_folder)?;
+
+    Ok(parts_folder.join(metafile_name))
+}
+
+/// Имена частей файла из части-описи, содержимое которой сверяется с хешем из ее заголовка
+fn read_manifest(parts_folder: &Path, manifest_name: &str, header: &PartHeader) -> Result<Vec<String>, DecodeErrors> {
+    let mut manifest_file = File::open(parts_folder.join(manifest_name))?;
+    manifest_file.seek(SeekFrom::Start(PART_HASH_LEN + part_header::HEADER_LEN))?;
+
+    let mut manifest = vec![];
+    manifest_file.take(header.part_len).read_to_end(&mut manifest)?;
+
+    let corrupt_manifest = || DecodeErrors::CorruptManifest { part_file_name: manifest_name.to_string() };
+
+    if header.hash_algorithm.digest(&manifest) != header.content_hash {
+        return Err(corrupt_manifest());
+    }
+
+    part_header::decode_manifest(&manifest, header.count_parts as usize).ok_or_else(corrupt_manifest)
+}
+
+/// Сборка файла из частей без сборочного файла: сборочный файл восстанавливается по заголовкам частей
+/// и кладется рядом с ними. Возвращает путь к восстановленному сборочному файлу
+pub fn decode_orphaned_parts(parts_folder: &Path, uuid_parts: &str, options: Options) -> Result<PathBuf, DecodeErrors> {
+    let metafile_path = rebuild_metafile(parts_folder, uuid_parts, options.passphrase.as_deref())?;
+
+    decode_file_with_options(&metafile_path, options)?;
+
+    Ok(metafile_path)
+}
+
+fn decode_part(parts_folder: &Path, part_file_name: &str, part_number: usize, part_hash: &[u8]) -> Result<FilePartDecode, DecodeErrors> {
+
+    let part_file_name = part_file_name.to_string();
+    let mut part_path = parts_folder.to_path_buf();
+    part_path.push(part_file_name.clone());
+
+    let mut part_file = match File::open(&part_path) {
+        Ok(part_file) => part_file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(DecodeErrors::PartNotFound { part_number, path: part_path });
+        },
+        Err(e) => return Err(e.into()),
+    };
+
+    // Сравнение хеша, полученного из сброчного файла и хеша в файле
+    let mut hash_bytes = Vec::with_capacity(part_hash.len());
+    (&mut part_file).take(part_hash.len() as u64).read_to_end(&mut hash_bytes)?;
+
+    if hash_bytes != part_hash {
+        return Err(DecodeErrors::PartNameHashMismatch {
+            part_number,
+            path: part_path,
+            expected: part_hash.to_vec(),
+            actual: hash_bytes,
+        });
+    }
+
+    Ok(FilePartDecode {
+        file: part_file,
+        hash_bytes,
+        part_file_name,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::test_utils::{compressible_data, temp_dir};
+
+    const FIXTURE_PARTS: [&[u8]; 2] = [b"first part of the old file, ", b"second part"];
+
+    /// Сборочный файл старой разметки: имя, расширение, uuid частей, кол-во частей и хеши имен частей
+    fn old_metafile(layout: MetafileLayout, uuid_parts: &str) -> Vec<u8> {
+        let mut metafile = vec![];
+
+        let write_str = |metafile: &mut Vec<u8>, bytes: &[u8]| match layout {
+            MetafileLayout::Narrow => {
+                metafile.push(bytes.len() as u8);
+                metafile.extend_from_slice(bytes);
+            },
+            MetafileLayout::Wide => {
+                metafile.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+                metafile.extend_from_slice(bytes);
+            },
+        };
+
+        if layout == MetafileLayout::Wide {
+            metafile.extend_from_slice(&metafile_format::WIDE_MARKER);
+        }
+
+        write_str(&mut metafile, b"fixture");
+        write_str(&mut metafile, b"txt");
+        write_str(&mut metafile, uuid_parts.as_bytes());
+
+        match layout {
+            MetafileLayout::Narrow => metafile.extend_from_slice(&FIXTURE_PARTS.len().to_be_bytes()),
+            MetafileLayout::Wide => metafile.extend_from_slice(&(FIXTURE_PARTS.len() as u64).to_be_bytes()),
+        }
+
+        for part_number in 1..=FIXTURE_PARTS.len() {
+            metafile.extend_from_slice(&md5::compute(format!("{}_{}.part", uuid_parts, part_number)).0);
+        }
+
+        metafile
+    }
+
+    /// Части старой разметки: хеш имени и данные без заголовка
+    fn write_old_parts(parts_folder: &Path, uuid_parts: &str) {
+        for (part_ind, data) in FIXTURE_PARTS.iter().enumerate() {
+            let part_file_name = format!("{}_{}.part", uuid_parts, part_ind + 1);
+
+            fs::write(parts_folder.join(&part_file_name), [&md5::compute(&part_file_name).0[..], data].concat()).unwrap();
+        }
+    }
+
+    #[test]
+    fn read_metafile_header_detects_old_layouts() {
+        let uuid_parts = Uuid::new_v4().to_string();
+
+        let narrow = old_metafile(MetafileLayout::Narrow, &uuid_parts);
+        let (version, body) = read_metafile_header(narrow.clone()).unwrap();
+        assert_eq!(version, metafile_format::VERSION_NARROW);
+        assert_eq!(body, narrow);
+
+        let wide = old_metafile(MetafileLayout::Wide, &uuid_parts);
+        let (version, body) = read_metafile_header(wide.clone()).unwrap();
+        assert_eq!(version, metafile_format::VERSION_WIDE);
+        assert_eq!(body, wide[metafile_format::WIDE_MARKER.len()..]);
+    }
+
+    #[test]
+    fn decode_file_reads_old_layouts() {
+        for layout in [MetafileLayout::Narrow, MetafileLayout::Wide] {
+            let parts_folder = temp_dir("old_layout");
+            let output_folder = temp_dir("old_layout_output");
+            let uuid_parts = Uuid::new_v4().to_string();
+
+            let metafile_path = parts_folder.join(format!("{}build_file_fixture.meta", Uuid::new_v4()));
+            fs::write(&metafile_path, old_metafile(layout, &uuid_parts)).unwrap();
+            write_old_parts(&parts_folder, &uuid_parts);
+
+            let composite_file = read_metafile(&metafile_path, None).unwrap();
+            assert_eq!(composite_file.filename, "fixture");
+            assert_eq!(composite_file.file_extension, "txt");
+            assert_eq!(composite_file.parts.len(), FIXTURE_PARTS.len());
+            assert_eq!(composite_file.part_header_len, 0);
+
+            decode_file(&metafile_path, output_folder.clone()).unwrap();
+            assert_eq!(fs::read(output_folder.join("fixture.txt")).unwrap(), FIXTURE_PARTS.concat());
+
+            fs::remove_dir_all(&parts_folder).unwrap();
+            fs::remove_dir_all(&output_folder).unwrap();
+        }
+    }
+
+    fn random_bytes(len: usize, mut state: u32) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// Разделение *data* в *parts_folder*, сборочный файл удаляется. Возвращает *uuid_parts* и части файла
+    fn encode_orphaned(data: &[u8], file_name: &str, options: Options) -> (String, Vec<FilePart>) {
+        let separation_file = file_separation::encode_reader(data, file_name.as_ref(), options.clone()).unwrap();
+
+        let metafile_path = options.path_for_save.unwrap().join(&separation_file.metafile);
+        let composite_file = read_metafile(&metafile_path, options.passphrase.as_deref()).unwrap();
+        fs::remove_file(&metafile_path).unwrap();
+
+        (composite_file.uuid_parts, composite_file.parts)
+    }
+
+    #[test]
+    fn rebuild_metafile_from_orphaned_parts() {
+        let parts_folder = temp_dir("rebuild");
+        let output_folder = temp_dir("rebuild_output");
+        let data = random_bytes(100_000, 7);
+
+        let options = Options {
+            path_for_save: Some(parts_folder.clone()),
+            part_size: Some(30_000),
+            compressed: Some(true),
+            parity_parts: Some(2),
+            passphrase: Some("passphrase".to_string()),
+            ..Default::default()
+        };
+
+        let (uuid_parts, parts) = encode_orphaned(&data, "orphaned.bin", options.clone());
+        assert_eq!(parts.len(), 4);
+
+        // Потерянная паритетная часть пересчитывается при восстановлении
+        fs::remove_file(parts_folder.join(format!("{}_5.part", uuid_parts))).unwrap();
+
+        assert_eq!(find_part_sets(&parts_folder).unwrap(), vec![uuid_parts.clone()]);
+        assert!(matches!(
+            rebuild_metafile(&parts_folder, &uuid_parts, Some("wrong")),
+            Err(DecodeErrors::WrongPassphrase)
+        ));
+
+        let metafile_path = decode_orphaned_parts(&parts_folder, &uuid_parts, Options {
+            path_for_save: Some(output_folder.clone()),
+            ..options
+        }).unwrap();
+
+        assert_eq!(fs::read(output_folder.join("orphaned.bin")).unwrap(), data);
+
+        let composite_file = read_metafile(&metafile_path, Some("passphrase")).unwrap();
+        assert!(composite_file.parity_parts.iter().all(|part| check_part(&parts_folder, &composite_file, part)));
+
+        // Без части файла сборочный файл не восстановить
+        fs::remove_file(parts_folder.join(&parts[1].part_file_name)).unwrap();
+        assert!(matches!(
+            rebuild_metafile(&parts_folder, &uuid_parts, Some("passphrase")),
+            Err(DecodeErrors::IncompletePartSet { missing_parts, .. }) if missing_parts == vec![2]
+        ));
+
+        fs::remove_dir_all(&parts_folder).unwrap();
+        fs::remove_dir_all(&output_folder).unwrap();
+    }
+
+    #[test]
+    fn rebuild_content_defined_file_with_repeated_and_shared_chunks() {
+        let parts_folder = temp_dir("rebuild_chunks");
+        let output_folder = temp_dir("rebuild_chunks_output");
+
+        let block = random_bytes(40_000, 11);
+        let first = [block.as_slice(), &block, &block, &random_bytes(20_000, 13)].concat();
+        let second = [random_bytes(10_000, 17).as_slice(), &block, &block].concat();
+
+        let options = Options {
+            path_for_save: Some(parts_folder.clone()),
+            part_size: Some(16_384),
+            content_defined_chunking: Some(true),
+            compressed: Some(true),
+            parity_parts: Some(1),
+            ..Default::default()
+        };
+
+        let (first_uuid, first_parts) = encode_orphaned(&first, "first.bin", options.clone());
+        let (second_uuid, second_parts) = encode_orphaned(&second, "second.bin", options.clone());
+
+        let first_names = first_parts.iter().map(|part| &part.part_file_name).collect::<Vec<_>>();
+        let mut unique_names = first_names.clone();
+        unique_names.sort();
+        unique_names.dedup();
+
+        assert!(unique_names.len() < first_names.len(), "В файле нет повторяющихся частей");
+        assert!(second_parts.iter().any(|part| first_names.contains(&&part.part_file_name)), "У файлов нет общих частей");
+
+        let mut part_sets = find_part_sets(&parts_folder).unwrap();
+        part_sets.sort();
+        let mut expected_sets = vec![first_uuid.clone(), second_uuid.clone()];
+        expected_sets.sort();
+        assert_eq!(part_sets, expected_sets);
+
+        for (uuid_parts, file_name, data) in [(&first_uuid, "first.bin", &first), (&second_uuid, "second.bin", &second)] {
+            let metafile_path = decode_orphaned_parts(&parts_folder, uuid_parts, Options {
+                path_for_save: Some(output_folder.clone()),
+                ..Default::default()
+            }).unwrap();
+
+            assert!(&fs::read(output_folder.join(file_name)).unwrap() == data, "{} собран с ошибкой", file_name);
+
+            // Испорченная часть-опись записывается заново по сборочному файлу
+            let composite_file = read_metafile(&metafile_path, None).unwrap();
+            let manifest_name = composite_file.manifest_part_name().unwrap();
+            fs::write(parts_folder.join(&manifest_name), b"damaged").unwrap();
+
+            assert_eq!(repair_parts(&parts_folder, &composite_file).unwrap(), vec![manifest_name]);
+            assert!(check_manifest_part(&parts_folder, &composite_file));
+
+            fs::remove_file(metafile_path).unwrap();
+        }
+
+        // Без части-описи порядок частей неизвестен
+        fs::remove_file(parts_folder.join(format!("{}_manifest.part", first_uuid))).unwrap();
+        assert!(rebuild_metafile(&parts_folder, &first_uuid, None).is_err());
+
+        fs::remove_dir_all(&parts_folder).unwrap();
+        fs::remove_dir_all(&output_folder).unwrap();
+    }
+
+    /// Сборочный файл файла из 200 000 байтов с частями по 131 072 байта
+    fn encode_ranged(options: Options) -> CompositeFile {
+        let parts_folder = temp_dir("ranges");
+        let options = Options { path_for_save: Some(parts_folder.clone()), part_size: Some(131_072), ..options };
+
+        let separation_file = file_separation::encode_reader(&compressible_data(200_000)[..], "data.bin".as_ref(), options.clone()).unwrap();
+        let composite_file = read_metafile(&parts_folder.join(&separation_file.metafile), options.passphrase.as_deref()).unwrap();
+
+        fs::remove_dir_all(&parts_folder).unwrap();
+
+        composite_file
+    }
+
+    #[test]
+    fn part_ranges_and_stored_ranges() {
+        let plain = encode_ranged(Options::default());
+        let data_offset = plain.part_data_offset();
+
+        let ranges = part_ranges(&plain, 65_000, 70_000).unwrap();
+        assert_eq!(ranges, vec![
+            PartRange { part_ind: 0, offset: 65_000, len: 66_072 },
+            PartRange { part_ind: 1, offset: 0, len: 3_928 },
+        ]);
+
+        assert_eq!(part_ranges(&plain, 199_000, 5_000).unwrap(), vec![PartRange { part_ind: 1, offset: 67_928, len: 1_000 }]);
+        assert!(part_ranges(&plain, 200_000, 10).unwrap().is_empty());
+        assert!(part_ranges(&plain, 10_000, 0).unwrap().is_empty());
+        assert!(part_ranges(&plain, u64::MAX, 10).unwrap().is_empty());
+
+        assert_eq!(stored_range(&plain, &ranges[0]), Some((data_offset + 65_000, 66_072)));
+
+        // Диапазон зашифрованной части расширяется до целых сегментов вместе с тегами
+        let encrypted = encode_ranged(Options { passphrase: Some("passphrase".to_string()), ..Default::default() });
+        let data_offset = encrypted.part_data_offset();
+        let stored_segment = (encryption::SEGMENT_SIZE + encryption::TAG_LEN) as u64;
+
+        assert_eq!(stored_range(&encrypted, &PartRange { part_ind: 0, offset: 1_000, len: 5_000 }), Some((data_offset, stored_segment)));
+        assert_eq!(stored_range(&encrypted, &ranges[0]), Some((data_offset, 2 * stored_segment)));
+        assert_eq!(
+            stored_range(&encrypted, &PartRange { part_ind: 1, offset: 66_000, len: 1_000 }),
+            Some((data_offset + stored_segment, encryption::encrypted_len(68_928) - stored_segment))
+        );
+
+        // Сжатая часть читается только целиком
+        let compressed = encode_ranged(Options { compressed: Some(true), ..Default::default() });
+        assert!(compressed.parts.iter().all(|part| part.compressed));
+        assert_eq!(stored_range(&compressed, &ranges[0]), None);
+    }
+
+    #[test]
+    fn decode_keeps_file_names_byte_for_byte() {
+        let mut file_names = vec![OsString::from("README"), OsString::from("a.tar.gz"), OsString::from(".bashrc")];
+
+        #[cfg(unix)]
+        file_names.push(crate::file::os_string_from_bytes(b"\xD0\xBE\xFF\xFE.tar.gz".to_vec()));
+
+        for file_name in file_names {
+            let parts_folder = temp_dir("file_name");
+            let output_folder = temp_dir("file_name_output");
+            let data = random_bytes(5_000, 3);
+
+            let options = Options { path_for_save: Some(parts_folder.clone()), part_size: Some(2_048), ..Default::default() };
+            let separation_file = file_separation::encode_reader(&data[..], &file_name, options).unwrap();
+            let metafile_path = parts_folder.join(&separation_file.metafile);
+
+            let composite_file = read_metafile(&metafile_path, None).unwrap();
+            let (filename, file_extension) = crate::file::split_file_name(&file_name);
+
+            assert_eq!((&composite_file.filename, &composite_file.file_extension), (&filename, &file_extension));
+            assert_eq!(composite_file.output_file_name(), file_name);
+
+            decode_file_with_options(&metafile_path, Options { path_for_save: Some(output_folder.clone()), ..Default::default() }).unwrap();
+            assert_eq!(fs::read(output_folder.join(&file_name)).unwrap(), data);
+            assert_eq!(fs::read_dir(&output_folder).unwrap().count(), 1);
+
+            fs::remove_dir_all(&parts_folder).unwrap();
+            fs::remove_dir_all(&output_folder).unwrap();
+        }
+    }
+
+    /// Байты сборочного файла из 5 000 байтов с частями по 2 048 байтов
+    fn encoded_metafile() -> Vec<u8> {
+        let parts_folder = temp_dir("metafile");
+        let options = Options { path_for_save: Some(parts_folder.clone()), part_size: Some(2_048), ..Default::default() };
+
+        let separation_file = file_separation::encode_reader(&random_bytes(5_000, 5)[..], "data.bin".as_ref(), options).unwrap();
+        let metafile = fs::read(parts_folder.join(&separation_file.metafile)).unwrap();
+
+        fs::remove_dir_all(&parts_folder).unwrap();
+
+        metafile
+    }
+
+    /// Пересчет контрольной суммы после изменения заголовка
+    fn reseal_metafile(metafile: &mut [u8]) {
+        let body_len = metafile.len() - metafile_format::CHECKSUM_LEN;
+        let checksum = md5::compute(&metafile[..body_len]).0;
+
+        metafile[body_len..].copy_from_slice(&checksum);
+    }
+
+    #[test]
+    fn damaged_metafile_returns_typed_errors() {
+        let metafile = encoded_metafile();
+        let version_offset = metafile_format::MAGIC.len();
+        let flags_offset = version_offset + 2;
+
+        assert!(decode_metafile(metafile.clone(), None).is_ok());
+
+        // Испорченный байт контрольной суммы, испорченный байт данных и обрезанный файл
+        let mut damaged_metafiles = vec![metafile[..metafile_format::HEADER_LEN + 4].to_vec()];
+
+        for ind in [metafile.len() - 1, metafile_format::HEADER_LEN + 1] {
+            let mut damaged = metafile.clone();
+            damaged[ind] ^= 0x01;
+            damaged_metafiles.push(damaged);
+        }
+
+        for damaged in damaged_metafiles {
+            assert!(matches!(read_metafile_header(damaged.clone()), Err(DecodeErrors::MetafileChecksumMismatch)));
+            assert!(matches!(decode_metafile(damaged, None), Err(DecodeErrors::MetafileChecksumMismatch)));
+        }
+
+        let newer_version = metafile_format::VERSION_CURRENT + 1;
+        let mut newer = metafile.clone();
+        newer[version_offset..flags_offset].copy_from_slice(&newer_version.to_be_bytes());
+        reseal_metafile(&mut newer);
+
+        assert!(matches!(read_metafile_header(newer.clone()), Err(DecodeErrors::UnsupportedMetafileVersion(version)) if version == newer_version));
+        assert!(matches!(decode_metafile(newer, None), Err(DecodeErrors::UnsupportedMetafileVersion(version)) if version == newer_version));
+
+        let unknown_flag = 1_u32 << 31;
+        let mut flagged = metafile.clone();
+        let flags = u32::from_be_bytes(flagged[flags_offset..metafile_format::HEADER_LEN].try_into().unwrap()) | unknown_flag;
+        flagged[flags_offset..metafile_format::HEADER_LEN].copy_from_slice(&flags.to_be_bytes());
+        reseal_metafile(&mut flagged);
+
+        assert!(matches!(read_metafile_header(flagged.clone()), Err(DecodeErrors::UnsupportedMetafileFlags(flags)) if flags == unknown_flag));
+        assert!(matches!(decode_metafile(flagged, None), Err(DecodeErrors::UnsupportedMetafileFlags(flags)) if flags == unknown_flag));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn decode_replaces_read_only_output() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let parts_folder = temp_dir("read_only");
+        let output_folder = temp_dir("read_only_output");
+        let source_path = parts_folder.join("data.bin");
+        let data = random_bytes(5_000, 9);
+
+        fs::write(&source_path, &data).unwrap();
+        fs::set_permissions(&source_path, fs::Permissions::from_mode(0o444)).unwrap();
+
+        let options = Options { path_for_save: Some(parts_folder.clone()), part_size: Some(2_048), ..Default::default() };
+        let separation_file = file_separation::encode_file(&source_path, options).unwrap();
+        let metafile_path = parts_folder.join(&separation_file.metafile);
+
+        // Повторная сборка в ту же папку заменяет файл, ставший доступным только для чтения
+        for _ in 0..2 {
+            decode_file_with_options(&metafile_path, Options { path_for_save: Some(output_folder.clone()), ..Default::default() }).unwrap();
+
+            let output_path = output_folder.join("data.bin");
+            assert_eq!(fs::read(&output_path).unwrap(), data);
+            assert_eq!(fs::metadata(&output_path).unwrap().permissions().mode() & 0o7777, 0o444);
+        }
+
+        fs::remove_dir_all(&parts_folder).unwrap();
+        fs::remove_dir_all(&output_folder).unwrap();
+    }
+
+    #[test]
+    fn wrong_passphrase_and_tampered_ciphertext_leave_no_output() {
+        let parts_folder = temp_dir("tampered");
+        let output_folder = temp_dir("tampered_output");
+        let data = random_bytes(100_000, 21);
+
+        let options = Options {
+            path_for_save: Some(parts_folder.clone()),
+            part_size: Some(40_000),
+            passphrase: Some("passphrase".to_string()),
+            ..Default::default()
+        };
+        let separation_file = file_separation::encode_reader(&data[..], "secret.bin".as_ref(), options).unwrap();
+        let metafile_path = parts_folder.join(&separation_file.metafile);
+
+        let decode = |passphrase: Option<&str>| decode_file_with_options(&metafile_path, Options {
+            path_for_save: Some(output_folder.clone()),
+            passphrase: passphrase.map(str::to_string),
+            ..Default::default()
+        });
+        let output_is_empty = || fs::read_dir(&output_folder).unwrap().next().is_none();
+
+        assert!(matches!(decode(Some("wrong")), Err(DecodeErrors::WrongPassphrase)));
+        assert!(output_is_empty());
+
+        assert!(matches!(decode(None), Err(DecodeErrors::PassphraseRequired)));
+        assert!(output_is_empty());
+
+        let composite_file = read_metafile(&metafile_path, Some("passphrase")).unwrap();
+        let part_path = parts_folder.join(&composite_file.parts[1].part_file_name);
+        let part_bytes = fs::read(&part_path).unwrap();
+
+        let range = part_ranges(&composite_file, 40_000, 40_000).unwrap().remove(0);
+        let (stored_offset, stored_len) = stored_range(&composite_file, &range).unwrap();
+        let stored_range = stored_offset as usize..(stored_offset + stored_len) as usize;
+
+        // Измененный байт шифротекста и измененный байт тега второй части. При сборке его находит
+        // ключевой хеш содержимого, а при чтении диапазона без хеша всей части проверка подлинности сегмента
+        for ind in [composite_file.part_data_offset() as usize + 10, part_bytes.len() - 1] {
+            let mut tampered = part_bytes.clone();
+            tampered[ind] ^= 0x01;
+            fs::write(&part_path, &tampered).unwrap();
+
+            let res = decode(Some("passphrase"));
+            assert!(matches!(res, Err(DecodeErrors::PartHashMismatch { part_number: 2, .. })), "{:?}", res);
+            assert!(output_is_empty());
+
+            let mut range_output = vec![];
+            let res = decode_stored_range(&tampered[stored_range.clone()], &composite_file, &range, &mut range_output);
+            assert!(matches!(res, Err(DecodeErrors::PartDecryptionFailed { part_number: 2, .. })), "{:?}", res);
+            assert!(range_output.is_empty());
+        }
+
+        fs::write(&part_path, part_bytes).unwrap();
+        decode(Some("passphrase")).unwrap();
+        assert_eq!(fs::read(output_folder.join("secret.bin")).unwrap(), data);
+
+        fs::remove_dir_all(&parts_folder).unwrap();
+        fs::remove_dir_all(&output_folder).unwrap();
     }
 }
\ No newline at end of file
@@ -1,19 +1,36 @@
 use std::{
+    ffi::{OsStr, OsString},
     fs::{self, File},
-    io::{self, BufRead, Write, BufReader, Error},
-    path::PathBuf,
-    process::ExitCode
+    io::{self, BufRead, Read, Write, BufReader, BufWriter, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    collections::VecDeque,
+    thread,
 };
-use std::path::Path;
 use uuid::Uuid;
 
-use super::{CompositeFile, FilePart, Options};
+use super::{CompositeFile, FilePart, Options, metafile_section, parity, chunk_file_name, CHUNK_NAMES_CONTENT_HASH, PART_HASH_LEN, metafile_format, metafile_flags, os_str_bytes, split_file_name};
+use super::chunking::PartSplitter;
+use super::compression::{self, CompressWriter};
+use super::encryption::{self, EncryptionParams, EncryptWriter};
+use super::hash::{HashAlgorithm, HashReader, HashWriter};
+use super::metadata::{self, FileMetadata};
+use super::part_header::{self, PartHeader};
 
 #[derive(Debug)]
 pub enum EncodeErrors {
     IOError(::std::io::Error),
     OsStringError(std::ffi::OsString),
     PathParseError,
+    /// Файл не помещается в допустимое кол-во частей, *count_parts* - сколько частей уже набралось
+    TooManyParts {
+        count_parts: usize,
+        max_count_parts: usize,
+    },
+    /// Разделяемый путь не существует или не является файлом
+    NotAFile {
+        path: PathBuf,
+    },
+    KeyDerivationError(argon2::Error),
 }
 
 impl From<std::io::Error> for EncodeErrors {
@@ -32,157 +49,828 @@ impl From<std::ffi::OsString> for EncodeErrors {
 pub struct SeparationFile {
     pub filename: String,
     pub file_extension: String,
+    /// Полное имя исходного файла
+    pub file_name: OsString,
     pub metafile: String,
     pub parts: Vec<FilePart>,
+    pub parity_parts: Vec<FilePart>,
+    /// Часть-опись с порядком частей, только у файлов с границами частей по содержимому
+    pub manifest_part: Option<String>,
+    /// Сведения об исходном файле, *None* если данные получены не из файла
+    pub metadata: Option<FileMetadata>,
     pub options: Options
 }
 
 pub fn encode_file(path: &PathBuf, options: Options) -> Result<SeparationFile, EncodeErrors> {
 
     if !path.is_file() {
-        println!("{}", path.display());
-        panic!("Предоставьте путь к файлу")
+        return Err(EncodeErrors::NotAFile { path: path.clone() });
     }
 
-    let path_for_save = dbg!(options.clone().path_for_save.unwrap_or(PathBuf::new()));
+    let file_name = path.file_name().ok_or(EncodeErrors::PathParseError)?;
 
-    println!("{}", path_for_save.is_dir());
+    let metadata = FileMetadata::read(path)?;
+
+    encode_stream(File::open(path)?, file_name, Some(metadata), options, Uuid::new_v4().to_string())
+}
+
+/// Разделение данных из любого источника (файл, *stdin*, канал) на части.
+/// Данные проходят через буфер фиксированного размера, поэтому расход памяти не зависит от размера части
+pub fn encode_reader(reader: impl Read, file_name: &OsStr, options: Options) -> Result<SeparationFile, EncodeErrors> {
+    encode_stream(reader, file_name, None, options, Uuid::new_v4().to_string())
+}
+
+fn encode_stream(
+    reader: impl Read,
+    file_name: &OsStr,
+    metadata: Option<FileMetadata>,
+    options: Options,
+    uuid_parts: String
+) -> Result<SeparationFile, EncodeErrors> {
+
+    let path_for_save = options.clone().path_for_save.unwrap_or(PathBuf::new());
 
     if !path_for_save.is_dir() {
-        return dbg!(Err(EncodeErrors::PathParseError));
+        return Err(EncodeErrors::PathParseError);
     }
-    let file = File::open(&path)?;
 
-    let mut size_part = options.part_size.unwrap_or(1_073_741_824_usize);
+    let size_part = options.part_size.unwrap_or(1_073_741_824_usize);
+    let content_defined = options.content_defined_chunking.unwrap_or(false);
+    let hash_algorithm = options.hash_algorithm.unwrap_or_default();
 
-    let mut f  = BufReader::with_capacity(
-        size_part,
-        file
-    );
+    let (filename, file_extension) = split_file_name(file_name);
 
     let mut composite_file = CompositeFile {
-        filename: path
-            .file_stem()
-            .ok_or(EncodeErrors::PathParseError)?
-            .to_os_string()
-            .into_string()?,
-        file_extension:  path
-            .extension()
-            .ok_or(EncodeErrors::PathParseError)?
-            .to_os_string()
-            .into_string()?,
-        file_len: f.capacity(),
+        filename,
+        file_extension,
+        file_name: os_str_bytes(file_name),
+        file_len: size_part,
         parts: vec![],
-        uuid_parts: Uuid::new_v4().to_string(),
+        parity_parts: vec![],
+        uuid_parts,
+        hash_algorithm: Some(hash_algorithm),
+        file_hash: vec![],
+        compression: None,
+        encryption: None,
+        content_defined_chunks: content_defined,
+        metadata,
+        part_header_len: part_header::HEADER_LEN,
     };
 
-    let max_count_parts = options.count_parts.unwrap_or(255);
+    if let Some(passphrase) = &options.passphrase {
+        composite_file.encryption = Some(
+            EncryptionParams::generate()
+                .derive_cipher(passphrase)
+                .map_err(EncodeErrors::KeyDerivationError)?
+        );
+    }
 
-    let mut number_part = 1;
+    // Хеш исходного файла считается по всем прочитанным байтам
+    let file_hasher = match &composite_file.encryption {
+        Some(cipher) => hash_algorithm.keyed_hasher(cipher.hash_key()),
+        None => hash_algorithm.hasher(),
+    };
+    let mut reader = BufReader::with_capacity(
+        compression::ENTROPY_SAMPLE_SIZE,
+        HashReader::new(reader, file_hasher)
+    );
 
-    let mut parts = vec![];
+    if options.compressed == Some(true) {
+        // Начало файла просматривается без чтения из источника, чтобы работали и каналы
+        let sample = reader.fill_buf()?;
 
-    while f.has_data_left()? {
+        if compression::is_compressed_input(&composite_file.file_extension, sample) {
+            println!("Файл уже сжат, части не будут сжиматься => {}", file_name.to_string_lossy());
+        } else {
+            let compression_algorithm = options.compression_algorithm.unwrap_or_default();
 
-        if number_part > max_count_parts {
-            println!(
-                "Файл слишком большой для размещения в {} частей",
-                max_count_parts
-            );
-            std::process::exit(1);
+            composite_file.compression = Some((
+                compression_algorithm,
+                options.compression_level.unwrap_or(compression_algorithm.default_level()),
+            ));
         }
+    }
 
-        let buffer_bytes = f.fill_buf()?;
-        let buffer_bytes_len = buffer_bytes.len();
+    let mut splitter = match content_defined {
+        true => PartSplitter::content_defined(reader, size_part),
+        false => PartSplitter::fixed(reader, size_part),
+    };
 
-        let part = encode_part(
-            &composite_file.uuid_parts,
-            number_part,
-            buffer_bytes,
-            &path_for_save
-        )?;
+    let encode_res = encode_parts(
+        &composite_file,
+        &mut splitter,
+        options.max_count_parts(),
+        &path_for_save,
+        hash_algorithm,
+        options.threads()
+    );
+
+    composite_file.parts = match encode_res {
+        Ok(parts) => parts,
+        Err((written_parts, e)) => {
+            // Записанные части без сборочного файла не нужны
+            for part in written_parts {
+                let _ = fs::remove_file(path_for_save.join(&part.part_file_name));
+            }
+
+            return Err(e);
+        },
+    };
 
-        parts.push(part.clone());
+    let parts = composite_file.parts.clone();
 
-        composite_file.parts.push(part);
+    (_, composite_file.file_hash) = splitter.into_inner().into_inner().finish();
 
-        number_part += 1;
-        f.consume(buffer_bytes_len);
+    let count_parity_parts = options.parity_parts.unwrap_or(0) as usize;
+
+    if count_parity_parts > 0 && !composite_file.parts.is_empty() {
+        composite_file.parity_parts = encode_parity_parts(
+            &composite_file,
+            count_parity_parts,
+            &path_for_save,
+            hash_algorithm
+        )?;
     }
 
+    // Кол-во частей и хеш файла известны только после записи всех частей
+    write_part_headers(&composite_file, &path_for_save)?;
+
+    let manifest_part = match part_header::manifest_part(&composite_file) {
+        Some((part_file_name, part_bytes)) => {
+            fs::write(path_for_save.join(&part_file_name), part_bytes)?;
+            Some(part_file_name)
+        },
+        None => None,
+    };
+
     let metafile = encode_metafile(&composite_file, &path_for_save)?;
 
     Ok(SeparationFile {
         filename: composite_file.filename,
         file_extension: composite_file.file_extension,
+        file_name: file_name.to_os_string(),
         metafile,
         parts,
+        parity_parts: composite_file.parity_parts,
+        manifest_part,
+        metadata: composite_file.metadata,
         options,
     })
 }
 
-fn encode_part(part_uuid: &str, part_number: u8, data: &[u8], path_for_save: &PathBuf) -> io::Result<FilePart> {
+/// Запись всех частей источника. В несколько потоков источник по-прежнему читается по порядку,
+/// а данные частей сжимаются, шифруются и хешируются параллельно, поэтому одновременно в памяти
+/// находится до *threads* частей. Части получаются такими же, как при записи в один поток.
+/// При ошибке возвращаются уже записанные части, чтобы их можно было удалить
+fn encode_parts<R: BufRead>(
+    composite_file: &CompositeFile,
+    splitter: &mut PartSplitter<R>,
+    max_count_parts: usize,
+    path_for_save: &Path,
+    hash_algorithm: HashAlgorithm,
+    threads: usize
+) -> Result<Vec<FilePart>, (Vec<FilePart>, EncodeErrors)> {
+
+    let mut parts = vec![];
+    let mut number_part = 1_u32;
+
+    if threads <= 1 {
+        loop {
+            match splitter.has_data_left() {
+                Ok(false) => return Ok(parts),
+                Ok(true) => {},
+                Err(e) => return Err((parts, e.into())),
+            }
+
+            if number_part as usize > max_count_parts {
+                return Err((parts, EncodeErrors::TooManyParts {
+                    count_parts: number_part as usize,
+                    max_count_parts,
+                }));
+            }
+
+            let encode_res = encode_part(
+                composite_file,
+                |mut writer| splitter.copy_part(&mut writer),
+                number_part,
+                path_for_save,
+                hash_algorithm
+            );
+
+            match encode_res {
+                Ok(part) => parts.push(part),
+                Err(e) => return Err((parts, e.into())),
+            }
 
-    let part_file_name = format!("{}_{}.part", part_uuid, part_number);
+            number_part += 1;
+        }
+    }
+
+    thread::scope(|scope| {
+        let mut in_progress = VecDeque::with_capacity(threads);
+        let mut res = Ok(());
+
+        // Части забираются у потоков по порядку, первая ошибка останавливает чтение источника
+        let take_part = |handle: thread::ScopedJoinHandle<io::Result<FilePart>>, parts: &mut Vec<FilePart>, res: &mut Result<(), EncodeErrors>| {
+            match handle.join().expect("Поток записи части завершился паникой") {
+                Ok(part) => parts.push(part),
+                Err(e) => if res.is_ok() {
+                    *res = Err(e.into());
+                },
+            }
+        };
+
+        while res.is_ok() {
+            match splitter.has_data_left() {
+                Ok(false) => break,
+                Ok(true) => {},
+                Err(e) => {
+                    res = Err(e.into());
+                    break;
+                },
+            }
+
+            if number_part as usize > max_count_parts {
+                res = Err(EncodeErrors::TooManyParts {
+                    count_parts: number_part as usize,
+                    max_count_parts,
+                });
+                break;
+            }
+
+            let mut part_data = vec![];
+
+            if let Err(e) = splitter.copy_part(&mut part_data) {
+                res = Err(e.into());
+                break;
+            }
+
+            let part_number = number_part;
+
+            in_progress.push_back(scope.spawn(move || encode_part(
+                composite_file,
+                |writer| writer.write_all(&part_data).map(|_| part_data.len() as u64),
+                part_number,
+                path_for_save,
+                hash_algorithm
+            )));
+
+            if in_progress.len() >= threads {
+                take_part(in_progress.pop_front().unwrap(), &mut parts, &mut res);
+            }
+
+            number_part += 1;
+        }
+
+        while let Some(handle) = in_progress.pop_front() {
+            take_part(handle, &mut parts, &mut res);
+        }
+
+        match res {
+            Ok(()) => Ok(parts),
+            Err(e) => Err((parts, e)),
+        }
+    })
+}
+
+/// Запись части: данные от *write_data* сжимаются, затем шифруются, хеш содержимого считается по записанным байтам.
+/// При сжатии части хранятся сжатыми, даже если сжатие их не уменьшило: размер становится известен
+/// только после записи части, а данные источника к этому моменту уже прочитаны
+fn encode_part(
+    composite_file: &CompositeFile,
+    write_data: impl FnOnce(&mut dyn Write) -> io::Result<u64>,
+    part_number: u32,
+    path_for_save: &Path,
+    hash_algorithm: HashAlgorithm
+) -> io::Result<FilePart> {
+
+    let numbered_name = format!("{}_{}.part", composite_file.uuid_parts, part_number);
+    let numbered_path = path_for_save.join(&numbered_name);
+
+    let mut part_file = File::create_new(&numbered_path)?;
+
+    // Имя части по содержимому известно только после записи, поэтому хеш имени записывается в конце,
+    // а заголовок после записи всех частей
+    part_file.write_all(&vec![0; composite_file.part_data_offset() as usize])?;
+
+    let content_writer = HashWriter::new(BufWriter::new(part_file), hash_algorithm.hasher());
+    let encrypt_writer = EncryptWriter::new(composite_file.encryption.as_ref(), part_number, content_writer);
+    let mut compress_writer = CompressWriter::new(composite_file.compression, encrypt_writer)?;
+
+    let data_len = write_data(&mut compress_writer)?;
+
+    let (part_file, content_hash) = compress_writer.finish()?.finish()?.finish();
+    let mut part_file = part_file.into_inner().map_err(|e| e.into_error())?;
+    let part_len = part_file.stream_position()? - composite_file.part_data_offset();
+
+    let part_file_name = match composite_file.content_defined_chunks {
+        true => chunk_file_name(&content_hash),
+        false => numbered_name,
+    };
     let hash_bytes = md5::compute(&part_file_name).0.to_vec();
 
-    let mut part_file = File::create_new(format!("{}{}", path_for_save.display(), &part_file_name))?;
-    part_file.write_all(&hash_bytes.as_slice())?;
-    part_file.write_all(&data)?;
+    part_file.seek(SeekFrom::Start(0))?;
+    part_file.write_all(&hash_bytes)?;
     part_file.flush()?;
+    drop(part_file);
+
+    // Одноименные части с границами по содержимому совпадают, поэтому часть может перезаписываться
+    if composite_file.content_defined_chunks {
+        fs::rename(&numbered_path, path_for_save.join(&part_file_name))?;
+    }
 
-    println!("Файл с частью данными был создан => {}{}", path_for_save.display(), &part_file_name);
+    println!("Файл с частью данными был создан => {}", path_for_save.join(&part_file_name).display());
 
     Ok(FilePart {
         hash_bytes,
         part_file_name,
+        part_len,
+        content_hash,
+        compressed: composite_file.compression.is_some(),
+        data_len: Some(data_len),
     })
 }
 
-fn encode_metafile(composite_file: &CompositeFile, path_for_save: &PathBuf) -> io::Result<String> {
+/// Создает паритетные части Рида-Соломона по уже записанным частям файла.
+/// Паритетные части нумеруются после частей файла
+fn encode_parity_parts(
+    composite_file: &CompositeFile,
+    count_parity_parts: usize,
+    path_for_save: &Path,
+    hash_algorithm: HashAlgorithm
+) -> Result<Vec<FilePart>, EncodeErrors> {
+
+    let count_parts = composite_file.parts.len() + count_parity_parts;
+
+    if count_parts > parity::MAX_PARTS {
+        return Err(EncodeErrors::TooManyParts {
+            count_parts,
+            max_count_parts: parity::MAX_PARTS,
+        });
+    }
+
+    let mut data_parts = composite_file.parts
+        .iter()
+        .map(|part| {
+            let mut part_file = File::open(path_for_save.join(&part.part_file_name))?;
+            part_file.seek(SeekFrom::Start(composite_file.part_data_offset()))?;
+            Ok(BufReader::new(part_file))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let data_lens = composite_file.parts
+        .iter()
+        .map(|part| part.part_len)
+        .collect::<Vec<_>>();
+
+    let parity_len = data_lens.iter().copied().max().unwrap_or(0);
+
+    let mut parity_parts = vec![];
+    let mut parity_files = vec![];
+
+    for parity_ind in 0..count_parity_parts {
+        let part_file_name = format!(
+            "{}_{}.part",
+            composite_file.uuid_parts,
+            composite_file.parts.len() + parity_ind + 1
+        );
+        let hash_bytes = md5::compute(&part_file_name).0.to_vec();
+
+        let mut part_file = File::create_new(path_for_save.join(&part_file_name))?;
+        part_file.write_all(&hash_bytes)?;
+        part_file.write_all(&vec![0; composite_file.part_header_len as usize])?;
+
+        parity_files.push(HashWriter::new(BufWriter::new(part_file), hash_algorithm.hasher()));
+        parity_parts.push(FilePart {
+            hash_bytes,
+            part_file_name,
+            part_len: parity_len,
+            content_hash: vec![],
+            compressed: false,
+            data_len: None,
+        });
+    }
+
+    parity::encode_parity(&mut data_parts, &data_lens, &mut parity_files)?;
+
+    for (parity_part, parity_file) in parity_parts.iter_mut().zip(parity_files) {
+        let (mut parity_file, content_hash) = parity_file.finish();
+        parity_file.flush()?;
+        parity_part.content_hash = content_hash;
+    }
+
+    println!("Создано паритетных частей => {}", parity_parts.len());
+
+    Ok(parity_parts)
+}
+
+/// Запись заголовков всех частей и паритетных частей поверх нулей после хеша имени
+fn write_part_headers(composite_file: &CompositeFile, path_for_save: &Path) -> io::Result<()> {
+
+    let parts = composite_file.parts.iter().chain(&composite_file.parity_parts);
+
+    for (part_ind, part) in parts.enumerate() {
+        let mut part_file = fs::OpenOptions::new()
+            .write(true)
+            .open(path_for_save.join(&part.part_file_name))?;
+
+        part_file.seek(SeekFrom::Start(PART_HASH_LEN))?;
+        part_file.write_all(&PartHeader::for_part(composite_file, part_ind).encode())?;
+    }
+
+    Ok(())
+}
+
+/// Запись сборочного файла в *path_for_save*, возвращает имя сборочного файла
+pub(super) fn encode_metafile(composite_file: &CompositeFile, path_for_save: &Path) -> io::Result<String> {
 
     let uuid = Uuid::new_v4().to_string();
-    let metafile_name = format!("{}build_file_{}.meta", uuid, composite_file.filename);
 
-    let mut metafile = File::create(
-        format!("{}{}", path_for_save.display(), &metafile_name)
-    )?;
-    let source_filename_bytes = composite_file.filename.as_bytes();
-    let source_format_bytes =  composite_file.file_extension.as_bytes();
+    // Имя зашифрованного файла не должно быть видно ни в имени, ни в содержимом сборочного файла
+    let metafile_name = match composite_file.encryption {
+        Some(_) => format!("{}build_file.meta", uuid),
+        None => format!("{}build_file_{}.meta", uuid, metafile_name_suffix(&composite_file.filename)),
+    };
+
+    // Сборочный файл собирается в памяти, так как контрольная сумма считается по всему содержимому
+    let mut metafile = vec![];
+    let (source_filename_bytes, source_format_bytes) = match composite_file.encryption {
+        Some(_) => ("".as_bytes(), "".as_bytes()),
+        None => (composite_file.filename.as_bytes(), composite_file.file_extension.as_bytes()),
+    };
     let parts_uuid_bytes = composite_file.uuid_parts.as_bytes();
 
+    metafile.write_all(&metafile_format::MAGIC)?;
+    metafile.write_all(&metafile_format::VERSION_CURRENT.to_be_bytes())?;
+    metafile.write_all(&metafile_flags(composite_file).to_be_bytes())?;
+
     // Запись имени исходного файла
-    metafile.write(
-        &(source_filename_bytes.len() as u8).to_be_bytes()
-    )?;
-    metafile.write(
-        source_filename_bytes
-    )?;
+    write_str(&mut metafile, source_filename_bytes)?;
 
     // Запись расширения исходного файла
-    metafile.write(
-        &(source_format_bytes.len() as u8).to_be_bytes()
-    )?;
-    metafile.write(
-        source_format_bytes
-    )?;
+    write_str(&mut metafile, source_format_bytes)?;
 
     // Запись uuid в названии частей.
-    metafile.write(
-        &(parts_uuid_bytes.len() as u8).to_be_bytes()
-    )?;
-    metafile.write(
-        parts_uuid_bytes
-    )?;
+    write_str(&mut metafile, parts_uuid_bytes)?;
 
     // Запись всех хешей частей как массив
-    metafile.write(&composite_file.parts.len().to_be_bytes())?;
+    metafile.write_all(&(composite_file.parts.len() as u64).to_be_bytes())?;
     composite_file.parts
         .iter()
-        .map(
-            |x| metafile.write(&x.hash_bytes).unwrap()
-        )
-        .for_each(drop);
+        .for_each(|part| metafile.extend_from_slice(&part.hash_bytes));
+
+    // Запись паритетных частей: их кол-во, длины частей файла и хеши паритетных частей
+    if !composite_file.parity_parts.is_empty() {
+        let mut section = vec![composite_file.parity_parts.len() as u8];
+
+        composite_file.parts
+            .iter()
+            .for_each(|part| section.extend_from_slice(&part.part_len.to_be_bytes()));
+
+        composite_file.parity_parts
+            .iter()
+            .for_each(|part| section.extend_from_slice(&part.hash_bytes));
+
+        write_section(&mut metafile, metafile_section::PARITY, &section)?;
+    }
+
+    // Запись хешей содержимого: алгоритм, длина хеша, хеш файла, хеши частей и паритетных частей
+    if let Some(hash_algorithm) = composite_file.hash_algorithm {
+        let mut section = vec![hash_algorithm.id(), composite_file.file_hash.len() as u8];
+        section.extend_from_slice(&composite_file.file_hash);
+
+        composite_file.parts
+            .iter()
+            .chain(&composite_file.parity_parts)
+            .for_each(|part| section.extend_from_slice(&part.content_hash));
+
+        write_section(&mut metafile, metafile_section::HASHES, &section)?;
+    }
+
+    // Запись сжатия: алгоритм, уровень и признак сжатия каждой части
+    if let Some((compression_algorithm, compression_level)) = composite_file.compression {
+        let mut section = vec![compression_algorithm.id()];
+        section.extend_from_slice(&compression_level.to_be_bytes());
+
+        composite_file.parts
+            .iter()
+            .for_each(|part| section.push(part.compressed as u8));
+
+        write_section(&mut metafile, metafile_section::COMPRESSION, &section)?;
+    }
+
+    // Запись шифрования: алгоритм, параметры Argon2id, соль, основа nonce и зашифрованные имя и расширение
+    if let Some(cipher) = &composite_file.encryption {
+        let params = &cipher.params;
+
+        let mut section = vec![encryption::CHACHA20_POLY1305];
+        section.extend_from_slice(&params.m_cost.to_be_bytes());
+        section.extend_from_slice(&params.t_cost.to_be_bytes());
+        section.extend_from_slice(&params.p_cost.to_be_bytes());
+        section.extend_from_slice(&params.salt);
+        section.extend_from_slice(&params.base_nonce);
+
+        let mut file_names = vec![];
+        write_str(&mut file_names, composite_file.filename.as_bytes())?;
+        write_str(&mut file_names, composite_file.file_extension.as_bytes())?;
+
+        section.extend(cipher.seal_metadata(encryption::METADATA_FILE_NAMES, &file_names));
+
+        write_section(&mut metafile, metafile_section::ENCRYPTION, &section)?;
+    }
+
+    // Запись схемы имен частей, названных по хешу содержимого
+    if composite_file.content_defined_chunks {
+        write_section(&mut metafile, metafile_section::CHUNKS, &[CHUNK_NAMES_CONTENT_HASH])?;
+    }
+
+    // Запись полного имени исходного файла, у зашифрованного файла оно зашифровано
+    let section = match &composite_file.encryption {
+        Some(cipher) => cipher.seal_metadata(encryption::METADATA_FULL_FILE_NAME, &composite_file.file_name),
+        None => composite_file.file_name.clone(),
+    };
+    write_section(&mut metafile, metafile_section::FILE_NAME, &section)?;
+
+    // Запись сведений об исходном файле, у зашифрованного файла они зашифрованы
+    if let Some(metadata) = &composite_file.metadata {
+        let section = encode_metadata(metadata)?;
+
+        let section = match &composite_file.encryption {
+            Some(cipher) => cipher.seal_metadata(encryption::METADATA_FILE_ATTRIBUTES, &section),
+            None => section,
+        };
+
+        write_section(&mut metafile, metafile_section::METADATA, &section)?;
+    }
+
+    // Запись длин исходных данных частей, у зашифрованного файла они зашифрованы
+    if has_part_sizes(composite_file) {
+        let section = composite_file.parts
+            .iter()
+            .flat_map(|part| part.data_len.unwrap_or(0).to_be_bytes())
+            .collect::<Vec<u8>>();
+
+        let section = match &composite_file.encryption {
+            Some(cipher) => cipher.seal_metadata(encryption::METADATA_PART_SIZES, &section),
+            None => section,
+        };
+        write_section(&mut metafile, metafile_section::PART_SIZES, &section)?;
+    }
+
+    // Запись длины заголовка частей
+    if composite_file.part_header_len != 0 {
+        write_section(&mut metafile, metafile_section::PART_HEADERS, &composite_file.part_header_len.to_be_bytes())?;
+    }
+
+    let checksum = md5::compute(&metafile).0;
+    metafile.extend_from_slice(&checksum);
+
+    fs::write(path_for_save.join(&metafile_name), metafile)?;
 
     Ok(metafile_name)
+}
+
+/// Имя файла в имени сборочного файла обрезается, чтобы имя сборочного файла не превысило 255 байт
+fn metafile_name_suffix(filename: &str) -> &str {
+    const MAX_SUFFIX_LEN: usize = 128;
+
+    let end = filename
+        .char_indices()
+        .map(|(ind, c)| ind + c.len_utf8())
+        .take_while(|&end| end <= MAX_SUFFIX_LEN)
+        .last()
+        .unwrap_or(0);
+
+    &filename[..end]
+}
+
+/// Флаги возможностей, использованных при разделении файла
+fn metafile_flags(composite_file: &CompositeFile) -> u32 {
+    [
+        (!composite_file.parity_parts.is_empty(), metafile_flags::PARITY),
+        (composite_file.hash_algorithm.is_some(), metafile_flags::HASHES),
+        (composite_file.compression.is_some(), metafile_flags::COMPRESSION),
+        (composite_file.encryption.is_some(), metafile_flags::ENCRYPTION),
+        (composite_file.content_defined_chunks, metafile_flags::CHUNKS),
+        (composite_file.metadata.is_some(), metafile_flags::METADATA),
+        (true, metafile_flags::FILE_NAME),
+        (has_part_sizes(composite_file), metafile_flags::PART_SIZES),
+        (composite_file.part_header_len != 0, metafile_flags::PART_HEADERS),
+    ]
+        .into_iter()
+        .filter(|(used, _)| *used)
+        .fold(0, |flags, (_, flag)| flags | flag)
+}
+
+/// Длины исходных данных известны у всех частей. У сборочного файла, восстановленного
+/// по заголовкам частей зашифрованного файла, их нет
+fn has_part_sizes(composite_file: &CompositeFile) -> bool {
+    composite_file.parts.iter().all(|part| part.data_len.is_some())
+}
+
+/// Сведения об исходном файле: размер, признаки известных полей, время изменения, права,
+/// владелец, группа и расширенные атрибуты с длинами *u32*
+fn encode_metadata(metadata: &FileMetadata) -> io::Result<Vec<u8>> {
+    let mut section = vec![];
+    section.write_all(&metadata.size.to_be_bytes())?;
+
+    let fields = [metadata.modified.is_some(), metadata.mode.is_some(), metadata.uid.is_some(), metadata.gid.is_some()]
+        .into_iter()
+        .enumerate()
+        .fold(0_u8, |fields, (ind, present)| fields | ((present as u8) << ind));
+    section.push(fields);
+
+    if let Some(modified) = metadata.modified {
+        let (secs, nanos) = metadata::to_unix_time(modified);
+        section.write_all(&secs.to_be_bytes())?;
+        section.write_all(&nanos.to_be_bytes())?;
+    }
+
+    for field in [metadata.mode, metadata.uid, metadata.gid].into_iter().flatten() {
+        section.write_all(&field.to_be_bytes())?;
+    }
+
+    section.write_all(&(metadata.xattrs.len() as u32).to_be_bytes())?;
+
+    for (name, value) in &metadata.xattrs {
+        write_str(&mut section, name)?;
+        write_str(&mut section, value)?;
+    }
+
+    Ok(section)
+}
+
+/// Запись строки с длиной *u32*
+fn write_str(output: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    output.write_all(&(bytes.len() as u32).to_be_bytes())?;
+    output.write_all(bytes)
+}
+
+/// Запись дополнительной секции сборочного файла
+fn write_section(metafile: &mut impl Write, tag: u8, section: &[u8]) -> io::Result<()> {
+    metafile.write_all(&[tag])?;
+    metafile.write_all(&(section.len() as u64).to_be_bytes())?;
+    metafile.write_all(section)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    use super::*;
+    use crate::file::file_assembly;
+    use crate::test_utils::temp_dir;
+
+    /// Повторяющиеся фрагменты со случайными байтами, чтобы данные сжимались, но не в ноль
+    fn test_data(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_u32;
+
+        (0..len)
+            .map(|ind| match (ind / 4096) % 3 {
+                0 => (ind % 251) as u8,
+                _ => {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as u8
+                },
+            })
+            .collect()
+    }
+
+    /// Части в папке по имени, без сборочных файлов
+    fn stored_parts(dir: &Path) -> BTreeMap<String, Vec<u8>> {
+        fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|extension| extension != "meta"))
+            .map(|path| (path.file_name().unwrap().to_string_lossy().to_string(), fs::read(&path).unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn parallel_encoding_matches_single_thread() {
+        let data = test_data(300_000);
+
+        for content_defined_chunking in [false, true] {
+            let uuid_parts = Uuid::new_v4().to_string();
+            let mut results = vec![];
+
+            for threads in [1, 4] {
+                let dir = temp_dir("threads");
+                let options = Options {
+                    path_for_save: Some(dir.clone()),
+                    part_size: Some(16_384),
+                    compressed: Some(true),
+                    parity_parts: Some(2),
+                    content_defined_chunking: Some(content_defined_chunking),
+                    threads: Some(threads),
+                    ..Default::default()
+                };
+
+                let separation_file = encode_stream(data.as_slice(), OsStr::new("data.bin"), None, options, uuid_parts.clone()).unwrap();
+                let metafile_path = dir.join(&separation_file.metafile);
+
+                let mut output = vec![];
+                file_assembly::decode_to_writer(&metafile_path, &mut output, Options { threads: Some(threads), ..Default::default() }).unwrap();
+                assert!(output == data, "Файл собран с ошибкой в {} потоков", threads);
+
+                results.push((stored_parts(&dir), fs::read(&metafile_path).unwrap()));
+
+                fs::remove_dir_all(&dir).unwrap();
+            }
+
+            let (single_parts, single_metafile) = &results[0];
+            let (parallel_parts, parallel_metafile) = &results[1];
+
+            assert!(single_parts.len() > 3);
+            assert_eq!(single_parts.keys().collect::<Vec<_>>(), parallel_parts.keys().collect::<Vec<_>>());
+            assert!(single_parts == parallel_parts, "Части различаются");
+            assert!(single_metafile == parallel_metafile, "Сборочные файлы различаются");
+        }
+    }
+
+    #[test]
+    fn encode_from_pipe_and_decode_to_writer() {
+        let data = test_data(200_000);
+        let dir = temp_dir("pipe");
+
+        // Источник отдает данные кусками, как канал между процессами
+        let (pipe_reader, mut pipe_writer) = io::pipe().unwrap();
+        let source = thread::spawn({
+            let data = data.clone();
+
+            move || {
+                for chunk in data.chunks(1000) {
+                    pipe_writer.write_all(chunk).unwrap();
+                }
+            }
+        });
+
+        let options = Options {
+            path_for_save: Some(dir.clone()),
+            part_size: Some(65_536),
+            compressed: Some(true),
+            passphrase: Some("passphrase".to_string()),
+            ..Default::default()
+        };
+
+        let separation_file = encode_reader(pipe_reader, OsStr::new("stream.bin"), options.clone()).unwrap();
+        source.join().unwrap();
+
+        assert!(separation_file.metadata.is_none());
+        assert_eq!(separation_file.file_name, "stream.bin");
+        assert_eq!(separation_file.parts.len(), 4);
+
+        let (mut pipe_reader, mut pipe_writer) = io::pipe().unwrap();
+        let receiver = thread::spawn(move || {
+            let mut output = vec![];
+            pipe_reader.read_to_end(&mut output).unwrap();
+            output
+        });
+
+        file_assembly::decode_to_writer(&dir.join(&separation_file.metafile), &mut pipe_writer, options).unwrap();
+        drop(pipe_writer);
+
+        assert!(receiver.join().unwrap() == data);
+        assert!(!dir.join("stream.bin").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn encode_into_folder_without_trailing_separator() {
+        let data = test_data(50_000);
+        let dir = temp_dir("no_separator");
+        let work_dir = dir.join("work");
+        let output_dir = dir.join("output");
+
+        fs::create_dir_all(&work_dir).unwrap();
+        fs::create_dir_all(&output_dir).unwrap();
+        fs::write(dir.join("data.bin"), &data).unwrap();
+
+        let options = Options {
+            path_for_save: Some(work_dir.clone()),
+            part_size: Some(16_384),
+            parity_parts: Some(1),
+            ..Default::default()
+        };
+
+        let separation_file = encode_file(&dir.join("data.bin"), options).unwrap();
+
+        let mut dir_names = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        dir_names.sort();
+
+        assert_eq!(dir_names, vec!["data.bin", "output", "work"]);
+        assert_eq!(stored_parts(&work_dir).len(), separation_file.parts.len() + separation_file.parity_parts.len());
+
+        file_assembly::decode_file(&work_dir.join(&separation_file.metafile), output_dir.clone()).unwrap();
+        assert!(fs::read(output_dir.join("data.bin")).unwrap() == data);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
\ No newline at end of file
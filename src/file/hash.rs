@@ -0,0 +1,161 @@
+use std::io::{self, Read, Write};
+
+use sha2::{Digest, Sha256};
+
+/// Алгоритм хеширования содержимого частей и исходного файла
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+
+    /// Идентификатор алгоритма в сборочном файле
+    pub fn id(self) -> u8 {
+        match self {
+            HashAlgorithm::Sha256 => 1,
+            HashAlgorithm::Blake3 => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(HashAlgorithm::Sha256),
+            2 => Some(HashAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    pub fn hasher(self) -> ContentHasher {
+        match self {
+            HashAlgorithm::Sha256 => ContentHasher::Sha256(Sha256::new()),
+            HashAlgorithm::Blake3 => ContentHasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    /// Хеш с ключом: *BLAKE3* в режиме с ключом, *SHA-256* с ключом перед данными
+    pub fn keyed_hasher(self, key: &[u8; 32]) -> ContentHasher {
+        match self {
+            HashAlgorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                Digest::update(&mut hasher, key);
+                ContentHasher::Sha256(hasher)
+            },
+            HashAlgorithm::Blake3 => ContentHasher::Blake3(Box::new(blake3::Hasher::new_keyed(key))),
+        }
+    }
+
+    pub fn digest(self, data: &[u8]) -> Vec<u8> {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+/// Шестнадцатеричная запись хеша
+pub fn to_hex(hash: &[u8]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Потоковое вычисление хеша содержимого
+#[derive(Debug, Clone)]
+pub enum ContentHasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl ContentHasher {
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            ContentHasher::Sha256(hasher) => hasher.update(data),
+            ContentHasher::Blake3(hasher) => {
+                hasher.update(data);
+            },
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            ContentHasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            ContentHasher::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+impl Write for ContentHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Запись, попутно вычисляющая хеш записанных байтов
+#[derive(Debug)]
+pub struct HashWriter<W: Write> {
+    inner: W,
+    hasher: ContentHasher,
+}
+
+impl<W: Write> HashWriter<W> {
+
+    pub fn new(inner: W, hasher: ContentHasher) -> Self {
+        Self {
+            inner,
+            hasher,
+        }
+    }
+
+    /// Возвращает вложенную запись и хеш всех записанных байтов
+    pub fn finish(self) -> (W, Vec<u8>) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Чтение, попутно вычисляющее хеш прочитанных байтов
+#[derive(Debug)]
+pub struct HashReader<R: Read> {
+    inner: R,
+    hasher: ContentHasher,
+}
+
+impl<R: Read> HashReader<R> {
+
+    pub fn new(inner: R, hasher: ContentHasher) -> Self {
+        Self {
+            inner,
+            hasher,
+        }
+    }
+
+    /// Возвращает вложенное чтение и хеш всех прочитанных байтов
+    pub fn finish(self) -> (R, Vec<u8>) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
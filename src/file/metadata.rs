@@ -0,0 +1,220 @@
+use std::{fs::{self, File}, io, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+#[cfg(unix)]
+use std::{ffi::OsStr, os::unix::{ffi::OsStrExt, fs::{MetadataExt, PermissionsExt}}};
+
+/// Сведения об исходном файле, восстанавливаемые при сборке
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// Права доступа Unix вместе с битами *setuid*, *setgid* и *sticky*
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Расширенные атрибуты: имя и значение
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl FileMetadata {
+
+    /// Чтение сведений о файле, расширенные атрибуты читаются по возможности
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+
+        #[allow(unused_mut)]
+        let mut file_metadata = Self {
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            ..Default::default()
+        };
+
+        #[cfg(unix)]
+        {
+            file_metadata.mode = Some(metadata.mode() & 0o7777);
+            file_metadata.uid = Some(metadata.uid());
+            file_metadata.gid = Some(metadata.gid());
+            file_metadata.xattrs = read_xattrs(path);
+        }
+
+        Ok(file_metadata)
+    }
+
+    /// Восстановление сведений у собранного файла.
+    /// Владелец и расширенные атрибуты восстанавливаются по возможности:
+    /// без прав суперпользователя владельца не сменить, а не каждая файловая система хранит атрибуты
+    pub fn restore(&self, path: &Path, restore_ownership: bool) -> io::Result<()> {
+
+        #[cfg(unix)]
+        {
+            for (name, value) in &self.xattrs {
+                if let Err(e) = xattr::set(path, OsStr::from_bytes(name), value) {
+                    println!("Не удалось восстановить атрибут {} => {}", String::from_utf8_lossy(name), e);
+                }
+            }
+
+            if restore_ownership && (self.uid.is_some() || self.gid.is_some()) {
+                if let Err(e) = std::os::unix::fs::chown(path, self.uid, self.gid) {
+                    println!("Не удалось восстановить владельца {} => {}", path.display(), e);
+                }
+            }
+        }
+
+        // Время изменения ставится до прав доступа, так как файл может стать доступным только для чтения.
+        // На Unix владельцу файла время меняется и без права записи
+        if let Some(modified) = self.modified {
+            #[cfg(unix)]
+            let file = File::open(path)?;
+            #[cfg(not(unix))]
+            let file = File::options().write(true).open(path)?;
+
+            file.set_modified(modified)?;
+        }
+
+        // Права доступа последними: смена владельца сбрасывает биты setuid и setgid
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Удаление прежнего собранного файла перед записью нового.
+/// Файл с восстановленными правами только для чтения не перезаписать, но можно удалить
+pub fn remove_previous_output(path: &Path) -> io::Result<()> {
+    if !path.is_file() {
+        return Ok(());
+    }
+
+    // Вне Unix файл только для чтения нельзя удалить
+    #[cfg(not(unix))]
+    {
+        let mut permissions = fs::metadata(path)?.permissions();
+
+        if permissions.readonly() {
+            permissions.set_readonly(false);
+            fs::set_permissions(path, permissions)?;
+        }
+    }
+
+    fs::remove_file(path)
+}
+
+/// Время в секундах и наносекундах от начала эпохи Unix, секунды отрицательны для времени до эпохи
+pub fn to_unix_time(time: SystemTime) -> (i64, u32) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => (duration.as_secs() as i64, duration.subsec_nanos()),
+        Err(e) => {
+            let duration = e.duration();
+
+            match duration.subsec_nanos() {
+                0 => (-(duration.as_secs() as i64), 0),
+                nanos => (-(duration.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+            }
+        },
+    }
+}
+
+pub fn from_unix_time(secs: i64, nanos: u32) -> SystemTime {
+    let since_epoch = match secs >= 0 {
+        true => UNIX_EPOCH + Duration::from_secs(secs as u64),
+        false => UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()),
+    };
+
+    since_epoch + Duration::from_nanos(nanos as u64)
+}
+
+#[cfg(unix)]
+fn read_xattrs(path: &Path) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let Ok(names) = xattr::list(path) else {
+        return vec![];
+    };
+
+    names
+        .filter_map(|name| {
+            let value = xattr::get(path, &name).ok()??;
+            Some((name.as_bytes().to_vec(), value))
+        })
+        .collect()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    /// Файл с заданными правами и временем изменения
+    fn source_file(path: &Path, mode: u32, modified: SystemTime) {
+        fs::write(path, b"metadata").unwrap();
+        File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn mtime_and_mode_round_trip() {
+        let dir = temp_dir("metadata");
+        let modified = from_unix_time(1_600_000_000, 123_456_789);
+
+        for mode in [0o640, 0o444, 0o2755] {
+            let source_path = dir.join("source");
+            let target_path = dir.join("target");
+
+            source_file(&source_path, mode, modified);
+            let file_metadata = FileMetadata::read(&source_path).unwrap();
+
+            assert_eq!(file_metadata.mode, Some(mode));
+            assert_eq!(file_metadata.modified, Some(modified));
+            assert_eq!(to_unix_time(modified), (1_600_000_000, 123_456_789));
+
+            fs::write(&target_path, b"metadata").unwrap();
+            file_metadata.restore(&target_path, true).unwrap();
+
+            // Повторное восстановление у файла, уже ставшего доступным только для чтения
+            file_metadata.restore(&target_path, true).unwrap();
+
+            let restored = FileMetadata::read(&target_path).unwrap();
+            assert_eq!(restored.mode, Some(mode));
+            assert_eq!(restored.modified, Some(modified));
+
+            for path in [&source_path, &target_path] {
+                remove_previous_output(path).unwrap();
+                assert!(!path.exists());
+            }
+        }
+
+        remove_previous_output(&dir.join("missing")).unwrap();
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn ownership_is_skipped_without_restore_ownership() {
+        let dir = temp_dir("ownership");
+        let target_path = dir.join("target");
+        fs::write(&target_path, b"metadata").unwrap();
+
+        let owner = FileMetadata::read(&target_path).unwrap();
+        let file_metadata = FileMetadata {
+            uid: owner.uid.map(|uid| uid + 1),
+            gid: owner.gid.map(|gid| gid + 1),
+            ..Default::default()
+        };
+
+        file_metadata.restore(&target_path, false).unwrap();
+
+        let restored = FileMetadata::read(&target_path).unwrap();
+        assert_eq!((restored.uid, restored.gid), (owner.uid, owner.gid));
+
+        // Без прав суперпользователя владелец не меняется, а ошибка только выводится
+        file_metadata.restore(&target_path, true).unwrap();
+
+        if owner.uid == Some(0) {
+            let restored = FileMetadata::read(&target_path).unwrap();
+            assert_eq!((restored.uid, restored.gid), (file_metadata.uid, file_metadata.gid));
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,28 +1,228 @@
 pub mod file_separation;
 pub mod file_assembly;
+pub mod chunking;
+pub mod compression;
+pub mod encryption;
+pub mod hash;
+pub mod metadata;
+pub mod part_header;
+mod parity;
+
+use std::ffi::{OsStr, OsString};
+
+pub use parity::MAX_PARTS;
+
+/// Схема имен частей: *{хеш содержимого}.chunk*
+pub const CHUNK_NAMES_CONTENT_HASH: u8 = 1;
+
+/// Имя части, названной по хешу содержимого
+pub fn chunk_file_name(content_hash: &[u8]) -> String {
+    format!("{}.chunk", hash::to_hex(content_hash))
+}
+
+/// Байты имени файла, на Unix в точности как в файловой системе
+pub fn os_str_bytes(name: &OsStr) -> Vec<u8> {
+    #[cfg(unix)]
+    return std::os::unix::ffi::OsStrExt::as_bytes(name).to_vec();
+
+    #[cfg(not(unix))]
+    return name.to_string_lossy().as_bytes().to_vec();
+}
+
+/// Имя и последнее расширение без точки, разделенные как у *Path*. Неверные байты UTF-8 заменяются
+pub fn split_file_name(file_name: &OsStr) -> (String, String) {
+    let path = std::path::Path::new(file_name);
+
+    (
+        path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default(),
+        path.extension().map(|extension| extension.to_string_lossy().to_string()).unwrap_or_default(),
+    )
+}
+
+pub fn os_string_from_bytes(bytes: Vec<u8>) -> OsString {
+    #[cfg(unix)]
+    return std::os::unix::ffi::OsStringExt::from_vec(bytes);
+
+    #[cfg(not(unix))]
+    return String::from_utf8_lossy(&bytes).into_owned().into();
+}
+
+/// Длина хеша, с которого начинается файл каждой части
+pub const PART_HASH_LEN: u64 = 16;
 
 /// Часть файла представленная массивом байтов
 #[derive(Debug, Clone)]
 pub struct FilePart {
     pub hash_bytes: Vec<u8>,
     pub part_file_name: String,
+    /// Длина данных части без хеша и заголовка в начале файла
+    pub part_len: u64,
+    /// Хеш данных части, пустой для сборочных файлов без хешей содержимого
+    pub content_hash: Vec<u8>,
+    /// Данные части сжаты алгоритмом *CompositeFile::compression*
+    pub compressed: bool,
+    /// Длина исходных данных части, *None* у паритетных частей и старых сборочных файлов
+    pub data_len: Option<u64>,
 }
 
 /// Собираемый файл
 #[derive(Debug, Clone)]
 pub struct CompositeFile {
+    /// Имя без последнего расширения, неверные байты UTF-8 заменены
     pub filename: String,
+    /// Последнее расширение без точки, пустое у файлов без расширения
     pub file_extension: String,
+    /// Полное имя исходного файла байт в байт, пустое у старых сборочных файлов
+    pub file_name: Vec<u8>,
     pub file_len: usize,
     pub parts: Vec<FilePart>,
+    /// Паритетные части Рида-Соломона, позволяющие восстановить потерянные части
+    pub parity_parts: Vec<FilePart>,
     pub uuid_parts: String,
+    /// Алгоритм хешей содержимого, *None* для сборочных файлов без хешей содержимого
+    pub hash_algorithm: Option<hash::HashAlgorithm>,
+    /// Хеш всего исходного файла
+    pub file_hash: Vec<u8>,
+    /// Алгоритм и уровень сжатия частей, *None* если части не сжимались
+    pub compression: Option<(compression::CompressionAlgorithm, i32)>,
+    /// Ключ шифрования частей, *None* если файл не шифровался
+    pub encryption: Option<encryption::FileCipher>,
+    /// Части разбиты по содержимому и названы по хешу содержимого
+    pub content_defined_chunks: bool,
+    /// Размер, время изменения, права, владелец и атрибуты исходного файла
+    pub metadata: Option<metadata::FileMetadata>,
+    /// Длина заголовка после хеша имени в каждой части, 0 у старых сборочных файлов
+    pub part_header_len: u64,
+}
+
+impl CompositeFile {
+
+    /// Имя собранного файла: сохраненное полное имя, у старых сборочных файлов имя и расширение через точку
+    pub fn output_file_name(&self) -> OsString {
+        match self.file_name.is_empty() {
+            true => format!("{}.{}", self.filename, self.file_extension).into(),
+            false => os_string_from_bytes(self.file_name.clone()),
+        }
+    }
+
+    /// Смещение данных в файле части: после хеша имени и заголовка
+    pub fn part_data_offset(&self) -> u64 {
+        PART_HASH_LEN + self.part_header_len
+    }
+
+    /// Имя части-описи, по которой восстанавливается порядок частей с границами по содержимому.
+    /// *None* у файлов с частями фиксированного размера и старых сборочных файлов
+    pub fn manifest_part_name(&self) -> Option<String> {
+        (self.content_defined_chunks && self.part_header_len != 0)
+            .then(|| format!("{}_manifest.part", self.uuid_parts))
+    }
 }
 
 /// Опции для настройки *file_separation* и *file_assembly*
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Options {
     pub path_for_save: Option<std::path::PathBuf>,
-    pub count_parts: Option<u8>,
+    /// Наибольшее кол-во частей файла, по умолчанию не ограничено
+    pub count_parts: Option<u32>,
     pub part_size: Option<usize>,
+    /// Сжимать части, уже сжатые файлы определяются и не сжимаются
     pub compressed: Option<bool>,
-}
\ No newline at end of file
+    /// Алгоритм сжатия, по умолчанию *zstd*
+    pub compression_algorithm: Option<compression::CompressionAlgorithm>,
+    /// Уровень сжатия, по умолчанию свой для каждого алгоритма
+    pub compression_level: Option<i32>,
+    /// Парольная фраза, из которой выводится ключ шифрования частей.
+    /// Зашифрованный файл собирается только с этой же фразой
+    pub passphrase: Option<String>,
+    /// Разбивать файл по содержимому, *part_size* при этом задает максимальный размер части.
+    /// Части называются по хешу содержимого, поэтому одинаковые части разных файлов совпадают
+    pub content_defined_chunking: Option<bool>,
+    /// Кол-во паритетных частей, столько же любых частей можно потерять
+    pub parity_parts: Option<u8>,
+    /// Алгоритм хешей содержимого частей и исходного файла, по умолчанию *SHA-256*
+    pub hash_algorithm: Option<hash::HashAlgorithm>,
+    /// Восстанавливать владельца и группу собранного файла, по умолчанию да
+    pub restore_ownership: Option<bool>,
+    /// Кол-во потоков, в которых части записываются при разделении и читаются при сборке, по умолчанию один.
+    /// В несколько потоков каждая обрабатываемая часть целиком находится в памяти
+    pub threads: Option<usize>,
+}
+
+impl Options {
+
+    /// Наибольшее кол-во частей файла.
+    /// Паритетные части считаются над полем из 256 элементов, поэтому с ними частей вместе не больше *MAX_PARTS*
+    pub fn max_count_parts(&self) -> usize {
+        let count_parts = self.count_parts.map(|count_parts| count_parts as usize).unwrap_or(usize::MAX);
+
+        match self.parity_parts.unwrap_or(0) as usize {
+            0 => count_parts,
+            parity_parts => count_parts.min(MAX_PARTS.saturating_sub(parity_parts)),
+        }
+    }
+
+    /// Кол-во потоков разделения и сборки, не меньше одного
+    pub fn threads(&self) -> usize {
+        self.threads.unwrap_or(1).max(1)
+    }
+}
+
+/// Заголовок и версии сборочного файла.
+/// Сборочный файл начинается с *[MAGIC][версия: u16][флаги: u32]* и заканчивается md5 всех предыдущих байтов
+pub(crate) mod metafile_format {
+    pub const MAGIC: [u8; 4] = *b"RCMF";
+    pub const HEADER_LEN: usize = MAGIC.len() + 2 + 4;
+    pub const CHECKSUM_LEN: usize = 16;
+
+    /// Без заголовка, длины строк *u8*, кол-во частей *usize*
+    pub const VERSION_NARROW: u16 = 0;
+    /// Без заголовка, начинается с *WIDE_MARKER*, длины строк *u32*, кол-во частей *u64*
+    pub const VERSION_WIDE: u16 = 1;
+    /// Заголовок с версией, флагами и контрольной суммой, разметка как у *VERSION_WIDE*
+    pub const VERSION_CURRENT: u16 = 2;
+
+    /// Начало сборочного файла версии *VERSION_WIDE*.
+    /// В старой разметке первым байтом идет длина имени *u8*, а пустое имя с расширением из 255 байт не встречается
+    pub const WIDE_MARKER: [u8; 2] = [0x00, 0xFF];
+}
+
+/// Флаги возможностей в заголовке сборочного файла, по одному на каждую секцию.
+/// Сборочный файл с неизвестными флагами не собирается
+pub(crate) mod metafile_flags {
+    pub const PARITY: u32 = 1 << 0;
+    pub const HASHES: u32 = 1 << 1;
+    pub const COMPRESSION: u32 = 1 << 2;
+    pub const ENCRYPTION: u32 = 1 << 3;
+    pub const CHUNKS: u32 = 1 << 4;
+    pub const METADATA: u32 = 1 << 5;
+    pub const FILE_NAME: u32 = 1 << 6;
+    pub const PART_SIZES: u32 = 1 << 7;
+    pub const PART_HEADERS: u32 = 1 << 8;
+
+    pub const ALL: u32 = PARITY | HASHES | COMPRESSION | ENCRYPTION | CHUNKS | METADATA | FILE_NAME | PART_SIZES | PART_HEADERS;
+}
+
+/// Секции сборочного файла, записываемые после хешей частей.
+/// Секция записывается как *[тег: u8][длина: u64][данные]*, неизвестные секции пропускаются
+pub(crate) mod metafile_section {
+    /// Кол-во паритетных частей, длины частей и хеши паритетных частей
+    pub const PARITY: u8 = 1;
+    /// Алгоритм, хеш исходного файла, хеши содержимого частей и паритетных частей
+    pub const HASHES: u8 = 2;
+    /// Алгоритм и уровень сжатия, признаки сжатия каждой части
+    pub const COMPRESSION: u8 = 3;
+    /// Параметры шифрования и зашифрованные имя и расширение исходного файла
+    pub const ENCRYPTION: u8 = 4;
+    /// Схема имен частей, названных по хешу содержимого
+    pub const CHUNKS: u8 = 5;
+    /// Размер, время изменения, права, владелец и расширенные атрибуты исходного файла.
+    /// У зашифрованного файла секция зашифрована
+    pub const METADATA: u8 = 6;
+    /// Полное имя исходного файла байт в байт. У зашифрованного файла секция зашифрована
+    pub const FILE_NAME: u8 = 7;
+    /// Длины исходных данных частей, по ним читается диапазон файла.
+    /// У зашифрованного файла секция зашифрована
+    pub const PART_SIZES: u8 = 8;
+    /// Длина заголовка в начале каждой части после хеша имени
+    pub const PART_HEADERS: u8 = 9;
+}
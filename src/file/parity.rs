@@ -0,0 +1,103 @@
+use std::io::{self, Read, Write};
+
+use reed_solomon_erasure::galois_8::ReedSolomon;
+
+/// Размер полосы, кодируемой за одну итерацию, ограничивает расход памяти
+const STRIPE_SIZE: u64 = 65_536;
+
+/// Максимальное кол-во частей вместе с паритетными
+pub const MAX_PARTS: usize = 256;
+
+/// Вычисляет паритетные части по данным частей.
+/// Части меньше самой длинной дополняются нулями, паритетные части имеют длину самой длинной части
+pub fn encode_parity<R: Read, W: Write>(data_parts: &mut [R], data_lens: &[u64], parity_parts: &mut [W]) -> io::Result<()> {
+
+    let reed_solomon = ReedSolomon::new(data_parts.len(), parity_parts.len())
+        .map_err(to_io_error)?;
+
+    let shard_len = data_lens.iter().copied().max().unwrap_or(0);
+    let mut offset = 0;
+
+    while offset < shard_len {
+        let stripe_len = STRIPE_SIZE.min(shard_len - offset);
+
+        let mut shards = data_parts
+            .iter_mut()
+            .zip(data_lens)
+            .map(|(part, &part_len)| read_stripe(part, part_len, offset, stripe_len))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        shards.resize(data_parts.len() + parity_parts.len(), vec![0; stripe_len as usize]);
+
+        reed_solomon.encode(&mut shards).map_err(to_io_error)?;
+
+        for (part, shard) in parity_parts.iter_mut().zip(&shards[data_parts.len()..]) {
+            part.write_all(shard)?;
+        }
+
+        offset += stripe_len;
+    }
+
+    Ok(())
+}
+
+/// Восстанавливает данные потерянных частей.
+/// *None* обозначает потерянную или испорченную часть,
+/// данные восстановленных частей записываются в *restored* по индексу части
+pub fn reconstruct<R: Read, W: Write>(
+    data_parts: &mut [Option<R>],
+    data_lens: &[u64],
+    parity_parts: &mut [Option<R>],
+    restored: &mut [(usize, W)]
+) -> io::Result<()> {
+
+    let reed_solomon = ReedSolomon::new(data_parts.len(), parity_parts.len())
+        .map_err(to_io_error)?;
+
+    let shard_len = data_lens.iter().copied().max().unwrap_or(0);
+    let mut offset = 0;
+
+    while offset < shard_len {
+        let stripe_len = STRIPE_SIZE.min(shard_len - offset);
+
+        let mut shards = data_parts
+            .iter_mut()
+            .zip(data_lens)
+            .chain(parity_parts.iter_mut().map(|part| (part, &shard_len)))
+            .map(|(part, &part_len)| part
+                .as_mut()
+                .map(|part| read_stripe(part, part_len, offset, stripe_len))
+                .transpose()
+            )
+            .collect::<io::Result<Vec<_>>>()?;
+
+        reed_solomon.reconstruct_data(&mut shards).map_err(to_io_error)?;
+
+        for (part_ind, part) in restored.iter_mut() {
+            let shard = shards[*part_ind]
+                .as_ref()
+                .expect("reconstruct_data восстанавливает все части данных");
+            let available = data_lens[*part_ind].saturating_sub(offset).min(stripe_len);
+
+            part.write_all(&shard[..available as usize])?;
+        }
+
+        offset += stripe_len;
+    }
+
+    Ok(())
+}
+
+/// Чтение полосы части, байты за концом части заполняются нулями
+fn read_stripe(part: &mut impl Read, part_len: u64, offset: u64, stripe_len: u64) -> io::Result<Vec<u8>> {
+    let mut stripe = vec![0; stripe_len as usize];
+    let available = part_len.saturating_sub(offset).min(stripe_len);
+
+    part.read_exact(&mut stripe[..available as usize])?;
+
+    Ok(stripe)
+}
+
+fn to_io_error(err: reed_solomon_erasure::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, err)
+}
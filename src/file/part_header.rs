@@ -0,0 +1,349 @@
+use super::{CompositeFile, FilePart, PART_HASH_LEN};
+use super::compression::CompressionAlgorithm;
+use super::encryption::{self, EncryptionParams, NONCE_LEN, SALT_LEN, TAG_LEN};
+use super::hash::HashAlgorithm;
+
+/// Начало заголовка части
+pub const MAGIC: [u8; 4] = *b"RCPH";
+pub const VERSION: u16 = 1;
+
+/// Место под хеш в заголовке, хеши короче дополняются нулями
+const MAX_HASH_LEN: usize = 32;
+
+/// Место под имя исходного файла, более длинные имена обрезаются.
+/// Заголовок одной длины у всех частей, поэтому диапазон части читается без скачивания заголовка
+pub const MAX_FILE_NAME_LEN: usize = 255;
+
+/// Длина заголовка вместе с местом под зашифрованное имя файла
+pub const HEADER_LEN: u64 = (MAGIC.len() + 2 + 4
+    + 16 + 4 * 3 + 8 * 2 + 1
+    + 2 + MAX_HASH_LEN * 2
+    + 1 + 4
+    + 1 + 4 * 3 + SALT_LEN + NONCE_LEN
+    + 2 + MAX_FILE_NAME_LEN + TAG_LEN) as u64;
+
+/// Признаки части в заголовке
+mod header_flags {
+    pub const COMPRESSED: u8 = 1 << 0;
+    pub const CONTENT_DEFINED_CHUNK: u8 = 1 << 1;
+    /// Длина исходных данных части известна, у зашифрованных файлов она не записывается
+    pub const DATA_LEN: u8 = 1 << 2;
+    pub const MANIFEST: u8 = 1 << 3;
+}
+
+/// Заголовок, записываемый в каждую часть после хеша имени.
+/// По заголовкам частей без сборочного файла восстанавливается сборочный файл: чья это часть,
+/// ее номер, кол-во частей, длины, хеши и параметры сжатия и шифрования файла.
+/// Часть с границами по содержимому бывает общей для нескольких файлов и повторяется в файле,
+/// поэтому ее заголовок описывает только содержимое, а файл и порядок частей описывает часть-опись.
+/// Разметка: *[MAGIC][версия: u16][длина заголовка: u32][uuid: 16][номер, кол-во частей и паритетных частей: u32]
+/// [длина части, длина исходных данных: u64][признаки: u8][алгоритм хеша: u8][длина хеша: u8][хеш части][хеш файла]
+/// [сжатие: u8][уровень: i32][шифрование: u8][m, t, p: u32][соль][основа nonce][длина имени: u16][имя]*,
+/// все числа в порядке *big-endian*
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartHeader {
+    /// Идентификатор частей файла, *CompositeFile::uuid_parts*, пустой у частей с границами по содержимому
+    pub uuid_parts: String,
+    /// Номер части, паритетные части нумеруются после частей файла
+    pub part_number: u32,
+    pub count_parts: u32,
+    pub count_parity_parts: u32,
+    /// Длина данных части без хеша имени и заголовка
+    pub part_len: u64,
+    /// Длина исходных данных части, *None* у паритетных частей и зашифрованных файлов
+    pub data_len: Option<u64>,
+    pub compressed: bool,
+    /// Часть названа по хешу содержимого
+    pub content_defined_chunk: bool,
+    /// Часть-опись файла с границами по содержимому, данные - имена частей файла по порядку
+    pub manifest: bool,
+    pub hash_algorithm: HashAlgorithm,
+    pub content_hash: Vec<u8>,
+    pub file_hash: Vec<u8>,
+    pub compression: Option<(CompressionAlgorithm, i32)>,
+    pub encryption: Option<EncryptionParams>,
+    /// Полное имя исходного файла, обрезанное до *MAX_FILE_NAME_LEN*.
+    /// У зашифрованного файла зашифровано ключом файла
+    pub file_name: Vec<u8>,
+}
+
+impl PartHeader {
+
+    /// Заголовок части файла или паритетной части, *part_ind* считается по частям файла, затем по паритетным частям
+    pub fn for_part(composite_file: &CompositeFile, part_ind: usize) -> Self {
+        let part = composite_file.parts
+            .iter()
+            .chain(&composite_file.parity_parts)
+            .nth(part_ind)
+            .expect("Номер части за пределами файла");
+
+        if composite_file.content_defined_chunks && part_ind < composite_file.parts.len() {
+            return Self::for_chunk(composite_file, part);
+        }
+
+        Self {
+            part_number: (part_ind + 1) as u32,
+            part_len: part.part_len,
+            data_len: part.data_len.filter(|_| composite_file.encryption.is_none()),
+            compressed: part.compressed,
+            content_hash: part.content_hash.clone(),
+            ..Self::for_file(composite_file)
+        }
+    }
+
+    /// Заголовок части-описи с данными *manifest*
+    pub fn for_manifest(composite_file: &CompositeFile, manifest: &[u8]) -> Self {
+        let hash_algorithm = composite_file.hash_algorithm.unwrap_or_default();
+
+        Self {
+            part_len: manifest.len() as u64,
+            manifest: true,
+            content_hash: hash_algorithm.digest(manifest),
+            ..Self::for_file(composite_file)
+        }
+    }
+
+    /// Заголовок части с границами по содержимому без сведений о файле, одинаковый у всех файлов с этой частью
+    fn for_chunk(composite_file: &CompositeFile, part: &FilePart) -> Self {
+        Self {
+            uuid_parts: String::new(),
+            part_number: 0,
+            count_parts: 0,
+            count_parity_parts: 0,
+            part_len: part.part_len,
+            data_len: part.data_len.filter(|_| composite_file.encryption.is_none()),
+            compressed: part.compressed,
+            content_defined_chunk: true,
+            manifest: false,
+            hash_algorithm: composite_file.hash_algorithm.unwrap_or_default(),
+            content_hash: part.content_hash.clone(),
+            file_hash: vec![],
+            compression: None,
+            encryption: None,
+            file_name: vec![],
+        }
+    }
+
+    /// Сведения о файле без сведений о части
+    fn for_file(composite_file: &CompositeFile) -> Self {
+        let file_name = &composite_file.file_name[..composite_file.file_name.len().min(MAX_FILE_NAME_LEN)];
+
+        Self {
+            uuid_parts: composite_file.uuid_parts.clone(),
+            part_number: 0,
+            count_parts: composite_file.parts.len() as u32,
+            count_parity_parts: composite_file.parity_parts.len() as u32,
+            part_len: 0,
+            data_len: None,
+            compressed: false,
+            content_defined_chunk: composite_file.content_defined_chunks,
+            manifest: false,
+            hash_algorithm: composite_file.hash_algorithm.unwrap_or_default(),
+            content_hash: vec![],
+            file_hash: composite_file.file_hash.clone(),
+            compression: composite_file.compression,
+            encryption: composite_file.encryption.as_ref().map(|cipher| cipher.params.clone()),
+            file_name: match &composite_file.encryption {
+                Some(cipher) => cipher.seal_metadata(encryption::METADATA_PART_HEADER_FILE_NAME, file_name),
+                None => file_name.to_vec(),
+            },
+        }
+    }
+
+    /// Заголовок длиной ровно *HEADER_LEN*
+    pub fn encode(&self) -> Vec<u8> {
+        let mut header = Vec::with_capacity(HEADER_LEN as usize);
+
+        header.extend_from_slice(&MAGIC);
+        header.extend_from_slice(&VERSION.to_be_bytes());
+        header.extend_from_slice(&(HEADER_LEN as u32).to_be_bytes());
+        header.extend_from_slice(&uuid_bytes(&self.uuid_parts));
+        header.extend_from_slice(&self.part_number.to_be_bytes());
+        header.extend_from_slice(&self.count_parts.to_be_bytes());
+        header.extend_from_slice(&self.count_parity_parts.to_be_bytes());
+        header.extend_from_slice(&self.part_len.to_be_bytes());
+        header.extend_from_slice(&self.data_len.unwrap_or(0).to_be_bytes());
+
+        let flags = [
+            (self.compressed, header_flags::COMPRESSED),
+            (self.content_defined_chunk, header_flags::CONTENT_DEFINED_CHUNK),
+            (self.data_len.is_some(), header_flags::DATA_LEN),
+            (self.manifest, header_flags::MANIFEST),
+        ]
+            .into_iter()
+            .filter(|(used, _)| *used)
+            .fold(0, |flags, (_, flag)| flags | flag);
+        header.push(flags);
+
+        header.push(self.hash_algorithm.id());
+        header.push(self.content_hash.len().min(MAX_HASH_LEN) as u8);
+        write_padded(&mut header, &self.content_hash, MAX_HASH_LEN);
+        write_padded(&mut header, &self.file_hash, MAX_HASH_LEN);
+
+        let (compression_id, compression_level) = self.compression
+            .map(|(compression_algorithm, compression_level)| (compression_algorithm.id(), compression_level))
+            .unwrap_or((0, 0));
+        header.push(compression_id);
+        header.extend_from_slice(&compression_level.to_be_bytes());
+
+        match &self.encryption {
+            Some(params) => {
+                header.push(encryption::CHACHA20_POLY1305);
+                header.extend_from_slice(&params.m_cost.to_be_bytes());
+                header.extend_from_slice(&params.t_cost.to_be_bytes());
+                header.extend_from_slice(&params.p_cost.to_be_bytes());
+                header.extend_from_slice(&params.salt);
+                header.extend_from_slice(&params.base_nonce);
+            },
+            None => header.resize(header.len() + 1 + 4 * 3 + SALT_LEN + NONCE_LEN, 0),
+        }
+
+        let file_name = &self.file_name[..self.file_name.len().min(MAX_FILE_NAME_LEN + TAG_LEN)];
+        header.extend_from_slice(&(file_name.len() as u16).to_be_bytes());
+        write_padded(&mut header, file_name, MAX_FILE_NAME_LEN + TAG_LEN);
+
+        header
+    }
+
+    /// Разбор заголовка, *None* если байты не являются заголовком части известной версии
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let mut reader = HeaderReader { bytes, pos: 0 };
+
+        if reader.take(MAGIC.len())? != MAGIC || reader.u16()? != VERSION || reader.u32()? as u64 != HEADER_LEN {
+            return None;
+        }
+
+        let uuid_parts = match uuid::Uuid::from_slice(reader.take(16)?).ok()? {
+            uuid if uuid.is_nil() => String::new(),
+            uuid => uuid.to_string(),
+        };
+        let part_number = reader.u32()?;
+        let count_parts = reader.u32()?;
+        let count_parity_parts = reader.u32()?;
+        let part_len = reader.u64()?;
+        let data_len = reader.u64()?;
+        let flags = reader.u8()?;
+
+        let hash_algorithm = HashAlgorithm::from_id(reader.u8()?)?;
+        let hash_len = (reader.u8()? as usize).min(MAX_HASH_LEN);
+        let content_hash = reader.take(MAX_HASH_LEN)?[..hash_len].to_vec();
+        let file_hash = reader.take(MAX_HASH_LEN)?[..hash_len].to_vec();
+
+        let compression_id = reader.u8()?;
+        let compression_level = reader.u32()? as i32;
+        let compression = match compression_id {
+            0 => None,
+            compression_id => Some((CompressionAlgorithm::from_id(compression_id)?, compression_level)),
+        };
+
+        let encryption_id = reader.u8()?;
+        let m_cost = reader.u32()?;
+        let t_cost = reader.u32()?;
+        let p_cost = reader.u32()?;
+        let salt = reader.take(SALT_LEN)?.try_into().ok()?;
+        let base_nonce = reader.take(NONCE_LEN)?.try_into().ok()?;
+        let encryption = match encryption_id {
+            0 => None,
+            encryption::CHACHA20_POLY1305 => Some(EncryptionParams { salt, base_nonce, m_cost, t_cost, p_cost }),
+            _ => return None,
+        };
+
+        let file_name_len = (reader.u16()? as usize).min(MAX_FILE_NAME_LEN + TAG_LEN);
+        let file_name = reader.take(MAX_FILE_NAME_LEN + TAG_LEN)?[..file_name_len].to_vec();
+
+        Some(Self {
+            uuid_parts,
+            part_number,
+            count_parts,
+            count_parity_parts,
+            part_len,
+            data_len: (flags & header_flags::DATA_LEN != 0).then_some(data_len),
+            compressed: flags & header_flags::COMPRESSED != 0,
+            content_defined_chunk: flags & header_flags::CONTENT_DEFINED_CHUNK != 0,
+            manifest: flags & header_flags::MANIFEST != 0,
+            hash_algorithm,
+            content_hash,
+            file_hash,
+            compression,
+            encryption,
+            file_name,
+        })
+    }
+}
+
+/// Часть-опись файла с границами по содержимому: хеш имени, заголовок и имена частей файла по порядку
+/// в виде *[длина имени: u16][имя]*. Возвращает имя и содержимое части, *None* у других файлов
+pub fn manifest_part(composite_file: &CompositeFile) -> Option<(String, Vec<u8>)> {
+    let part_file_name = composite_file.manifest_part_name()?;
+
+    let mut manifest = vec![];
+
+    for part in &composite_file.parts {
+        manifest.extend_from_slice(&(part.part_file_name.len() as u16).to_be_bytes());
+        manifest.extend_from_slice(part.part_file_name.as_bytes());
+    }
+
+    let mut part_bytes = Vec::with_capacity((PART_HASH_LEN + HEADER_LEN) as usize + manifest.len());
+    part_bytes.extend_from_slice(&md5::compute(&part_file_name).0);
+    part_bytes.extend_from_slice(&PartHeader::for_manifest(composite_file, &manifest).encode());
+    part_bytes.extend_from_slice(&manifest);
+
+    Some((part_file_name, part_bytes))
+}
+
+/// Имена частей из данных части-описи, *None* если их меньше *count_parts* или данные испорчены
+pub fn decode_manifest(manifest: &[u8], count_parts: usize) -> Option<Vec<String>> {
+    let mut reader = HeaderReader { bytes: manifest, pos: 0 };
+
+    let part_names = (0..count_parts)
+        .map(|_| {
+            let name_len = reader.u16()? as usize;
+            String::from_utf8(reader.take(name_len)?.to_vec()).ok()
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    (reader.pos == manifest.len()).then_some(part_names)
+}
+
+fn uuid_bytes(uuid_parts: &str) -> [u8; 16] {
+    uuid::Uuid::parse_str(uuid_parts)
+        .map(|uuid| *uuid.as_bytes())
+        .unwrap_or_default()
+}
+
+/// Запись байтов в поле фиксированной длины, остаток заполняется нулями
+fn write_padded(header: &mut Vec<u8>, bytes: &[u8], field_len: usize) {
+    let bytes = &bytes[..bytes.len().min(field_len)];
+
+    header.extend_from_slice(bytes);
+    header.resize(header.len() + field_len - bytes.len(), 0);
+}
+
+struct HeaderReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> HeaderReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.bytes.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_be_bytes(self.take(8)?.try_into().ok()?))
+    }
+}
@@ -2,14 +2,49 @@
 #![feature(file_create_new)]
 
 mod core;
-mod file;
+pub mod file;
 pub mod cloud;
 pub mod vfs;
 pub mod telegram_backend;
+pub mod local_backend;
+pub mod memory_backend;
+pub mod s3_backend;
+pub mod webdav_backend;
+pub mod replicated_backend;
+#[cfg(test)]
+mod test_utils;
 
-use std::{io, path};
+use std::{fs, future::Future, io::{self, Read, Seek}, path, sync::Arc};
 use cloud::error::CloudError;
 
+/// Размер части по умолчанию (1 GiB)
+pub const DEFAULT_PART_SIZE: u64 = 1_073_741_824;
+
+/// Ограничения и возможности хранилища, по которым *Cloud* подбирает размер частей
+/// и кол-во одновременных операций
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BackendCapabilities {
+    /// Максимальный размер одного файла в хранилище
+    pub max_object_size: u64,
+    /// Размер части, с которым хранилище работает лучше всего
+    pub preferred_part_size: u64,
+    /// Максимальное кол-во одновременных загрузок и скачиваний
+    pub max_concurrent_transfers: usize,
+    /// Хранилище умеет отдавать диапазон байтов файла
+    pub ranged_reads: bool,
+}
+
+impl Default for BackendCapabilities {
+    fn default() -> Self {
+        Self {
+            max_object_size: u64::MAX,
+            preferred_part_size: DEFAULT_PART_SIZE,
+            max_concurrent_transfers: 4,
+            ranged_reads: false,
+        }
+    }
+}
+
 pub trait CloudBackend {
     fn create(input: impl io::Read, output: impl io::Write) -> Self;
     fn load(&self) -> Result<(), CloudError>;
@@ -18,4 +53,129 @@ pub trait CloudBackend {
     fn remove_file(&self, file_path: &path::Path) -> Result<(), CloudError>;
     fn check_file(&self, file_name: &str) -> bool;
     fn close(self) -> Result<(), CloudError>;
+
+    /// Скачивание *len* байтов файла начиная с *offset*. Хранилища без поддержки диапазонов
+    /// скачивают файл целиком в *file_path* и читают из него нужные байты
+    fn download_file_range(&self, file_path: &path::Path, offset: u64, len: u64) -> Result<Vec<u8>, CloudError> {
+        self.download_file(file_path)?;
+
+        let mut file = fs::File::open(file_path)?;
+        file.seek(io::SeekFrom::Start(offset))?;
+
+        let mut bytes = vec![];
+        file.take(len).read_to_end(&mut bytes)?;
+
+        Ok(bytes)
+    }
+
+    /// Ограничения хранилища, по умолчанию хранилище ничем не ограничено
+    fn capabilities(&self) -> BackendCapabilities {
+        BackendCapabilities::default()
+    }
+
+    /// Имена реплик, на которых хранится файл. Пусто, если хранилище не реплицируется
+    fn replicas_of(&self, _file_name: &str) -> Vec<String> {
+        vec![]
+    }
+
+    /// Запоминает реплики файла, записанные в VFS. Вызывается при загрузке VFS
+    fn restore_replicas(&self, _file_name: &str, _replicas: &[String]) {}
+
+    /// Восстанавливает копии файла на репликах, где он отсутствует.
+    /// *file_path* используется как временное место для файла
+    fn repair_file(&self, _file_path: &path::Path) -> Result<(), CloudError> {
+        Ok(())
+    }
+
+    /// Имена всех файлов в хранилище, по ним находятся части, на которые не ссылается VFS
+    fn list_files(&self) -> Result<Vec<String>, CloudError> {
+        Err(CloudError::BackendError {
+            message: "Хранилище не умеет перечислять файлы".to_string()
+        })
+    }
+}
+
+/// Асинхронное хранилище, операции которого *Cloud* выполняет параллельно.
+/// Операции принимают владение путями, чтобы их можно было выполнять в отдельных задачах
+pub trait AsyncCloudBackend: Send + Sync + Sized + 'static {
+    fn create(input: impl io::Read, output: impl io::Write) -> Self;
+    fn load(self: Arc<Self>) -> impl Future<Output = Result<(), CloudError>> + Send;
+    fn upload_file(self: Arc<Self>, file_path: path::PathBuf) -> impl Future<Output = Result<(), CloudError>> + Send;
+    fn download_file(self: Arc<Self>, file_path: path::PathBuf) -> impl Future<Output = Result<(), CloudError>> + Send;
+    fn download_file_range(self: Arc<Self>, file_path: path::PathBuf, offset: u64, len: u64) -> impl Future<Output = Result<Vec<u8>, CloudError>> + Send;
+    fn remove_file(self: Arc<Self>, file_path: path::PathBuf) -> impl Future<Output = Result<(), CloudError>> + Send;
+    fn check_file(self: Arc<Self>, file_name: String) -> impl Future<Output = bool> + Send;
+    fn close(self) -> impl Future<Output = Result<(), CloudError>> + Send;
+    fn capabilities(&self) -> BackendCapabilities;
+    fn replicas_of(&self, file_name: &str) -> Vec<String>;
+    fn restore_replicas(&self, file_name: &str, replicas: &[String]);
+    fn repair_file(self: Arc<Self>, file_path: path::PathBuf) -> impl Future<Output = Result<(), CloudError>> + Send;
+    fn list_files(self: Arc<Self>) -> impl Future<Output = Result<Vec<String>, CloudError>> + Send;
+}
+
+/// Адаптер синхронных хранилищ: блокирующие операции выполняются в пуле потоков *tokio*,
+/// поэтому вызывать их можно только внутри *tokio* runtime
+impl<T: CloudBackend + Send + Sync + 'static> AsyncCloudBackend for T {
+    fn create(input: impl io::Read, output: impl io::Write) -> Self {
+        <T as CloudBackend>::create(input, output)
+    }
+
+    async fn load(self: Arc<Self>) -> Result<(), CloudError> {
+        run_blocking(move || CloudBackend::load(&*self)).await?
+    }
+
+    async fn upload_file(self: Arc<Self>, file_path: path::PathBuf) -> Result<(), CloudError> {
+        run_blocking(move || CloudBackend::upload_file(&*self, &file_path)).await?
+    }
+
+    async fn download_file(self: Arc<Self>, file_path: path::PathBuf) -> Result<(), CloudError> {
+        run_blocking(move || CloudBackend::download_file(&*self, &file_path)).await?
+    }
+
+    async fn download_file_range(self: Arc<Self>, file_path: path::PathBuf, offset: u64, len: u64) -> Result<Vec<u8>, CloudError> {
+        run_blocking(move || CloudBackend::download_file_range(&*self, &file_path, offset, len)).await?
+    }
+
+    async fn remove_file(self: Arc<Self>, file_path: path::PathBuf) -> Result<(), CloudError> {
+        run_blocking(move || CloudBackend::remove_file(&*self, &file_path)).await?
+    }
+
+    async fn check_file(self: Arc<Self>, file_name: String) -> bool {
+        run_blocking(move || CloudBackend::check_file(&*self, &file_name))
+            .await
+            .unwrap_or(false)
+    }
+
+    async fn close(self) -> Result<(), CloudError> {
+        run_blocking(move || CloudBackend::close(self)).await?
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        CloudBackend::capabilities(self)
+    }
+
+    fn replicas_of(&self, file_name: &str) -> Vec<String> {
+        CloudBackend::replicas_of(self, file_name)
+    }
+
+    fn restore_replicas(&self, file_name: &str, replicas: &[String]) {
+        CloudBackend::restore_replicas(self, file_name, replicas)
+    }
+
+    async fn repair_file(self: Arc<Self>, file_path: path::PathBuf) -> Result<(), CloudError> {
+        run_blocking(move || CloudBackend::repair_file(&*self, &file_path)).await?
+    }
+
+    async fn list_files(self: Arc<Self>) -> Result<Vec<String>, CloudError> {
+        run_blocking(move || CloudBackend::list_files(&*self)).await?
+    }
+}
+
+/// Выполнение блокирующей операции хранилища в пуле потоков *tokio*
+async fn run_blocking<R: Send + 'static>(operation: impl FnOnce() -> R + Send + 'static) -> Result<R, CloudError> {
+    tokio::task::spawn_blocking(operation)
+        .await
+        .map_err(|e| CloudError::BackendError {
+            message: format!("Операция хранилища прервана: {}", e)
+        })
 }
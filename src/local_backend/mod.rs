@@ -0,0 +1,181 @@
+use std::fs;
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use crate::cloud::error::CloudError;
+use crate::{BackendCapabilities, CloudBackend};
+
+/// Папка хранилища по умолчанию
+const DEFAULT_STORAGE_DIR: &str = "./local_storage/";
+
+/// Окончание временного файла, в который копируется файл при загрузке
+const TMP_SUFFIX: &str = ".tmp";
+
+/// Хранилище частей и сборочных файлов в локальной папке.
+/// Позволяет работать с *Cloud* без подключения к Telegram.
+#[derive(Debug, Clone)]
+pub struct LocalDirBackend {
+    storage_dir: PathBuf,
+}
+
+impl LocalDirBackend {
+
+    pub fn new(storage_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            storage_dir: storage_dir.into(),
+        }
+    }
+
+    /// Папка, в которой хранятся файлы
+    pub fn storage_dir(&self) -> &Path {
+        &self.storage_dir
+    }
+
+    /// Путь к файлу внутри хранилища, файлы хранятся по имени без вложенных папок
+    fn storage_path(&self, file_path: &Path) -> Result<PathBuf, CloudError> {
+        let file_name = file_path
+            .file_name()
+            .ok_or(CloudError::IOError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Путь {} не содержит имени файла", file_path.display())
+            )))?;
+
+        Ok(self.storage_dir.join(file_name))
+    }
+}
+
+/// Имя файла без разделителей пути, не равное *.* или *..*, такое имя не выходит за пределы хранилища
+fn is_storage_file_name(file_name: &str) -> bool {
+    !matches!(file_name, "" | "." | "..") && !file_name.contains(['/', '\\'])
+}
+
+impl CloudBackend for LocalDirBackend {
+    fn create(_input: impl Read, _output: impl Write) -> Self {
+        fs::create_dir_all(DEFAULT_STORAGE_DIR)
+            .expect("Не удалось создать папку хранилища");
+
+        Self::new(DEFAULT_STORAGE_DIR)
+    }
+
+    fn load(&self) -> Result<(), CloudError> {
+        fs::create_dir_all(&self.storage_dir)?;
+        Ok(())
+    }
+
+    fn upload_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let storage_path = self.storage_path(file_path)?;
+
+        // Копирование во временный файл, чтобы в хранилище не осталось недописанной части
+        let mut tmp_path = storage_path.clone().into_os_string();
+        tmp_path.push(TMP_SUFFIX);
+
+        fs::copy(file_path, &tmp_path)?;
+        fs::rename(&tmp_path, &storage_path)?;
+
+        Ok(())
+    }
+
+    fn download_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let storage_path = self.storage_path(file_path)?;
+
+        fs::copy(&storage_path, file_path)?;
+
+        Ok(())
+    }
+
+    fn download_file_range(&self, file_path: &Path, offset: u64, len: u64) -> Result<Vec<u8>, CloudError> {
+        let mut file = fs::File::open(self.storage_path(file_path)?)?;
+        file.seek(io::SeekFrom::Start(offset))?;
+
+        let mut bytes = vec![];
+        file.take(len).read_to_end(&mut bytes)?;
+
+        Ok(bytes)
+    }
+
+    fn remove_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let storage_path = self.storage_path(file_path)?;
+
+        fs::remove_file(&storage_path)?;
+
+        Ok(())
+    }
+
+    fn check_file(&self, file_name: &str) -> bool {
+        is_storage_file_name(file_name) && self.storage_dir.join(file_name).is_file()
+    }
+
+    fn close(self) -> Result<(), CloudError> {
+        Ok(())
+    }
+
+    /// Временные файлы прерванных загрузок не перечисляются
+    fn list_files(&self) -> Result<Vec<String>, CloudError> {
+        let mut file_names = vec![];
+
+        for entry in fs::read_dir(&self.storage_dir)? {
+            let entry = entry?;
+            let file_name = entry.file_name().to_string_lossy().to_string();
+
+            if entry.file_type()?.is_file() && !file_name.ends_with(TMP_SUFFIX) {
+                file_names.push(file_name);
+            }
+        }
+
+        Ok(file_names)
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        BackendCapabilities {
+            ranged_reads: true,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    fn temp_storage() -> LocalDirBackend {
+        let storage_dir = temp_dir("local").join("storage");
+        fs::create_dir_all(&storage_dir).unwrap();
+
+        LocalDirBackend::new(storage_dir)
+    }
+
+    #[test]
+    fn check_file_stays_inside_storage() {
+        let backend = temp_storage();
+
+        fs::write(backend.storage_dir().join("part.part"), b"part").unwrap();
+        fs::write(backend.storage_dir().join("../outside.part"), b"outside").unwrap();
+
+        assert!(backend.check_file("part.part"));
+        assert!(!backend.check_file("../outside.part"));
+        assert!(!backend.check_file("storage/part.part"));
+        assert!(!backend.check_file("..\\outside.part"));
+        assert!(!backend.check_file(".."));
+        assert!(!backend.check_file("."));
+        assert!(!backend.check_file(""));
+
+        fs::write(backend.storage_dir().join("a..b.part"), b"part").unwrap();
+        assert!(backend.check_file("a..b.part"));
+
+        fs::remove_dir_all(backend.storage_dir().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn list_files_skips_interrupted_uploads() {
+        let backend = temp_storage();
+
+        fs::write(backend.storage_dir().join("a.part"), b"part").unwrap();
+        fs::write(backend.storage_dir().join("b.part.tmp"), b"half").unwrap();
+        fs::create_dir(backend.storage_dir().join("nested")).unwrap();
+
+        assert_eq!(backend.list_files().unwrap(), vec!["a.part".to_string()]);
+
+        fs::remove_dir_all(backend.storage_dir().parent().unwrap()).unwrap();
+    }
+}
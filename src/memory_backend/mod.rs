@@ -0,0 +1,387 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::cloud::error::CloudError;
+use crate::{BackendCapabilities, CloudBackend};
+
+/// Сбои, которые *InMemoryBackend* внедряет в свою работу.
+/// Номера операций считаются с 1 от создания хранилища.
+#[derive(Debug, Clone, Default)]
+pub struct FaultOptions {
+    /// Номер загрузки, которая завершится ошибкой
+    pub fail_upload: Option<usize>,
+    /// Номер загрузки, которая завершится успешно, но файл не попадет в хранилище
+    pub drop_upload: Option<usize>,
+    /// Номер скачивания, при котором байты файла будут испорчены
+    pub corrupt_download: Option<usize>,
+    /// Номер скачивания, которое завершится ошибкой. Скачивания диапазонов тоже считаются
+    pub fail_download: Option<usize>,
+    /// Задержка перед каждой операцией
+    pub latency: Option<Duration>,
+}
+
+/// Хранилище файлов в оперативной памяти с программируемыми сбоями.
+/// Предназначено для проверки поведения *Cloud* при ошибках хранилища.
+#[derive(Debug, Default)]
+pub struct InMemoryBackend {
+    files: RwLock<HashMap<String, Vec<u8>>>,
+    faults: RwLock<FaultOptions>,
+    capabilities: RwLock<BackendCapabilities>,
+    upload_count: AtomicUsize,
+    download_count: AtomicUsize,
+    downloaded_bytes: AtomicU64,
+}
+
+impl InMemoryBackend {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_faults(faults: FaultOptions) -> Self {
+        Self {
+            faults: RwLock::new(faults),
+            ..Default::default()
+        }
+    }
+
+    /// Заменяет сбои, номера операций при этом не сбрасываются
+    pub fn set_faults(&self, faults: FaultOptions) {
+        *self.faults.write().unwrap() = faults;
+    }
+
+    /// Заменяет ограничения хранилища, например для проверки подбора размера частей
+    pub fn set_capabilities(&self, capabilities: BackendCapabilities) {
+        *self.capabilities.write().unwrap() = capabilities;
+    }
+
+    /// Имена всех хранимых файлов
+    pub fn file_names(&self) -> Vec<String> {
+        self.files.read().unwrap().keys().cloned().collect()
+    }
+
+    /// Кол-во вызовов *upload_file*
+    pub fn upload_count(&self) -> usize {
+        self.upload_count.load(Ordering::SeqCst)
+    }
+
+    /// Кол-во вызовов *download_file* и *download_file_range*
+    pub fn download_count(&self) -> usize {
+        self.download_count.load(Ordering::SeqCst)
+    }
+
+    /// Кол-во отданных байтов
+    pub fn downloaded_bytes(&self) -> u64 {
+        self.downloaded_bytes.load(Ordering::SeqCst)
+    }
+
+    /// Номер очередного скачивания или ошибка, если на нем внедрен сбой
+    fn start_download(&self) -> Result<usize, CloudError> {
+        let download_number = self.download_count.fetch_add(1, Ordering::SeqCst) + 1;
+
+        if self.faults.read().unwrap().fail_download == Some(download_number) {
+            return Err(CloudError::BackendError {
+                message: format!("Внедренный сбой скачивания №{}", download_number)
+            });
+        }
+
+        Ok(download_number)
+    }
+
+    fn file_name(file_path: &Path) -> Result<String, CloudError> {
+        file_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or(CloudError::IOError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Путь {} не содержит имени файла", file_path.display())
+            )))
+    }
+
+    fn wait_latency(&self) {
+        if let Some(latency) = self.faults.read().unwrap().latency {
+            thread::sleep(latency);
+        }
+    }
+}
+
+impl CloudBackend for InMemoryBackend {
+    fn create(_input: impl Read, _output: impl Write) -> Self {
+        Self::new()
+    }
+
+    fn load(&self) -> Result<(), CloudError> {
+        self.wait_latency();
+        Ok(())
+    }
+
+    fn upload_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        self.wait_latency();
+
+        let upload_number = self.upload_count.fetch_add(1, Ordering::SeqCst) + 1;
+        let faults = self.faults.read().unwrap().clone();
+
+        if faults.fail_upload == Some(upload_number) {
+            return Err(CloudError::BackendError {
+                message: format!("Внедренный сбой загрузки №{}", upload_number)
+            });
+        }
+
+        let file_name = Self::file_name(file_path)?;
+        let bytes = fs::read(file_path)?;
+
+        if faults.drop_upload != Some(upload_number) {
+            self.files.write().unwrap().insert(file_name, bytes);
+        }
+
+        Ok(())
+    }
+
+    fn download_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        self.wait_latency();
+
+        let download_number = self.start_download()?;
+        let file_name = Self::file_name(file_path)?;
+
+        let mut bytes = self.files
+            .read()
+            .unwrap()
+            .get(&file_name)
+            .cloned()
+            .ok_or(CloudError::FileNotFound { file_name })?;
+
+        if self.faults.read().unwrap().corrupt_download == Some(download_number) {
+            if let Some(last_byte) = bytes.last_mut() {
+                *last_byte ^= 0xFF;
+            }
+        }
+
+        fs::write(file_path, &bytes)?;
+        self.downloaded_bytes.fetch_add(bytes.len() as u64, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    fn download_file_range(&self, file_path: &Path, offset: u64, len: u64) -> Result<Vec<u8>, CloudError> {
+        self.wait_latency();
+
+        self.start_download()?;
+        let file_name = Self::file_name(file_path)?;
+
+        let files = self.files.read().unwrap();
+        let bytes = files
+            .get(&file_name)
+            .ok_or(CloudError::FileNotFound { file_name: file_name.clone() })?;
+
+        let start = (offset as usize).min(bytes.len());
+        let end = start.saturating_add(len as usize).min(bytes.len());
+
+        self.downloaded_bytes.fetch_add((end - start) as u64, Ordering::SeqCst);
+
+        Ok(bytes[start..end].to_vec())
+    }
+
+    fn remove_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        self.wait_latency();
+
+        let file_name = Self::file_name(file_path)?;
+
+        self.files
+            .write()
+            .unwrap()
+            .remove(&file_name)
+            .map(drop)
+            .ok_or(CloudError::FileNotFound { file_name })
+    }
+
+    fn check_file(&self, file_name: &str) -> bool {
+        self.files.read().unwrap().contains_key(file_name)
+    }
+
+    fn close(self) -> Result<(), CloudError> {
+        Ok(())
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        *self.capabilities.read().unwrap()
+    }
+
+    fn list_files(&self) -> Result<Vec<String>, CloudError> {
+        Ok(self.file_names())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::cloud::Cloud;
+    use crate::file::Options as SeparationOptions;
+    use crate::vfs::error::VFSError;
+    use crate::test_utils::{block_on, cloud_options, temp_dir, test_data};
+
+    const DATA_LEN: usize = 10_000;
+    const PART_SIZE: usize = 2_048;
+
+    /// Облако в отдельной временной папке и загружаемый файл в ней
+    fn temp_cloud(name: &str, backend: InMemoryBackend, parity_parts: Option<u8>) -> (Cloud<InMemoryBackend>, PathBuf, Vec<u8>) {
+        let dir = temp_dir(name);
+        let data = test_data(DATA_LEN);
+
+        fs::write(dir.join("data.bin"), &data).unwrap();
+
+        let cloud = Cloud::with_backend(backend, cloud_options(&dir, SeparationOptions {
+            part_size: Some(PART_SIZE),
+            parity_parts,
+            ..Default::default()
+        }));
+
+        (cloud, dir, data)
+    }
+
+    #[test]
+    fn failed_upload_is_not_added_and_cancel_removes_uploaded_parts() {
+        let faults = FaultOptions { fail_upload: Some(2), ..Default::default() };
+        let (cloud, dir, _) = temp_cloud("fail_upload", InMemoryBackend::with_faults(faults), None);
+
+        block_on(async {
+            let res = cloud.async_upload_file(&dir.join("data.bin"), Path::new("fs:")).await;
+            assert!(matches!(res, Err(CloudError::BackendError { .. })));
+            assert!(cloud.get_file(Path::new("fs:/data.bin")).is_err());
+
+            // Загруженные части остаются вместе с журналом, чтобы загрузку можно было продолжить
+            let pending = cloud.pending_uploads().unwrap();
+            assert_eq!(pending.len(), 1);
+            assert!(!cloud.backend().file_names().contains(&pending[0].metafile.file_name));
+
+            cloud.cancel_upload(&pending[0].upload_id).await.unwrap();
+
+            assert!(cloud.pending_uploads().unwrap().is_empty());
+            assert!(cloud.backend().file_names().is_empty());
+            assert!(cloud.get_file(Path::new("fs:/data.bin")).is_err());
+        });
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn dropped_upload_is_detected_and_resumed() {
+        let faults = FaultOptions { drop_upload: Some(2), ..Default::default() };
+        let (cloud, dir, data) = temp_cloud("drop_upload", InMemoryBackend::with_faults(faults), None);
+
+        block_on(async {
+            let res = cloud.async_upload_file(&dir.join("data.bin"), Path::new("fs:")).await;
+            assert!(matches!(res, Err(CloudError::UploadNotStored { .. })));
+            assert!(cloud.get_file(Path::new("fs:/data.bin")).is_err());
+
+            let pending = cloud.pending_uploads().unwrap();
+            assert_eq!(pending.len(), 1);
+
+            // Потерянная хранилищем часть загружается заново
+            let results = cloud.resume_uploads().await.unwrap();
+            assert_eq!(results.len(), 1);
+            assert!(results[0].1.is_ok());
+            assert!(cloud.pending_uploads().unwrap().is_empty());
+
+            let v_file = cloud.get_file(Path::new("fs:/data.bin")).unwrap();
+            let mut stored_names = cloud.backend().file_names();
+            stored_names.sort();
+
+            let mut expected_names = [v_file.chunks.as_slice(), std::slice::from_ref(&v_file.build_metafile)].concat();
+            expected_names.sort();
+            expected_names.dedup();
+
+            assert_eq!(stored_names, expected_names);
+
+            fs::remove_dir_all(dir.join("work")).unwrap();
+
+            let output_path = cloud.async_download_file(Path::new("fs:/data.bin")).await.unwrap();
+            assert_eq!(fs::read(output_path).unwrap(), data);
+        });
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_download_is_caught_by_hash() {
+        for parity_parts in [None, Some(1)] {
+            let (cloud, dir, data) = temp_cloud("corrupt_download", InMemoryBackend::new(), parity_parts);
+
+            block_on(async {
+                cloud.async_upload_file(&dir.join("data.bin"), Path::new("fs:")).await.unwrap();
+
+                // Без рабочей папки первым скачивается сборочный файл, затем части
+                fs::remove_dir_all(dir.join("work")).unwrap();
+
+                cloud.backend().set_faults(FaultOptions {
+                    corrupt_download: Some(cloud.backend().download_count() + 2),
+                    ..Default::default()
+                });
+
+                let res = cloud.async_download_file(Path::new("fs:/data.bin")).await;
+                let output_path = dir.join("work").join("data.bin");
+
+                match parity_parts {
+                    None => {
+                        assert!(matches!(res, Err(CloudError::PartHashMismatch { .. })), "{:?}", res);
+                        assert!(!output_path.exists());
+                    },
+                    Some(_) => {
+                        assert_eq!(res.unwrap(), output_path);
+                        assert_eq!(fs::read(output_path).unwrap(), data);
+                    },
+                }
+            });
+
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_file_name_round_trip_and_collision() {
+        let faults = FaultOptions { fail_upload: Some(2), ..Default::default() };
+        let (cloud, dir, data) = temp_cloud("non_utf8", InMemoryBackend::with_faults(faults), None);
+
+        let first_name = crate::file::os_string_from_bytes(b"data\xFF.bin".to_vec());
+        let second_name = crate::file::os_string_from_bytes(b"data\xFE.bin".to_vec());
+        let upload_dir = dir.join("upload");
+        fs::create_dir_all(&upload_dir).unwrap();
+
+        for file_name in [&first_name, &second_name] {
+            fs::write(upload_dir.join(file_name), &data).unwrap();
+        }
+
+        block_on(async {
+            // Журнал хранит путь не в UTF-8 байт в байт, загрузка продолжается по нему
+            assert!(cloud.async_upload_file(&upload_dir.join(&first_name), Path::new("fs:")).await.is_err());
+            assert_eq!(cloud.pending_uploads().unwrap()[0].file_path, upload_dir.join(&first_name));
+
+            cloud.backend().set_faults(FaultOptions::default());
+            assert!(cloud.resume_uploads().await.unwrap().iter().all(|(_, res)| res.is_ok()));
+
+            // Имя с теми же замененными байтами отклоняется до загрузки частей
+            let upload_count = cloud.backend().upload_count();
+            let res = cloud.async_upload_file(&upload_dir.join(&second_name), Path::new("fs:")).await;
+
+            assert!(matches!(res, Err(CloudError::VFSError(VFSError::FileNameCollision { .. }))), "{:?}", res);
+            assert_eq!(cloud.backend().upload_count(), upload_count);
+            assert!(cloud.get_file(&Path::new("fs:").join(&second_name)).is_err());
+
+            fs::remove_dir_all(dir.join("work")).unwrap();
+
+            let output_path = cloud.async_download_file(&Path::new("fs:").join(&first_name)).await.unwrap();
+            assert_eq!(output_path, dir.join("work").join(&first_name));
+            assert_eq!(fs::read(output_path).unwrap(), data);
+        });
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
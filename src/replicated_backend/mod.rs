@@ -0,0 +1,539 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cloud::error::CloudError;
+use crate::{BackendCapabilities, CloudBackend};
+use crate::local_backend::LocalDirBackend;
+use crate::s3_backend::{S3Backend, S3Options};
+use crate::telegram_backend::TelegramBackend;
+use crate::webdav_backend::{WebDavBackend, WebDavOptions};
+
+/// Объектно-безопасная часть *CloudBackend*, позволяющая хранить реплики разных типов
+trait Replica: Debug + Send + Sync {
+    fn load(&self) -> Result<(), CloudError>;
+    fn upload_file(&self, file_path: &Path) -> Result<(), CloudError>;
+    fn download_file(&self, file_path: &Path) -> Result<(), CloudError>;
+    fn download_file_range(&self, file_path: &Path, offset: u64, len: u64) -> Result<Vec<u8>, CloudError>;
+    fn remove_file(&self, file_path: &Path) -> Result<(), CloudError>;
+    fn check_file(&self, file_name: &str) -> bool;
+    fn capabilities(&self) -> BackendCapabilities;
+    fn list_files(&self) -> Result<Vec<String>, CloudError>;
+    fn close_boxed(self: Box<Self>) -> Result<(), CloudError>;
+}
+
+impl<T: CloudBackend + Debug + Send + Sync> Replica for T {
+    fn load(&self) -> Result<(), CloudError> {
+        CloudBackend::load(self)
+    }
+
+    fn upload_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        CloudBackend::upload_file(self, file_path)
+    }
+
+    fn download_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        CloudBackend::download_file(self, file_path)
+    }
+
+    fn download_file_range(&self, file_path: &Path, offset: u64, len: u64) -> Result<Vec<u8>, CloudError> {
+        CloudBackend::download_file_range(self, file_path, offset, len)
+    }
+
+    fn remove_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        CloudBackend::remove_file(self, file_path)
+    }
+
+    fn check_file(&self, file_name: &str) -> bool {
+        CloudBackend::check_file(self, file_name)
+    }
+
+    fn capabilities(&self) -> BackendCapabilities {
+        CloudBackend::capabilities(self)
+    }
+
+    fn list_files(&self) -> Result<Vec<String>, CloudError> {
+        CloudBackend::list_files(self)
+    }
+
+    fn close_boxed(self: Box<Self>) -> Result<(), CloudError> {
+        CloudBackend::close(*self)
+    }
+}
+
+#[derive(Debug)]
+struct ReplicaEntry {
+    name: String,
+    backend: Box<dyn Replica>,
+    /// Последняя операция с репликой завершилась успешно
+    healthy: AtomicBool,
+}
+
+/// Настройки реплик в файле *replicas.json*
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ReplicasConfig {
+    min_replicas: Option<usize>,
+    replicas: Vec<ReplicaConfig>,
+}
+
+/// Реплика в *replicas.json*. Под *id* реплика записывается в VFS,
+/// поэтому он не должен меняться, пока на реплике хранятся файлы
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicaConfig {
+    pub id: String,
+    #[serde(flatten)]
+    pub storage: ReplicaStorage,
+}
+
+/// Тип хранилища реплики (поле *type*) вместе с его настройками
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ReplicaStorage {
+    /// Telegram настраивается при авторизации в TDLib
+    Telegram,
+    Local {
+        storage_dir: PathBuf,
+    },
+    S3(S3Options),
+    Webdav(WebDavOptions),
+}
+
+/// Хранилище, зеркалирующее каждый файл на несколько хранилищ.
+/// Файл читается с первой исправной реплики, на которой он есть
+#[derive(Debug)]
+pub struct ReplicatedBackend {
+    replicas: Vec<ReplicaEntry>,
+    /// Минимальное кол-во реплик, на которые должен попасть файл при загрузке
+    min_replicas: usize,
+    /// Индексы реплик, хранящих файл
+    placement: RwLock<HashMap<String, Vec<usize>>>,
+    /// Ошибка в *replicas.json*, возвращается из *load*
+    config_error: Option<String>,
+}
+
+impl ReplicatedBackend {
+
+    pub fn new(min_replicas: usize) -> Self {
+        Self {
+            replicas: vec![],
+            min_replicas: min_replicas.max(1),
+            placement: RwLock::new(HashMap::new()),
+            config_error: None,
+        }
+    }
+
+    /// Создает хранилище по настройкам в формате *replicas.json*
+    pub fn from_config(config_json: &str) -> Result<Self, CloudError> {
+        let config = serde_json::from_str::<ReplicasConfig>(config_json)
+            .map_err(|e| CloudError::BackendError {
+                message: format!("replicas.json содержит неверные настройки: {}", e)
+            })?;
+
+        let mut backend = Self::new(config.min_replicas.unwrap_or(1));
+
+        for replica in config.replicas {
+            if backend.replicas.iter().any(|entry| entry.name == replica.id) {
+                return Err(CloudError::BackendError {
+                    message: format!("replicas.json содержит повторяющийся id реплики {}", replica.id)
+                });
+            }
+
+            match replica.storage {
+                ReplicaStorage::Telegram => backend.add_replica(replica.id, TelegramBackend::create(io::stdin(), io::stdout())),
+                ReplicaStorage::Local { storage_dir } => backend.add_replica(replica.id, LocalDirBackend::new(storage_dir)),
+                ReplicaStorage::S3(options) => backend.add_replica(replica.id, S3Backend::new(options)),
+                ReplicaStorage::Webdav(options) => backend.add_replica(replica.id, WebDavBackend::new(options)),
+            }
+        }
+
+        Ok(backend)
+    }
+
+    /// Добавляет реплику с именем *name*, под которым она записывается в VFS
+    pub fn add_replica(&mut self, name: impl Into<String>, backend: impl CloudBackend + Debug + Send + Sync + 'static) {
+        self.replicas.push(ReplicaEntry {
+            name: name.into(),
+            backend: Box::new(backend),
+            healthy: AtomicBool::new(true),
+        });
+    }
+
+    fn file_name(file_path: &Path) -> Result<String, CloudError> {
+        file_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or(CloudError::IOError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Путь {} не содержит имени файла", file_path.display())
+            )))
+    }
+
+    /// Порядок опроса реплик при чтении: сначала исправные реплики, хранящие файл
+    fn read_order(&self, file_name: &str) -> Vec<usize> {
+        let mut order = self.placement
+            .read()
+            .unwrap()
+            .get(file_name)
+            .cloned()
+            .unwrap_or_else(|| (0..self.replicas.len()).collect());
+
+        order.sort_by_key(|&ind| !self.replicas[ind].healthy.load(Ordering::Relaxed));
+        order
+    }
+
+    /// Выполняет операцию над репликой и запоминает ее исправность.
+    /// Реплика, ответившая, что файла нет, исправна
+    fn track<R>(&self, replica_ind: usize, res: Result<R, CloudError>) -> Result<R, CloudError> {
+        let healthy = matches!(res, Ok(_) | Err(CloudError::FileNotFound { .. }));
+
+        self.replicas[replica_ind].healthy.store(healthy, Ordering::Relaxed);
+        res
+    }
+
+    /// Параллельная загрузка файла на реплики, возвращает индексы реплик, принявших файл
+    fn upload_to(&self, replica_inds: &[usize], file_path: &Path) -> (Vec<usize>, Vec<CloudError>) {
+        let results = thread::scope(|scope| {
+            replica_inds
+                .iter()
+                .map(|&ind| scope.spawn(move || {
+                    (ind, self.track(ind, self.replicas[ind].backend.upload_file(file_path)))
+                }))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .map(|handle| handle.join().expect("Поток загрузки на реплику завершился паникой"))
+                .collect::<Vec<_>>()
+        });
+
+        let mut uploaded = vec![];
+        let mut errors = vec![];
+
+        for (ind, res) in results {
+            match res {
+                Ok(()) => uploaded.push(ind),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        (uploaded, errors)
+    }
+}
+
+impl CloudBackend for ReplicatedBackend {
+    fn create(_input: impl Read, _output: impl Write) -> Self {
+
+        let config_json = fs::read_to_string("replicas.json")
+            .expect("Не найден файл настроек replicas.json");
+
+        Self::from_config(&config_json).unwrap_or_else(|e| Self {
+            config_error: Some(format!("{:?}", e)),
+            ..Self::new(1)
+        })
+    }
+
+    fn load(&self) -> Result<(), CloudError> {
+        if let Some(message) = &self.config_error {
+            return Err(CloudError::BackendError { message: message.clone() });
+        }
+
+        let mut last_error = None;
+
+        for ind in 0..self.replicas.len() {
+            if let Err(e) = self.track(ind, self.replicas[ind].backend.load()) {
+                println!("Реплика {} недоступна: {:?}", self.replicas[ind].name, e);
+                last_error = Some(e);
+            }
+        }
+
+        // Облако работоспособно, пока доступна хотя бы одна реплика
+        match last_error {
+            Some(e) if self.replicas.iter().all(|replica| !replica.healthy.load(Ordering::Relaxed)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn upload_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let file_name = Self::file_name(file_path)?;
+        let all_replicas = (0..self.replicas.len()).collect::<Vec<_>>();
+
+        let (uploaded, mut errors) = self.upload_to(&all_replicas, file_path);
+
+        if uploaded.len() < self.min_replicas.min(self.replicas.len()) || uploaded.is_empty() {
+
+            // Файл, не набравший нужного кол-ва реплик, не должен оставаться в хранилищах
+            for ind in uploaded {
+                let _ = self.replicas[ind].backend.remove_file(file_path);
+            }
+
+            return Err(errors.pop().unwrap_or(CloudError::BackendError {
+                message: "Нет реплик для загрузки файла".to_string()
+            }));
+        }
+
+        self.placement.write().unwrap().insert(file_name, uploaded);
+
+        Ok(())
+    }
+
+    fn download_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let file_name = Self::file_name(file_path)?;
+        let mut last_error = CloudError::FileNotFound { file_name: file_name.clone() };
+
+        for ind in self.read_order(&file_name) {
+            match self.track(ind, self.replicas[ind].backend.download_file(file_path)) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    println!("Реплика {} не отдала {}: {:?}", self.replicas[ind].name, file_name, e);
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    fn download_file_range(&self, file_path: &Path, offset: u64, len: u64) -> Result<Vec<u8>, CloudError> {
+        let file_name = Self::file_name(file_path)?;
+        let mut last_error = CloudError::FileNotFound { file_name: file_name.clone() };
+
+        for ind in self.read_order(&file_name) {
+            match self.track(ind, self.replicas[ind].backend.download_file_range(file_path, offset, len)) {
+                Ok(bytes) => return Ok(bytes),
+                Err(e) => {
+                    println!("Реплика {} не отдала {}: {:?}", self.replicas[ind].name, file_name, e);
+                    last_error = e;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+
+    fn remove_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let file_name = Self::file_name(file_path)?;
+        let mut res = Ok(());
+
+        for ind in self.read_order(&file_name) {
+            if !self.replicas[ind].backend.check_file(&file_name) {
+                continue;
+            }
+
+            if let Err(e) = self.track(ind, self.replicas[ind].backend.remove_file(file_path)) {
+                res = Err(e);
+            }
+        }
+
+        self.placement.write().unwrap().remove(&file_name);
+
+        res
+    }
+
+    fn check_file(&self, file_name: &str) -> bool {
+        self.read_order(file_name)
+            .into_iter()
+            .any(|ind| self.replicas[ind].backend.check_file(file_name))
+    }
+
+    fn close(self) -> Result<(), CloudError> {
+        self.replicas
+            .into_iter()
+            .map(|replica| replica.backend.close_boxed())
+            .fold(Ok(()), |res, close_res| res.and(close_res))
+    }
+
+    /// Файл должен поместиться на каждую реплику, поэтому берутся самые строгие ограничения
+    fn capabilities(&self) -> BackendCapabilities {
+        self.replicas
+            .iter()
+            .map(|replica| replica.backend.capabilities())
+            .reduce(|all, replica| BackendCapabilities {
+                max_object_size: all.max_object_size.min(replica.max_object_size),
+                preferred_part_size: all.preferred_part_size.min(replica.preferred_part_size),
+                max_concurrent_transfers: all.max_concurrent_transfers.min(replica.max_concurrent_transfers),
+                ranged_reads: all.ranged_reads && replica.ranged_reads,
+            })
+            .unwrap_or_default()
+    }
+
+    /// Индексы реплик по их *id*, реплики, которых больше нет в настройках, пропускаются
+    fn restore_replicas(&self, file_name: &str, replicas: &[String]) {
+        let replica_inds = replicas
+            .iter()
+            .filter_map(|name| self.replicas.iter().position(|replica| &replica.name == name))
+            .collect::<Vec<_>>();
+
+        if !replica_inds.is_empty() {
+            self.placement.write().unwrap().insert(file_name.to_string(), replica_inds);
+        }
+    }
+
+    fn replicas_of(&self, file_name: &str) -> Vec<String> {
+        self.placement
+            .read()
+            .unwrap()
+            .get(file_name)
+            .map(|inds| inds.iter().map(|&ind| self.replicas[ind].name.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Файлы со всех реплик, хотя бы одна реплика должна уметь перечислять файлы
+    fn list_files(&self) -> Result<Vec<String>, CloudError> {
+        let mut file_names = vec![];
+        let mut last_error = None;
+
+        // Хранилища без перечисления файлов не считаются неисправными, поэтому *track* не вызывается
+        for replica in &self.replicas {
+            match replica.backend.list_files() {
+                Ok(replica_files) => file_names.extend(replica_files),
+                Err(e) => {
+                    println!("Реплика {} не перечислила файлы: {:?}", replica.name, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+
+        if file_names.is_empty() {
+            if let Some(e) = last_error {
+                return Err(e);
+            }
+        }
+
+        file_names.sort();
+        file_names.dedup();
+
+        Ok(file_names)
+    }
+
+    /// Копирует файл с исправной реплики на реплики, где его нет
+    fn repair_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let file_name = Self::file_name(file_path)?;
+
+        let (holders, lagging): (Vec<usize>, Vec<usize>) = (0..self.replicas.len())
+            .partition(|&ind| self.replicas[ind].backend.check_file(&file_name));
+
+        if holders.is_empty() {
+            return Err(CloudError::FileNotFound { file_name });
+        }
+
+        if !lagging.is_empty() {
+            CloudBackend::download_file(self, file_path)?;
+
+            let (repaired, errors) = self.upload_to(&lagging, file_path);
+
+            for e in errors {
+                println!("Не удалось восстановить {} на реплике: {:?}", file_name, e);
+            }
+
+            let mut placement = holders;
+            placement.extend(repaired);
+            placement.sort();
+
+            self.placement.write().unwrap().insert(file_name, placement);
+        } else {
+            self.placement.write().unwrap().insert(file_name, holders);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cloud::Cloud;
+    use crate::memory_backend::{FaultOptions, InMemoryBackend};
+    use crate::test_utils::{block_on, cloud_options, temp_dir};
+
+    fn replica_names(backend: &ReplicatedBackend) -> Vec<&str> {
+        backend.replicas.iter().map(|replica| replica.name.as_str()).collect()
+    }
+
+    #[test]
+    fn config_gives_each_replica_an_id_and_settings() {
+        let backend = ReplicatedBackend::from_config(r#"{
+            "min_replicas": 2,
+            "replicas": [
+                { "id": "nas", "type": "local", "storage_dir": "/mnt/nas/recloud" },
+                { "id": "usb", "type": "local", "storage_dir": "/mnt/usb/recloud" },
+                {
+                    "id": "minio", "type": "s3", "endpoint": "http://127.0.0.1:9000", "region": "us-east-1",
+                    "bucket": "recloud", "access_key": "access", "secret_key": "secret"
+                },
+                { "id": "dav", "type": "webdav", "url": "http://127.0.0.1:8080/recloud/" }
+            ]
+        }"#).unwrap();
+
+        assert_eq!(replica_names(&backend), vec!["nas", "usb", "minio", "dav"]);
+        assert_eq!(backend.min_replicas, 2);
+
+        let unknown_type = ReplicatedBackend::from_config(r#"{ "replicas": [{ "id": "ftp", "type": "ftp" }] }"#);
+        assert!(matches!(unknown_type, Err(CloudError::BackendError { .. })));
+
+        let duplicate_id = ReplicatedBackend::from_config(r#"{ "replicas": [
+            { "id": "nas", "type": "local", "storage_dir": "/mnt/nas/recloud" },
+            { "id": "nas", "type": "local", "storage_dir": "/mnt/usb/recloud" }
+        ] }"#);
+        assert!(matches!(duplicate_id, Err(CloudError::BackendError { .. })));
+    }
+
+    #[test]
+    fn missing_file_does_not_mark_replica_unhealthy() {
+        let dir = temp_dir("replicated");
+        let file_path = dir.join("a.part");
+        fs::write(&file_path, b"part").unwrap();
+
+        let mut backend = ReplicatedBackend::new(1);
+        backend.add_replica("dropping", InMemoryBackend::with_faults(FaultOptions { drop_upload: Some(1), ..Default::default() }));
+        backend.add_replica("failing", InMemoryBackend::with_faults(FaultOptions { fail_upload: Some(1), ..Default::default() }));
+        backend.add_replica("stable", InMemoryBackend::new());
+
+        CloudBackend::upload_file(&backend, &file_path).unwrap();
+        assert_eq!(backend.replicas_of("a.part"), vec!["dropping", "stable"]);
+        assert!(!backend.replicas[1].healthy.load(Ordering::Relaxed));
+
+        fs::remove_file(&file_path).unwrap();
+        CloudBackend::download_file(&backend, &file_path).unwrap();
+
+        assert_eq!(fs::read(&file_path).unwrap(), b"part");
+        assert!(backend.replicas[0].healthy.load(Ordering::Relaxed));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn cloud_restores_placement_from_vfs() {
+        let dir = temp_dir("replicated_cloud");
+        fs::write(dir.join("data.bin"), vec![7; 5_000]).unwrap();
+
+        let options = cloud_options(&dir, Default::default());
+
+        let replicated_backend = || {
+            let mut backend = ReplicatedBackend::new(2);
+            backend.add_replica("nas", LocalDirBackend::new(dir.join("nas")));
+            backend.add_replica("usb", LocalDirBackend::new(dir.join("usb")));
+            backend
+        };
+
+        let cloud = Cloud::with_backend(replicated_backend(), options.clone());
+        CloudBackend::load(cloud.backend()).unwrap();
+        block_on(cloud.async_upload_file(&dir.join("data.bin"), Path::new("fs:"))).unwrap();
+
+        let v_file = cloud.get_file(Path::new("fs:/data.bin")).unwrap();
+        assert_eq!(v_file.replicas.get(&v_file.build_metafile), Some(&vec!["nas".to_string(), "usb".to_string()]));
+
+        drop(cloud);
+
+        let cloud = Cloud::with_backend(replicated_backend(), options);
+
+        for (file_name, replicas) in &v_file.replicas {
+            assert_eq!(&cloud.backend().replicas_of(file_name), replicas);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,755 @@
+mod signature;
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cloud::error::CloudError;
+use crate::{BackendCapabilities, CloudBackend, DEFAULT_PART_SIZE};
+
+use signature::{RequestSignature, UNSIGNED_PAYLOAD, sha256_hex, uri_encode};
+
+/// Максимальный размер объекта, загружаемого одним PUT запросом (5 GiB)
+const MAX_SINGLE_PUT_SIZE: u64 = 5_368_709_120;
+
+/// Максимальный размер объекта (5 TiB)
+const MAX_OBJECT_SIZE: u64 = 5_497_558_138_880;
+
+/// Размер части multipart загрузки по умолчанию (64 MiB)
+const DEFAULT_MULTIPART_PART_SIZE: u64 = 67_108_864;
+
+/// Минимальный размер части multipart загрузки, кроме последней (5 MiB)
+const MIN_MULTIPART_PART_SIZE: u64 = 5_242_880;
+
+/// Максимальное кол-во частей multipart загрузки
+const MAX_MULTIPART_PARTS: u64 = 10_000;
+
+/// Настройки подключения к S3 совместимому хранилищу
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct S3Options {
+    /// Адрес сервера вместе со схемой, например *http://127.0.0.1:9000*
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    /// Префикс ключей объектов внутри бакета
+    pub prefix: Option<String>,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Указывать бакет в пути запроса, а не в имени хоста (необходимо для MinIO и Ceph)
+    pub path_style: Option<bool>,
+    /// Размер файла, начиная с которого используется multipart загрузка
+    pub multipart_threshold: Option<u64>,
+    /// Размер одной части multipart загрузки
+    pub multipart_part_size: Option<u64>,
+}
+
+/// Хранилище частей и сборочных файлов в S3 совместимом объектном хранилище.
+/// Каждый файл хранится отдельным объектом с ключом *{prefix}{file_name}*
+#[derive(Debug)]
+pub struct S3Backend {
+    agent: ureq::Agent,
+    options: S3Options,
+}
+
+impl S3Backend {
+
+    pub fn new(options: S3Options) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new().build(),
+            options,
+        }
+    }
+
+    /// Ключ объекта для файла
+    fn object_key(&self, file_name: &str) -> String {
+        let prefix = self.options.prefix.as_deref().unwrap_or("");
+
+        if prefix.is_empty() || prefix.ends_with('/') {
+            format!("{}{}", prefix, file_name)
+        } else {
+            format!("{}/{}", prefix, file_name)
+        }
+    }
+
+    fn file_name(file_path: &Path) -> Result<String, CloudError> {
+        file_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or(CloudError::IOError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Путь {} не содержит имени файла", file_path.display())
+            )))
+    }
+
+    /// Создание подписанного запроса к объекту или, при *key* = *None*, к бакету
+    fn request(&self, method: &str, key: Option<&str>, query: &[(&str, String)], payload_hash: &str) -> ureq::Request {
+
+        let (scheme, endpoint_host) = self.options.endpoint
+            .split_once("://")
+            .unwrap_or(("https", &self.options.endpoint));
+        let endpoint_host = endpoint_host.trim_end_matches('/');
+
+        let encoded_key = key.map(|key| uri_encode(key, false)).unwrap_or_default();
+
+        let (host, canonical_uri) = if self.options.path_style.unwrap_or(true) {
+            (
+                endpoint_host.to_string(),
+                match key {
+                    Some(_) => format!("/{}/{}", self.options.bucket, encoded_key),
+                    None => format!("/{}", self.options.bucket),
+                }
+            )
+        } else {
+            (
+                format!("{}.{}", self.options.bucket, endpoint_host),
+                format!("/{}", encoded_key)
+            )
+        };
+
+        let signed_headers = RequestSignature {
+            access_key: &self.options.access_key,
+            secret_key: &self.options.secret_key,
+            region: &self.options.region,
+            service: "s3",
+            method,
+            host: &host,
+            canonical_uri: &canonical_uri,
+            query,
+            payload_hash,
+        }.sign(SystemTime::now());
+
+        let query_string = query
+            .iter()
+            .map(|(key, value)| format!("{}={}", uri_encode(key, true), uri_encode(value, true)))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let url = if query_string.is_empty() {
+            format!("{}://{}{}", scheme, host, canonical_uri)
+        } else {
+            format!("{}://{}{}?{}", scheme, host, canonical_uri, query_string)
+        };
+
+        self.agent
+            .request(method, &url)
+            .set("x-amz-date", &signed_headers.amz_date)
+            .set("x-amz-content-sha256", payload_hash)
+            .set("Authorization", &signed_headers.authorization)
+    }
+
+    fn map_error(err: ureq::Error, file_name: &str) -> CloudError {
+        match err {
+            ureq::Error::Status(404, _) => CloudError::FileNotFound {
+                file_name: file_name.to_string()
+            },
+            ureq::Error::Status(code, response) => CloudError::BackendError {
+                message: format!(
+                    "S3 вернул код {} для {}: {}",
+                    code,
+                    file_name,
+                    response.into_string().unwrap_or_default()
+                )
+            },
+            ureq::Error::Transport(transport) => CloudError::BackendError {
+                message: format!("Ошибка соединения с S3 для {}: {}", file_name, transport)
+            },
+        }
+    }
+
+    /// Загрузка объекта по частям, для файлов больше лимита одного PUT запроса
+    fn multipart_upload(&self, file_name: &str, file_path: &Path, file_len: u64) -> Result<(), CloudError> {
+        let key = self.object_key(file_name);
+
+        let response = self
+            .request("POST", Some(&key), &[("uploads", String::new())], &sha256_hex(b""))
+            .call()
+            .map_err(|e| Self::map_error(e, file_name))?;
+
+        let response_body = response.into_string()?;
+        let upload_id = xml_value(&response_body, "UploadId")
+            .ok_or(CloudError::BackendError {
+                message: format!("S3 не прислал UploadId: {}", response_body)
+            })?;
+
+        let upload_res = self.upload_parts(file_name, &key, &upload_id, file_path, file_len);
+
+        if upload_res.is_err() {
+            // Отмена загрузки, чтобы S3 не хранил уже загруженные части
+            let _ = self
+                .request("DELETE", Some(&key), &[("uploadId", upload_id)], UNSIGNED_PAYLOAD)
+                .call();
+        }
+
+        upload_res
+    }
+
+    fn upload_parts(&self, file_name: &str, key: &str, upload_id: &str, file_path: &Path, file_len: u64) -> Result<(), CloudError> {
+
+        let part_size = self.options.multipart_part_size
+            .unwrap_or(DEFAULT_MULTIPART_PART_SIZE)
+            .max(MIN_MULTIPART_PART_SIZE)
+            .max(file_len.div_ceil(MAX_MULTIPART_PARTS));
+
+        let mut file = File::open(file_path)?;
+        let mut etags = vec![];
+        let mut offset = 0;
+
+        while offset < file_len {
+            let len = part_size.min(file_len - offset);
+            let part_number = etags.len() + 1;
+
+            let response = self
+                .request(
+                    "PUT",
+                    Some(key),
+                    &[("partNumber", part_number.to_string()), ("uploadId", upload_id.to_string())],
+                    UNSIGNED_PAYLOAD
+                )
+                .set("Content-Length", &len.to_string())
+                .send((&mut file).take(len))
+                .map_err(|e| Self::map_error(e, file_name))?;
+
+            let etag = response
+                .header("ETag")
+                .ok_or(CloudError::BackendError {
+                    message: format!("S3 не прислал ETag части {} для {}", part_number, file_name)
+                })?;
+
+            etags.push(etag.to_string());
+            offset += len;
+        }
+
+        let complete_body = format!(
+            "<CompleteMultipartUpload>{}</CompleteMultipartUpload>",
+            etags
+                .iter()
+                .enumerate()
+                .map(|(ind, etag)| format!(
+                    "<Part><PartNumber>{}</PartNumber><ETag>{}</ETag></Part>",
+                    ind + 1,
+                    etag
+                ))
+                .collect::<String>()
+        );
+
+        let response = self
+            .request("POST", Some(key), &[("uploadId", upload_id.to_string())], &sha256_hex(complete_body.as_bytes()))
+            .send_string(&complete_body)
+            .map_err(|e| Self::map_error(e, file_name))?;
+
+        // S3 может вернуть ошибку в теле ответа с кодом 200
+        let response_body = response.into_string()?;
+        if response_body.contains("<Error>") {
+            return Err(CloudError::BackendError {
+                message: format!("S3 не завершил загрузку {}: {}", file_name, response_body)
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl CloudBackend for S3Backend {
+    fn create(_input: impl Read, _output: impl Write) -> Self {
+
+        let options_json = fs::read_to_string("s3.json")
+            .expect("Не найден файл настроек s3.json");
+
+        let options = serde_json::from_str::<S3Options>(&options_json)
+            .expect("s3.json содержит невалидный json");
+
+        Self::new(options)
+    }
+
+    fn load(&self) -> Result<(), CloudError> {
+        self.request("HEAD", None, &[], &sha256_hex(b""))
+            .call()
+            .map(drop)
+            .map_err(|e| Self::map_error(e, &self.options.bucket))
+    }
+
+    fn upload_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let file_name = Self::file_name(file_path)?;
+        let file_len = fs::metadata(file_path)?.len();
+
+        if file_len > self.options.multipart_threshold.unwrap_or(MAX_SINGLE_PUT_SIZE) {
+            return self.multipart_upload(&file_name, file_path, file_len);
+        }
+
+        self.request("PUT", Some(&self.object_key(&file_name)), &[], UNSIGNED_PAYLOAD)
+            .set("Content-Length", &file_len.to_string())
+            .send(File::open(file_path)?)
+            .map(drop)
+            .map_err(|e| Self::map_error(e, &file_name))
+    }
+
+    fn download_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let file_name = Self::file_name(file_path)?;
+
+        let response = self
+            .request("GET", Some(&self.object_key(&file_name)), &[], &sha256_hex(b""))
+            .call()
+            .map_err(|e| Self::map_error(e, &file_name))?;
+
+        let mut file = File::create(file_path)?;
+        io::copy(&mut response.into_reader(), &mut file)?;
+        file.flush()?;
+
+        Ok(())
+    }
+
+    fn download_file_range(&self, file_path: &Path, offset: u64, len: u64) -> Result<Vec<u8>, CloudError> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+
+        let file_name = Self::file_name(file_path)?;
+
+        let response = self
+            .request("GET", Some(&self.object_key(&file_name)), &[], &sha256_hex(b""))
+            .set("Range", &format!("bytes={}-{}", offset, offset + len - 1))
+            .call();
+
+        let response = match response {
+            // 416 - диапазон начинается за концом файла
+            Err(ureq::Error::Status(416, _)) => return Ok(vec![]),
+            response => response.map_err(|e| Self::map_error(e, &file_name))?,
+        };
+
+        let mut bytes = vec![];
+        response.into_reader().take(len).read_to_end(&mut bytes)?;
+
+        Ok(bytes)
+    }
+
+    fn remove_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let file_name = Self::file_name(file_path)?;
+
+        self.request("DELETE", Some(&self.object_key(&file_name)), &[], &sha256_hex(b""))
+            .call()
+            .map(drop)
+            .map_err(|e| Self::map_error(e, &file_name))
+    }
+
+    fn check_file(&self, file_name: &str) -> bool {
+        self.request("HEAD", Some(&self.object_key(file_name)), &[], &sha256_hex(b""))
+            .call()
+            .is_ok()
+    }
+
+    fn close(self) -> Result<(), CloudError> {
+        Ok(())
+    }
+
+    /// Ключи с префиксом хранилища, запрашиваются страницами по 1000 ключей
+    fn list_files(&self) -> Result<Vec<String>, CloudError> {
+        let prefix = self.object_key("");
+        let mut file_names = vec![];
+        let mut continuation_token = None;
+
+        loop {
+            let mut query = vec![("list-type", "2".to_string()), ("prefix", prefix.clone())];
+
+            if let Some(token) = continuation_token.take() {
+                query.push(("continuation-token", token));
+            }
+
+            let response_body = self
+                .request("GET", None, &query, &sha256_hex(b""))
+                .call()
+                .map_err(|e| Self::map_error(e, &self.options.bucket))?
+                .into_string()?;
+
+            file_names.extend(
+                xml_values(&response_body, "Key")
+                    .into_iter()
+                    .filter_map(|key| key.strip_prefix(&prefix).map(str::to_string))
+                    .filter(|file_name| !file_name.is_empty() && !file_name.contains('/'))
+            );
+
+            match xml_value(&response_body, "IsTruncated").as_deref() {
+                Some("true") => continuation_token = xml_value(&response_body, "NextContinuationToken"),
+                _ => break,
+            }
+
+            if continuation_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(file_names)
+    }
+
+    /// Части до порога multipart загрузки отправляются одним запросом
+    fn capabilities(&self) -> BackendCapabilities {
+        BackendCapabilities {
+            max_object_size: MAX_OBJECT_SIZE,
+            preferred_part_size: self.options.multipart_threshold
+                .unwrap_or(MAX_SINGLE_PUT_SIZE)
+                .min(DEFAULT_PART_SIZE),
+            max_concurrent_transfers: 8,
+            ranged_reads: true,
+        }
+    }
+}
+
+/// Значение первого тега *tag* в XML ответе S3
+fn xml_value(xml: &str, tag: &str) -> Option<String> {
+    let open_tag = format!("<{}>", tag);
+    let close_tag = format!("</{}>", tag);
+
+    let start = xml.find(&open_tag)? + open_tag.len();
+    let end = start + xml[start..].find(&close_tag)?;
+
+    Some(xml_unescape(&xml[start..end]))
+}
+
+/// Значения всех тегов *tag* в XML ответе S3
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+    let open_tag = format!("<{}>", tag);
+    let close_tag = format!("</{}>", tag);
+
+    xml.split(&open_tag)
+        .skip(1)
+        .filter_map(|rest| rest.find(&close_tag).map(|end| xml_unescape(&rest[..end])))
+        .collect()
+}
+
+fn xml_unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::*;
+    use crate::test_utils::temp_dir;
+
+    const BUCKET: &str = "bucket";
+    const ACCESS_KEY: &str = "test-access";
+
+    /// Объекты заглушки S3 по ключу и части незавершенных multipart загрузок по *uploadId*
+    #[derive(Debug, Default)]
+    struct StubStorage {
+        objects: HashMap<String, Vec<u8>>,
+        uploads: HashMap<String, HashMap<usize, Vec<u8>>>,
+        next_upload_id: usize,
+    }
+
+    struct StubRequest {
+        method: String,
+        /// Ключ объекта, пустой для запроса к бакету
+        key: String,
+        query: HashMap<String, String>,
+        headers: HashMap<String, String>,
+        body: Vec<u8>,
+    }
+
+    struct StubResponse {
+        status: u16,
+        headers: Vec<(&'static str, String)>,
+        body: Vec<u8>,
+    }
+
+    impl StubResponse {
+        fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+            Self { status, headers: vec![], body: body.into() }
+        }
+    }
+
+    /// Заглушка S3 на случайном порту, отвечает на запросы по одному на соединение
+    fn start_stub() -> (String, Arc<Mutex<StubStorage>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let storage = Arc::new(Mutex::new(StubStorage::default()));
+
+        let stub_storage = storage.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { break };
+                serve_connection(stream, &stub_storage);
+            }
+        });
+
+        (endpoint, storage)
+    }
+
+    fn serve_connection(mut stream: TcpStream, storage: &Mutex<StubStorage>) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let Some(request) = read_request(&mut reader) else { return };
+
+        let response = match authorized(&request) {
+            true => handle_request(&mut storage.lock().unwrap(), request),
+            false => StubResponse::new(403, "<Error><Code>AccessDenied</Code></Error>"),
+        };
+
+        let mut head = format!("HTTP/1.1 {} Stub\r\nConnection: close\r\nContent-Length: {}\r\n", response.status, response.body.len());
+
+        for (name, value) in &response.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+
+        head.push_str("\r\n");
+
+        stream.write_all(head.as_bytes()).unwrap();
+        stream.write_all(&response.body).unwrap();
+    }
+
+    fn read_request(reader: &mut BufReader<TcpStream>) -> Option<StubRequest> {
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).ok()?;
+
+        let mut request_parts = request_line.split_whitespace();
+        let method = request_parts.next()?.to_string();
+        let target = request_parts.next()?;
+
+        let mut headers = HashMap::new();
+
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).ok()?;
+
+            match line.trim_end().split_once(':') {
+                Some((name, value)) => headers.insert(name.to_lowercase(), value.trim().to_string()),
+                None => break,
+            };
+        }
+
+        assert!(!headers.contains_key("transfer-encoding"), "Тело должно передаваться с Content-Length");
+
+        let body_len = headers
+            .get("content-length")
+            .map(|len| len.parse::<usize>().unwrap())
+            .unwrap_or(0);
+
+        let mut body = vec![0; body_len];
+        reader.read_exact(&mut body).ok()?;
+
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        let key = path
+            .strip_prefix(&format!("/{}", BUCKET))?
+            .trim_start_matches('/');
+
+        let query = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(name), percent_decode(value))
+            })
+            .collect();
+
+        Some(StubRequest { method, key: percent_decode(key), query, headers, body })
+    }
+
+    fn percent_decode(value: &str) -> String {
+        let bytes = value.as_bytes();
+        let mut decoded = vec![];
+        let mut ind = 0;
+
+        while ind < bytes.len() {
+            if bytes[ind] == b'%' {
+                decoded.push(u8::from_str_radix(&value[ind + 1..ind + 3], 16).unwrap());
+                ind += 3;
+            } else {
+                decoded.push(bytes[ind]);
+                ind += 1;
+            }
+        }
+
+        String::from_utf8(decoded).unwrap()
+    }
+
+    /// Подпись не проверяется, только наличие заголовков подписи
+    fn authorized(request: &StubRequest) -> bool {
+        request.headers
+            .get("authorization")
+            .is_some_and(|authorization| authorization.starts_with(&format!("AWS4-HMAC-SHA256 Credential={}/", ACCESS_KEY)))
+            && request.headers.contains_key("x-amz-date")
+            && request.headers.contains_key("x-amz-content-sha256")
+    }
+
+    fn handle_request(storage: &mut StubStorage, request: StubRequest) -> StubResponse {
+        let not_found = || StubResponse::new(404, "<Error><Code>NoSuchKey</Code></Error>");
+
+        match (request.method.as_str(), request.key.is_empty()) {
+            ("HEAD", true) => StubResponse::new(200, ""),
+            ("GET", true) => {
+                let prefix = request.query.get("prefix").cloned().unwrap_or_default();
+
+                let mut keys = storage.objects
+                    .keys()
+                    .filter(|key| key.starts_with(&prefix))
+                    .map(|key| format!("<Contents><Key>{}</Key></Contents>", key))
+                    .collect::<Vec<_>>();
+                keys.sort();
+
+                StubResponse::new(200, format!("<ListBucketResult><IsTruncated>false</IsTruncated>{}</ListBucketResult>", keys.concat()))
+            },
+            ("HEAD", false) => match storage.objects.contains_key(&request.key) {
+                true => StubResponse::new(200, ""),
+                false => StubResponse::new(404, ""),
+            },
+            ("GET", false) => {
+                let Some(object) = storage.objects.get(&request.key) else { return not_found() };
+
+                let Some(range) = request.headers.get("range") else { return StubResponse::new(200, object.clone()) };
+
+                let (start, end) = range
+                    .strip_prefix("bytes=")
+                    .and_then(|range| range.split_once('-'))
+                    .map(|(start, end)| (start.parse::<usize>().unwrap(), end.parse::<usize>().unwrap()))
+                    .unwrap();
+
+                match start < object.len() {
+                    true => StubResponse::new(206, &object[start..=end.min(object.len() - 1)]),
+                    false => StubResponse::new(416, ""),
+                }
+            },
+            ("PUT", false) => match (request.query.get("uploadId"), request.query.get("partNumber")) {
+                (Some(upload_id), Some(part_number)) => {
+                    let Some(parts) = storage.uploads.get_mut(upload_id) else { return not_found() };
+                    let part_number = part_number.parse::<usize>().unwrap();
+
+                    parts.insert(part_number, request.body);
+
+                    StubResponse {
+                        headers: vec![("ETag", format!("\"etag-{}\"", part_number))],
+                        ..StubResponse::new(200, "")
+                    }
+                },
+                _ => {
+                    storage.objects.insert(request.key, request.body);
+                    StubResponse::new(200, "")
+                },
+            },
+            ("POST", false) if request.query.contains_key("uploads") => {
+                storage.next_upload_id += 1;
+                let upload_id = format!("upload-{}", storage.next_upload_id);
+
+                storage.uploads.insert(upload_id.clone(), HashMap::new());
+
+                StubResponse::new(200, format!("<InitiateMultipartUploadResult><UploadId>{}</UploadId></InitiateMultipartUploadResult>", upload_id))
+            },
+            ("POST", false) => {
+                let Some(mut parts) = request.query.get("uploadId").and_then(|upload_id| storage.uploads.remove(upload_id)) else {
+                    return not_found()
+                };
+
+                let complete_body = String::from_utf8(request.body).unwrap();
+                let mut object = vec![];
+
+                for part_number in 1..=parts.len() {
+                    assert!(complete_body.contains(&format!("<PartNumber>{}</PartNumber><ETag>\"etag-{}\"</ETag>", part_number, part_number)));
+                    object.extend(parts.remove(&part_number).unwrap());
+                }
+
+                storage.objects.insert(request.key, object);
+
+                StubResponse::new(200, "<CompleteMultipartUploadResult></CompleteMultipartUploadResult>")
+            },
+            ("DELETE", false) => match request.query.get("uploadId") {
+                Some(upload_id) => {
+                    storage.uploads.remove(upload_id);
+                    StubResponse::new(204, "")
+                },
+                None => match storage.objects.remove(&request.key) {
+                    Some(_) => StubResponse::new(204, ""),
+                    None => not_found(),
+                },
+            },
+            _ => StubResponse::new(405, ""),
+        }
+    }
+
+    fn stub_backend(endpoint: String, multipart_threshold: Option<u64>) -> S3Backend {
+        S3Backend::new(S3Options {
+            endpoint,
+            region: "us-east-1".to_string(),
+            bucket: BUCKET.to_string(),
+            prefix: Some("recloud".to_string()),
+            access_key: ACCESS_KEY.to_string(),
+            secret_key: "test-secret".to_string(),
+            path_style: None,
+            multipart_threshold,
+            multipart_part_size: None,
+        })
+    }
+
+    #[test]
+    fn stores_objects_under_prefix() {
+        let (endpoint, storage) = start_stub();
+        let backend = stub_backend(endpoint, None);
+        let dir = temp_dir("s3");
+
+        let data = (0..1000u32).map(|i| i as u8).collect::<Vec<u8>>();
+        let part_path = dir.join("a.part");
+        fs::write(&part_path, &data).unwrap();
+
+        backend.load().unwrap();
+        backend.upload_file(&part_path).unwrap();
+
+        {
+            let mut storage = storage.lock().unwrap();
+            assert_eq!(storage.objects.get("recloud/a.part"), Some(&data));
+
+            storage.objects.insert("other/b.part".to_string(), vec![]);
+            storage.objects.insert("recloud/nested/c.part".to_string(), vec![]);
+        }
+
+        assert!(backend.check_file("a.part"));
+        assert!(!backend.check_file("b.part"));
+        assert_eq!(backend.list_files().unwrap(), vec!["a.part".to_string()]);
+
+        fs::remove_file(&part_path).unwrap();
+        backend.download_file(&part_path).unwrap();
+        assert_eq!(fs::read(&part_path).unwrap(), data);
+
+        assert_eq!(backend.download_file_range(&part_path, 900, 500).unwrap(), &data[900..]);
+        assert!(backend.download_file_range(&part_path, 1000, 10).unwrap().is_empty());
+
+        backend.remove_file(&part_path).unwrap();
+        assert!(!backend.check_file("a.part"));
+        assert!(matches!(backend.download_file(&part_path), Err(CloudError::FileNotFound { .. })));
+        assert!(matches!(backend.remove_file(&part_path), Err(CloudError::FileNotFound { .. })));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn uploads_large_files_in_parts() {
+        let (endpoint, storage) = start_stub();
+        let backend = stub_backend(endpoint, Some(1024));
+        let dir = temp_dir("s3_multipart");
+
+        // Части multipart загрузки не меньше MIN_MULTIPART_PART_SIZE, поэтому файл делится на две части
+        let data = (0..MIN_MULTIPART_PART_SIZE as u32 + 100)
+            .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+            .collect::<Vec<u8>>();
+        let part_path = dir.join("large.part");
+        fs::write(&part_path, &data).unwrap();
+
+        backend.upload_file(&part_path).unwrap();
+
+        let storage = storage.lock().unwrap();
+        assert_eq!(storage.objects.get("recloud/large.part"), Some(&data));
+        assert!(storage.uploads.is_empty());
+        assert_eq!(storage.next_upload_id, 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
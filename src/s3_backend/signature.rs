@@ -0,0 +1,265 @@
+use std::time::SystemTime;
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// Хеш тела запроса, которое не подписывается (тело передается потоком)
+pub const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Данные для подписи запроса по схеме *AWS Signature Version 4*
+pub struct RequestSignature<'a> {
+    pub access_key: &'a str,
+    pub secret_key: &'a str,
+    pub region: &'a str,
+    /// Имя сервиса в области подписи, для S3 - *s3*
+    pub service: &'a str,
+    pub method: &'a str,
+    pub host: &'a str,
+    /// Путь запроса, уже закодированный *uri_encode*
+    pub canonical_uri: &'a str,
+    /// Параметры запроса в виде пар ключ-значение без кодирования
+    pub query: &'a [(&'a str, String)],
+    pub payload_hash: &'a str,
+}
+
+/// Заголовки, которые необходимо добавить к подписанному запросу
+pub struct SignedHeaders {
+    pub authorization: String,
+    pub amz_date: String,
+}
+
+impl RequestSignature<'_> {
+
+    pub fn sign(&self, time: SystemTime) -> SignedHeaders {
+        let amz_date = format_amz_date(time);
+        let signature = self.signature(&amz_date);
+
+        SignedHeaders {
+            authorization: format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                self.access_key, self.scope(&amz_date), self.signed_headers(), signature
+            ),
+            amz_date,
+        }
+    }
+
+    /// Подписываемые заголовки по алфавиту. S3 требует подписывать хеш тела запроса
+    fn headers(&self, amz_date: &str) -> Vec<(&str, String)> {
+        let mut headers = vec![("host", self.host.to_string())];
+
+        if self.service == "s3" {
+            headers.push(("x-amz-content-sha256", self.payload_hash.to_string()));
+        }
+
+        headers.push(("x-amz-date", amz_date.to_string()));
+
+        headers
+    }
+
+    fn signed_headers(&self) -> String {
+        self.headers("")
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+
+    fn scope(&self, amz_date: &str) -> String {
+        format!("{}/{}/{}/aws4_request", &amz_date[..8], self.region, self.service)
+    }
+
+    fn canonical_request(&self, amz_date: &str) -> String {
+        let canonical_headers = self.headers(amz_date)
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect::<String>();
+
+        format!(
+            "{}\n{}\n{}\n{}\n{}\n{}",
+            self.method,
+            self.canonical_uri,
+            canonical_query(self.query),
+            canonical_headers,
+            self.signed_headers(),
+            self.payload_hash
+        )
+    }
+
+    fn string_to_sign(&self, amz_date: &str) -> String {
+        format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            self.scope(amz_date),
+            sha256_hex(self.canonical_request(amz_date).as_bytes())
+        )
+    }
+
+    fn signature(&self, amz_date: &str) -> String {
+        let date = &amz_date[..8];
+
+        let signing_key = [self.region, self.service, "aws4_request"]
+            .iter()
+            .fold(
+                hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), date.as_bytes()),
+                |key, part| hmac_sha256(&key, part.as_bytes())
+            );
+
+        to_hex(&hmac_sha256(&signing_key, self.string_to_sign(amz_date).as_bytes()))
+    }
+}
+
+/// Кодирование строки по правилам *SigV4*, `/` кодируется только при *encode_slash*
+pub fn uri_encode(input: &str, encode_slash: bool) -> String {
+    input
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' =>
+                (byte as char).to_string(),
+            b'/' if !encode_slash => "/".to_string(),
+            _ => format!("%{:02X}", byte)
+        })
+        .collect()
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    to_hex(&Sha256::digest(bytes))
+}
+
+fn canonical_query(query: &[(&str, String)]) -> String {
+    let mut encoded_query = query
+        .iter()
+        .map(|(key, value)| (uri_encode(key, true), uri_encode(value, true)))
+        .collect::<Vec<_>>();
+    encoded_query.sort();
+
+    encoded_query
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC принимает ключ любой длины");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Время в формате *YYYYMMDD'T'HHMMSS'Z'* по UTC
+fn format_amz_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let days = (secs / 86_400) as i64;
+    let secs_of_day = secs % 86_400;
+
+    // Перевод дней от начала эпохи в григорианскую дату (алгоритм Хиннанта)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+
+    /// Векторы *get-vanilla* и *post-vanilla-query* из набора тестов *AWS Signature Version 4*
+    const ACCESS_KEY: &str = "AKIDEXAMPLE";
+    const SECRET_KEY: &str = "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY";
+    const AMZ_DATE: &str = "20150830T123600Z";
+
+    fn vanilla_request<'a>(method: &'a str, query: &'a [(&'a str, String)], payload_hash: &'a str) -> RequestSignature<'a> {
+        RequestSignature {
+            access_key: ACCESS_KEY,
+            secret_key: SECRET_KEY,
+            region: "us-east-1",
+            service: "service",
+            method,
+            host: "example.amazonaws.com",
+            canonical_uri: "/",
+            query,
+            payload_hash,
+        }
+    }
+
+    #[test]
+    fn get_vanilla() {
+        let payload_hash = sha256_hex(b"");
+        let request = vanilla_request("GET", &[], &payload_hash);
+
+        assert_eq!(
+            request.canonical_request(AMZ_DATE),
+            "GET\n/\n\nhost:example.amazonaws.com\nx-amz-date:20150830T123600Z\n\nhost;x-amz-date\n\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            request.string_to_sign(AMZ_DATE),
+            "AWS4-HMAC-SHA256\n20150830T123600Z\n20150830/us-east-1/service/aws4_request\n\
+             bb579772317eb040ac9ed261061d46c1f17a8133879d6129b6e1c25292927e63"
+        );
+        assert_eq!(
+            request.signature(AMZ_DATE),
+            "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"
+        );
+    }
+
+    #[test]
+    fn post_vanilla_query() {
+        let payload_hash = sha256_hex(b"");
+        let query = [("Param1", "value1".to_string())];
+        let request = vanilla_request("POST", &query, &payload_hash);
+
+        assert_eq!(
+            request.canonical_request(AMZ_DATE),
+            "POST\n/\nParam1=value1\nhost:example.amazonaws.com\nx-amz-date:20150830T123600Z\n\nhost;x-amz-date\n\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            request.string_to_sign(AMZ_DATE),
+            "AWS4-HMAC-SHA256\n20150830T123600Z\n20150830/us-east-1/service/aws4_request\n\
+             9d659678c1756bb3113e2ce898845a0a79dbbc57b740555917687f1b3340fbbd"
+        );
+
+        let signed_headers = request.sign(SystemTime::UNIX_EPOCH + Duration::from_secs(1_440_938_160));
+
+        assert_eq!(signed_headers.amz_date, AMZ_DATE);
+        assert_eq!(
+            signed_headers.authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=28038455d6de14eafc1f9222cf5aa6f1a96197d7deb8263271d420d138af7f11"
+        );
+    }
+
+    #[test]
+    fn s3_signs_payload_hash() {
+        let request = RequestSignature { service: "s3", ..vanilla_request("PUT", &[], UNSIGNED_PAYLOAD) };
+
+        assert_eq!(request.signed_headers(), "host;x-amz-content-sha256;x-amz-date");
+        assert!(request.canonical_request(AMZ_DATE).contains("\nx-amz-content-sha256:UNSIGNED-PAYLOAD\n"));
+    }
+}
@@ -31,12 +31,12 @@
 use std::collections::HashMap;
 use std::fs;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 use serde_json::{Value, json};
 use crate::cloud::error::CloudError;
-use crate::CloudBackend;
+use crate::{BackendCapabilities, CloudBackend};
 use crate::core::TDApp;
 
 // pub struct IO {
@@ -49,9 +49,20 @@ pub struct TelegramBackend {
     telegram_app: TDApp,
     cloud_chat_id: i64,
     cloud_chat: Value,
+    /// Telegram Premium увеличивает максимальный размер файла
+    is_premium: bool,
     files: RwLock<HashMap<String, (i64, Value)>>,
+    /// TDLib не позволяет получать обновления из нескольких потоков одновременно,
+    /// поэтому запросы к клиенту выполняются по одному
+    td_lock: Mutex<()>,
 }
 
+/// Максимальный размер файла в Telegram (2000 MiB)
+const MAX_FILE_SIZE: u64 = 2_097_152_000;
+
+/// Максимальный размер файла в Telegram с Premium подпиской (4000 MiB)
+const MAX_PREMIUM_FILE_SIZE: u64 = 4_194_304_000;
+
 impl TelegramBackend {
 
     fn get_cloud_chat_id(app: &TDApp) -> i64 {
@@ -83,6 +94,16 @@ impl TelegramBackend {
         return cloud_chat_id;
     }
 
+    fn file_name(file_path: &Path) -> Result<String, CloudError> {
+        file_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or(CloudError::IOError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Путь {} не содержит имени файла", file_path.display())
+            )))
+    }
+
     fn get_files_from_message(messages: Vec<Value>) -> HashMap<String, (i64, Value)> {
 
         let mut files_hm = HashMap::with_capacity(messages.len());
@@ -121,6 +142,8 @@ impl CloudBackend for TelegramBackend {
         app.account_auth().expect("Ошибка авторизации в Telegram");
         app.skip_all_update(0.1);
 
+        let is_premium = app.get_me()["is_premium"].as_bool().unwrap_or(false);
+
         let cloud_chat_id: i64 = TelegramBackend::get_cloud_chat_id(&app);
         let cloud_chat = dbg!(app.get_chat(cloud_chat_id));
         let messages = dbg!(app.load_all_messages(cloud_chat_id));
@@ -130,139 +153,129 @@ impl CloudBackend for TelegramBackend {
             telegram_app: app,
             cloud_chat_id,
             cloud_chat,
+            is_premium,
             files: RwLock::new(files),
+            td_lock: Mutex::new(()),
         }
     }
 
     fn load(&self) -> Result<(), CloudError> {
-        todo!()
-    }
+        let _td_guard = self.td_lock.lock().unwrap();
+        let messages = self.telegram_app.load_all_messages(self.cloud_chat_id);
 
-    fn upload_file(&self, file_path: &Path) -> Result<(), CloudError> {
-        todo!()
-    }
+        *self.files.write().unwrap() = TelegramBackend::get_files_from_message(messages);
 
-    fn download_file(&self, file_path: &Path) -> Result<(), CloudError> {
-        todo!()
-    }
-
-    fn remove_file(&self, file_path: &Path) -> Result<(), CloudError> {
-        todo!()
-    }
-
-    fn check_file(&self, file_name: &str) -> bool {
-        todo!()
-    }
-
-    fn close(self) -> Result<(), CloudError> {
-        todo!()
+        Ok(())
     }
-}
-
-
-/*
-
-#[async_trait::async_trait]
-impl AsyncCloudBackend for TelegramBackend {
-    fn create() -> Self {
-        // Отключение вывод логов в консоль
-        futures::executor::block_on(TDApp::execute_query(&json!({
-            "@type": "setLogVerbosityLevel",
-            "new_verbosity_level": 0
-        }).to_string())).unwrap();
-
-        // Создание телеграм клиента
-        let mut app = futures::executor::block_on(TDApp::create());
-        futures::executor::block_on(app.account_auth()).expect("Ошибка авторизации в Telegram");
-        futures::executor::block_on(app.skip_all_update(0.1));
-
-        let cloud_chat_id: i64;
-
-        if let Ok(mut f) = File::open("mod.json") {
-            let mut file_str = String::new();
-            f.read_to_string(&mut file_str).unwrap();
-
-            let json_file = serde_json::from_str::<Value>(&file_str)
-                .expect("mod.json содержит невалидный json");
 
-            cloud_chat_id = json_file["cloud_chat_id"].as_i64().unwrap();
-
-        } else {
-
-            let new_cloud_chat = futures::executor::block_on(app.create_chat());
-
-            cloud_chat_id = new_cloud_chat["id"].as_i64().unwrap();
-
-            let json_file = json!({
-                "cloud_chat_id": cloud_chat_id,
-                "cloud_chat": new_cloud_chat
-            }).to_string();
-
-            fs::write("mod.json", json_file.as_bytes()).unwrap();
-        }
-
-        let cloud_chat = dbg!(futures::executor::block_on(app.get_chat(cloud_chat_id)));
-
-        let messages = dbg!(futures::executor::block_on(app.load_all_messages(cloud_chat_id)));
+    fn upload_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let file_name = TelegramBackend::file_name(file_path)?;
 
-        //dbg!(app.get_message(messages[5], CLOUD_CHAT_ID).await);
-        //println!("{}", serde_json::to_string(&messages[0..5]).unwrap());
+        let _td_guard = self.td_lock.lock().unwrap();
+        let (file_id, message) = self.telegram_app
+            .upload_file(file_path, self.cloud_chat_id)
+            .map_err(|_| CloudError::BackendError {
+                message: format!("Не удалось загрузить файл {} в Telegram", file_path.display())
+            })?;
 
-        return Self {
-            cloud_chat_id,
-            telegram: app,
-            cloud_chat,
-            files: AsyncRwLock::new(TelegramBackend::get_files_from_message(messages)),
-        }
-    }
-    async fn load_backend(&self) -> Result<(), CloudError> {
-        // let res_send_query = self.telegram.borrow_mut().send_query(&json!({
-        //     "@type": "ddd",
-        //     "chat_id": CLOUD_CHAT_ID
-        // }).to_string());
+        self.files.write().unwrap().insert(file_name, (file_id, message));
 
         Ok(())
     }
 
-    async fn upload_file(&self, file_path: &Path) -> Result<(), CloudError> {
-        let result_upload = self.telegram.upload_file(file_path, self.cloud_chat_id).await;
-        let file_name = file_path.file_name().unwrap().to_string_lossy().to_string();
+    fn download_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let file_name = TelegramBackend::file_name(file_path)?;
+
+        let file_id = self.files
+            .read()
+            .unwrap()
+            .get(&file_name)
+            .map(|(file_id, _)| *file_id)
+            .ok_or(CloudError::FileNotFound { file_name })?;
+
+        let _td_guard = self.td_lock.lock().unwrap();
+        let downloaded_file = self.telegram_app
+            .download_file(file_id)
+            .map_err(|_| CloudError::BackendError {
+                message: format!("Не удалось скачать файл {} из Telegram", file_path.display())
+            })?;
+
+        // TDLib присылает либо *file*, либо *updateFile* с вложенным файлом
+        let local_file = if downloaded_file["@type"] == "updateFile" {
+            &downloaded_file["file"]["local"]
+        } else {
+            &downloaded_file["local"]
+        };
+
+        let local_path = local_file["path"]
+            .as_str()
+            .map(PathBuf::from)
+            .ok_or(CloudError::BackendError {
+                message: format!("TDLib не прислал путь к скачанному файлу: {}", downloaded_file)
+            })?;
+
+        // TDLib сохраняет файл в своей папке и может изменить его имя
+        if local_path != file_path {
+            fs::copy(&local_path, file_path)?;
+        }
 
-        self.files.write().await.insert(file_name, result_upload);
         Ok(())
     }
 
-    async fn download_file(&self, file_path: &Path) -> Result<(), CloudError> {
-        let file_name = file_path.file_name().unwrap().to_string_lossy().to_string();
-        let file_id = self.files.read().await.get(&file_name).unwrap().0;
-
-        let download_file = self.telegram.download_file(file_id).await.unwrap();
+    fn remove_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let file_name = TelegramBackend::file_name(file_path)?;
+
+        let message = self.files
+            .read()
+            .unwrap()
+            .get(&file_name)
+            .map(|(_, message)| message.clone())
+            .ok_or(CloudError::FileNotFound { file_name: file_name.clone() })?;
+
+        let message_id = message["id"]
+            .as_i64()
+            .ok_or(CloudError::BackendError {
+                message: format!("Сообщение с файлом не содержит id: {}", message)
+            })?;
+
+        let _td_guard = self.td_lock.lock().unwrap();
+        self.telegram_app
+            .delete_message(self.cloud_chat_id, &[message_id])
+            .map_err(|_| CloudError::BackendError {
+                message: format!("Не удалось удалить сообщение {}", message_id)
+            })?;
+
+        // Файл пропадает из списка только после удаления сообщения
+        self.files.write().unwrap().remove(&file_name);
 
         Ok(())
     }
 
-    async fn remove_file(&self, file_path: &Path) -> Result<(), CloudError> {
-        let file_name = file_path.file_name().unwrap().to_string_lossy().to_string();
-
-        let (file_id, remove_message) = self.files.write().await.remove(&file_name).unwrap();
-
-        let remove_message_id = remove_message["message"]["id"].as_i64().unwrap();
-
-        println!("{}", remove_message);
-
-        self.telegram
-            .delete_message(self.cloud_chat_id, &vec![remove_message_id]).await
-            .unwrap();
-
-        Ok(())
+    fn check_file(&self, file_name: &str) -> bool {
+        self.files.read().unwrap().contains_key(file_name)
     }
 
-    async fn check_file(&self, file_name: &str) -> bool {
-        todo!()
+    fn close(self) -> Result<(), CloudError> {
+        let _td_guard = self.td_lock.lock().unwrap();
+        self.telegram_app
+            .close()
+            .map_err(|e| CloudError::BackendError {
+                message: format!("Не удалось закрыть TDLib клиент: {:?}", e)
+            })
     }
 
-    async fn close(self) -> Result<(), CloudError> {
-        todo!()
+    /// Запросы к TDLib выполняются по одному, поэтому параллельные загрузки не ускоряют работу
+    fn capabilities(&self) -> BackendCapabilities {
+        let max_object_size = match self.is_premium {
+            true => MAX_PREMIUM_FILE_SIZE,
+            false => MAX_FILE_SIZE,
+        };
+
+        BackendCapabilities {
+            max_object_size,
+            preferred_part_size: max_object_size,
+            max_concurrent_transfers: 1,
+            ranged_reads: false,
+        }
     }
-}
-*/
\ No newline at end of file
+}
\ No newline at end of file
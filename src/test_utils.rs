@@ -0,0 +1,46 @@
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use crate::cloud::CloudOptions;
+use crate::file::Options as SeparationOptions;
+
+/// Новая временная папка теста, удаляется самим тестом
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("recloud_{}_{}", name, Uuid::new_v4()));
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+/// Опции облака в папке теста: рабочая папка *work*, VFS в *vfs.json*
+pub fn cloud_options(dir: &Path, file_options: SeparationOptions) -> CloudOptions {
+    CloudOptions {
+        work_dir: dir.join("work"),
+        vfs_path: dir.join("vfs.json"),
+        file_options,
+        ..Default::default()
+    }
+}
+
+/// Псевдослучайные байты, которые почти не сжимаются
+pub fn test_data(len: usize) -> Vec<u8> {
+    (0..len as u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 24) as u8)
+        .collect()
+}
+
+/// Байты из 64 значений: энтропия ниже порога уже сжатых данных, поэтому части сжимаются
+pub fn compressible_data(len: usize) -> Vec<u8> {
+    test_data(len).into_iter().map(|byte| byte & 0x3f).collect()
+}
+
+/// Выполнение асинхронных методов облака в однопоточном *tokio* runtime
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
@@ -5,9 +5,13 @@ pub enum VFSError {
     NodeNotFound,
     FolderNotFound,
     FileNotFound,
-    NodeNotRemove(Box<dyn std::error::Error + 'static>),
+    NodeNotRemove(Box<dyn std::error::Error + Send + Sync + 'static>),
     FileAlreadyExists,
     FolderAlreadyExists,
+    /// В папке есть другой файл с тем же именем после замены неверных байтов UTF-8
+    FileNameCollision {
+        file_name: String,
+    },
     PathError {
         message: String,
     },
@@ -1,10 +1,12 @@
 pub mod error;
 
-use std::{collections::HashMap, ffi::OsStr, fmt, fs, io, path::{Path, PathBuf}};
+use std::{collections::{HashMap, HashSet}, ffi::{OsStr, OsString}, fmt, fs, io, path::{Path, PathBuf}};
 
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Deserializer};
 
 use error::VFSError;
+use crate::file::metadata::{self, FileMetadata};
+use crate::file::os_string_from_bytes;
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct FSOption {
@@ -12,8 +14,44 @@ pub struct FSOption {
     owner: String
 }
 
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct Metadata;
+/// Сведения об исходном файле. У папок и у файлов, загруженных до появления сведений, поля пустые
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// Время изменения: секунды и наносекунды от начала эпохи Unix
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modified: Option<(i64, u32)>,
+    /// Права доступа Unix
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    /// Расширенные атрибуты: имя и значение
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl From<&FileMetadata> for Metadata {
+    fn from(value: &FileMetadata) -> Self {
+        Self {
+            size: Some(value.size),
+            modified: value.modified.map(metadata::to_unix_time),
+            mode: value.mode,
+            uid: value.uid,
+            gid: value.gid,
+            xattrs: value.xattrs.clone(),
+        }
+    }
+}
+
+/// В старых *vfs.json* сведения записаны как *null*
+fn deserialize_metadata<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Metadata, D::Error> {
+    Ok(Option::<Metadata>::deserialize(deserializer)?.unwrap_or_default())
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum FileSystemNode {
@@ -25,22 +63,81 @@ pub enum FileSystemNode {
 pub struct VFSFile {
     pub name: String,
     pub extension: String,
+    /// Полное имя файла, под ним файл хранится в папке. Пустое у файлов, загруженных до его появления
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub file_name: String,
+    /// Байты имени, если оно не в UTF-8. В *file_name* тогда неверные байты заменены
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_name_bytes: Option<Vec<u8>>,
     pub build_metafile: String,
-    pub parts_name: Vec<String>,
-    pub metadata : Metadata
+    /// Имена частей файла по порядку, одна часть может встречаться несколько раз
+    #[serde(alias = "parts_name")]
+    pub chunks: Vec<String>,
+    /// Паритетные части, по которым восстанавливаются потерянные части
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parity_parts_name: Vec<String>,
+    /// Часть-опись с порядком частей, только у файлов с границами частей по содержимому
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest_part: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_metadata")]
+    pub metadata : Metadata,
+    /// Имена реплик, хранящих каждую часть и сборочный файл
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub replicas: HashMap<String, Vec<String>>,
+}
+
+impl VFSFile {
+
+    /// Имя узла файла в папке
+    pub fn node_name(&self) -> &str {
+        match self.file_name.is_empty() {
+            true => &self.name,
+            false => &self.file_name,
+        }
+    }
+
+    /// Имя файла байт в байт, у старых файлов имя и расширение через точку
+    pub fn os_file_name(&self) -> OsString {
+        match (&self.file_name_bytes, self.file_name.is_empty()) {
+            (Some(bytes), _) => os_string_from_bytes(bytes.clone()),
+            (None, false) => self.file_name.clone().into(),
+            (None, true) => format!("{}.{}", self.name, self.extension).into(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VFSFolder {
     pub name: String,
+    #[serde(default, deserialize_with = "deserialize_metadata")]
     pub metadata : Metadata,
     pub children: HashMap<String, FileSystemNode>
 }
 
+impl VFSFolder {
+
+    /// Можно ли добавить файл в папку. Узлы хранятся под именем с замененными неверными байтами UTF-8,
+    /// поэтому разные имена не в UTF-8 могут совпасть, такой файл отклоняется
+    pub fn check_new_file(&self, file: &VFSFile) -> Result<(), VFSError> {
+        let Some(node) = self.children.get(file.node_name()) else {
+            return Ok(());
+        };
+
+        match node {
+            FileSystemNode::File(existing) if existing.file_name_bytes != file.file_name_bytes =>
+                Err(VFSError::FileNameCollision { file_name: file.file_name.clone() }),
+            _ => Err(VFSError::FileAlreadyExists),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VirtualFileSystem {
     pub dirs: HashMap<String, FileSystemNode>,
     pub options: FSOption,
+    /// Индекс частей: кол-во файлов, ссылающихся на каждую часть в облаке
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub chunk_index: HashMap<String, usize>,
 }
 
 impl VirtualFileSystem {
@@ -58,13 +155,19 @@ impl VirtualFileSystem {
                 )
             ]),
             options,
+            chunk_index: HashMap::new(),
         }
     }
 
     /// Сохраняет вирутальную файловую систему в файл *vfs.json*
     pub fn save_vfs(&self) -> io::Result<()> {
+        self.save_vfs_to(Path::new("vfs.json"))
+    }
+
+    /// Сохраняет вирутальную файловую систему в файл *vfs_path*
+    pub fn save_vfs_to(&self, vfs_path: &Path) -> io::Result<()> {
         let vfs_json = serde_json::to_string(&self).unwrap();
-        fs::write("vfs.json", vfs_json.as_bytes())?;
+        fs::write(vfs_path, vfs_json.as_bytes())?;
 
         Ok(())
     }
@@ -122,15 +225,17 @@ impl VirtualFileSystem {
         return match folder_for_add {
             FileSystemNode::Folder(folder) => {
 
-                if folder.children.contains_key(&file.name) {
-                    return Err(VFSError::FileAlreadyExists);
-                }
+                folder.check_new_file(&file)?;
+
+                let chunks = file.chunks.clone();
 
                 folder.children.insert(
-                    file.name.clone(),
+                    file.node_name().to_string(),
                     FileSystemNode::File(file)
                 );
 
+                self.retain_chunks(&chunks);
+
                 Ok(())
             }
             FileSystemNode::File { .. } =>
@@ -166,21 +271,70 @@ impl VirtualFileSystem {
             .iter()
             .last()
             .ok_or(VFSError::PathError {message: String::from("Элемент удаления не найден в пути")})?
-            .to_string_lossy()
-            .to_string();
+            .to_os_string();
         path.pop();
 
         let folder = self.get_mut_folder(&path)?;
 
-        folder.children.remove(&remove_name).ok_or(
-            VFSError::NodeNotRemove(
+        let node_key = remove_name.to_string_lossy().to_string();
+
+        // Узел с тем же именем после замены неверных байтов, но другим именем на диске, не удаляется
+        let removed_node = folder.children
+            .get(&node_key)
+            .is_some_and(|node| node_matches(node, &remove_name))
+            .then(|| folder.children.remove(&node_key))
+            .flatten()
+            .ok_or(VFSError::NodeNotRemove(
                 Box::new(VFSError::NodeNotFound)
-            )
-        )?;
+            ))?;
+
+        let mut removed_files = vec![];
+        collect_files(&removed_node, &mut removed_files);
+
+        for file in removed_files {
+            self.release_chunks(&file.chunks);
+        }
 
         Ok(())
     }
 
+    /// Виртуальные пути всех файлов
+    pub fn file_paths(&self) -> Vec<PathBuf> {
+        let mut file_paths = vec![];
+
+        if let Some(root) = self.dirs.get("fs:") {
+            collect_file_paths(root, PathBuf::from("fs:"), &mut file_paths);
+        }
+
+        file_paths.sort();
+        file_paths
+    }
+
+    /// Хранится ли часть в облаке по данным индекса частей
+    pub fn has_chunk(&self, chunk_name: &str) -> bool {
+        self.chunk_index.contains_key(chunk_name)
+    }
+
+    /// Учитывает ссылки файла на части
+    fn retain_chunks(&mut self, chunks: &[String]) {
+        for chunk_name in unique_chunks(chunks) {
+            *self.chunk_index.entry(chunk_name.clone()).or_insert(0) += 1;
+        }
+    }
+
+    /// Снимает ссылки файла на части, части без ссылок удаляются из индекса
+    fn release_chunks(&mut self, chunks: &[String]) {
+        for chunk_name in unique_chunks(chunks) {
+            if let Some(count) = self.chunk_index.get_mut(chunk_name) {
+                *count -= 1;
+
+                if *count == 0 {
+                    self.chunk_index.remove(chunk_name);
+                }
+            }
+        }
+    }
+
     /// Получение мутабельного узла виртуального пути
     fn get_mut_fs_node(&mut self, path: &Path) -> Result<&mut FileSystemNode, VFSError> {
 
@@ -202,14 +356,13 @@ impl VirtualFileSystem {
 
         for path_part in path_iter {
 
-            let path_part = &*path_part.to_string_lossy();
-
             match current_node {
 
                 &mut FileSystemNode::Folder (ref mut folder) => {
 
                     current_node = folder.children
-                        .get_mut(path_part)
+                        .get_mut(&*path_part.to_string_lossy())
+                        .filter(|node| node_matches(node, path_part))
                         .ok_or(VFSError::PathError {
                             message: String::from("VFS не содержи узла пути")
                         })?;
@@ -244,14 +397,13 @@ impl VirtualFileSystem {
 
         for path_part in path_iter {
 
-            let path_part = &*path_part.to_string_lossy();
-
             match current_node {
 
                 &FileSystemNode::Folder (ref folder) => {
 
                     current_node = folder.children
-                        .get(path_part)
+                        .get(&*path_part.to_string_lossy())
+                        .filter(|node| node_matches(node, path_part))
                         .ok_or(VFSError::PathError {
                             message: String::from("VFS не содержи узла пути")
                         })?;
@@ -267,8 +419,120 @@ impl VirtualFileSystem {
     }
 }
 
+/// Части без повторов в порядке первого появления
+pub fn unique_chunks(chunks: &[String]) -> Vec<&String> {
+    let mut seen = HashSet::with_capacity(chunks.len());
+
+    chunks
+        .iter()
+        .filter(|chunk_name| seen.insert(*chunk_name))
+        .collect()
+}
+
+/// Подходит ли узел к имени из пути. Имя не в UTF-8 должно совпасть с именем файла байт в байт,
+/// имя в UTF-8 подходит и к файлу, чье имя с замененными неверными байтами совпало с ним
+fn node_matches(node: &FileSystemNode, name: &OsStr) -> bool {
+    name.to_str().is_some() || matches!(node, FileSystemNode::File(file) if file.os_file_name() == name)
+}
+
+/// Все файлы узла, включая файлы вложенных папок
+fn collect_files<'a>(node: &'a FileSystemNode, files: &mut Vec<&'a VFSFile>) {
+    match node {
+        FileSystemNode::File(file) => files.push(file),
+        FileSystemNode::Folder(folder) => folder.children
+            .values()
+            .for_each(|child| collect_files(child, files)),
+    }
+}
+
+/// Виртуальные пути всех файлов узла, включая файлы вложенных папок
+fn collect_file_paths(node: &FileSystemNode, path: PathBuf, file_paths: &mut Vec<PathBuf>) {
+    match node {
+        FileSystemNode::File(_) => file_paths.push(path),
+        FileSystemNode::Folder(folder) => folder.children
+            .iter()
+            .for_each(|(name, child)| collect_file_paths(child, path.join(name), file_paths)),
+    }
+}
+
 impl fmt::Display for VirtualFileSystem {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", serde_json::to_string(&self).unwrap())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::os_str_bytes;
+
+    /// Файл VFS с именем как у загруженного файла
+    fn vfs_file(file_name: &OsStr, chunk_name: &str) -> VFSFile {
+        let (name, extension) = crate::file::split_file_name(file_name);
+
+        VFSFile {
+            name,
+            extension,
+            file_name: file_name.to_string_lossy().to_string(),
+            file_name_bytes: file_name.to_str().is_none().then(|| os_str_bytes(file_name)),
+            build_metafile: format!("{}.meta", chunk_name),
+            chunks: vec![format!("{}.part", chunk_name)],
+            parity_parts_name: vec![],
+            manifest_part: None,
+            metadata: Metadata::default(),
+            replicas: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn files_without_extension_and_with_several_dots() {
+        let mut vfs = VirtualFileSystem::new(FSOption::default());
+
+        for (file_name, name, extension) in [("README", "README", ""), ("a.tar.gz", "a.tar", "gz"), (".bashrc", ".bashrc", "")] {
+            vfs.add_file(Path::new("fs:"), vfs_file(OsStr::new(file_name), file_name)).unwrap();
+
+            let file = vfs.get_file(&Path::new("fs:").join(file_name)).unwrap();
+            assert_eq!((file.name.as_str(), file.extension.as_str()), (name, extension));
+            assert_eq!(file.os_file_name(), file_name);
+        }
+
+        assert_eq!(vfs.file_paths(), ["fs:/.bashrc", "fs:/README", "fs:/a.tar.gz"].map(PathBuf::from));
+        assert!(vfs.get_file(Path::new("fs:/a.tar")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_do_not_collide() {
+        let first_name = os_string_from_bytes(b"data\xFF.bin".to_vec());
+        let second_name = os_string_from_bytes(b"data\xFE.bin".to_vec());
+
+        let mut vfs = VirtualFileSystem::new(FSOption::default());
+        vfs.add_file(Path::new("fs:"), vfs_file(&first_name, "first")).unwrap();
+
+        let file = vfs.get_file(&Path::new("fs:").join(&first_name)).unwrap();
+        assert_eq!(file.os_file_name(), first_name);
+        assert_eq!(file.node_name(), "data\u{FFFD}.bin");
+
+        // Путь из списка файлов ведет к файлу по имени с замененными байтами
+        assert_eq!(vfs.file_paths(), [PathBuf::from("fs:/data\u{FFFD}.bin")]);
+        assert!(vfs.get_file(&vfs.file_paths()[0]).is_ok());
+
+        // Другое имя с теми же замененными байтами не находит чужой файл и не добавляется
+        assert!(vfs.get_file(&Path::new("fs:").join(&second_name)).is_err());
+
+        for colliding_name in [second_name.as_os_str(), OsStr::new("data\u{FFFD}.bin")] {
+            let res = vfs.add_file(Path::new("fs:"), vfs_file(colliding_name, "second"));
+            assert!(matches!(res, Err(VFSError::FileNameCollision { .. })), "{:?}", res);
+        }
+
+        assert!(matches!(vfs.add_file(Path::new("fs:"), vfs_file(&first_name, "third")), Err(VFSError::FileAlreadyExists)));
+        assert!(!vfs.has_chunk("second.part") && !vfs.has_chunk("third.part"));
+
+        assert!(vfs.remove_node(&Path::new("fs:").join(&second_name)).is_err());
+        assert!(vfs.get_file(&Path::new("fs:").join(&first_name)).is_ok());
+
+        vfs.remove_node(&Path::new("fs:").join(&first_name)).unwrap();
+        assert!(vfs.file_paths().is_empty());
+        assert!(!vfs.has_chunk("first.part"));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,233 @@
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Параметры *Digest* авторизации, присланные сервером в *WWW-Authenticate*
+#[derive(Debug, Clone)]
+pub struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    qop_auth: bool,
+    algorithm: DigestAlgorithm,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum DigestAlgorithm {
+    Md5,
+    Sha256,
+}
+
+impl DigestAlgorithm {
+    fn hash(&self, data: &str) -> String {
+        match self {
+            DigestAlgorithm::Md5 => format!("{:x}", md5::compute(data)),
+            DigestAlgorithm::Sha256 => Sha256::digest(data.as_bytes())
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        }
+    }
+}
+
+impl DigestChallenge {
+
+    /// Разбор заголовка *WWW-Authenticate*, *None* если сервер не предлагает *Digest*
+    pub fn parse(header: &str) -> Option<Self> {
+        let params = header.trim().strip_prefix("Digest")?;
+        let params = parse_params(params);
+
+        let param = |name: &str| params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.clone());
+
+        let algorithm = match param("algorithm").as_deref() {
+            None => DigestAlgorithm::Md5,
+            Some(algorithm) if algorithm.eq_ignore_ascii_case("MD5") => DigestAlgorithm::Md5,
+            Some(algorithm) if algorithm.eq_ignore_ascii_case("SHA-256") => DigestAlgorithm::Sha256,
+            Some(_) => return None,
+        };
+
+        Some(Self {
+            realm: param("realm")?,
+            nonce: param("nonce")?,
+            opaque: param("opaque"),
+            qop_auth: param("qop")
+                .map(|qop| qop.split(',').any(|value| value.trim() == "auth"))
+                .unwrap_or(false),
+            algorithm,
+        })
+    }
+
+    /// Значение заголовка *Authorization* для запроса
+    pub fn authorization(&self, username: &str, password: &str, method: &str, uri: &str, nonce_count: u32) -> String {
+        let cnonce = Uuid::new_v4().simple().to_string();
+
+        self.authorization_with_cnonce(username, password, method, uri, nonce_count, &cnonce)
+    }
+
+    /// Заголовок *Authorization* с заданным *cnonce* клиента
+    fn authorization_with_cnonce(
+        &self,
+        username: &str,
+        password: &str,
+        method: &str,
+        uri: &str,
+        nonce_count: u32,
+        cnonce: &str
+    ) -> String {
+        let ha1 = self.algorithm.hash(&format!("{}:{}:{}", username, self.realm, password));
+        let ha2 = self.algorithm.hash(&format!("{}:{}", method, uri));
+
+        let algorithm = match self.algorithm {
+            DigestAlgorithm::Md5 => "MD5",
+            DigestAlgorithm::Sha256 => "SHA-256",
+        };
+
+        let mut header = format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", algorithm={}",
+            username, self.realm, self.nonce, uri, algorithm
+        );
+
+        if self.qop_auth {
+            let nc = format!("{:08x}", nonce_count);
+            let response = self.algorithm.hash(
+                &format!("{}:{}:{}:{}:auth:{}", ha1, self.nonce, nc, cnonce, ha2)
+            );
+
+            header.push_str(&format!(
+                ", qop=auth, nc={}, cnonce=\"{}\", response=\"{}\"",
+                nc, cnonce, response
+            ));
+        } else {
+            let response = self.algorithm.hash(&format!("{}:{}:{}", ha1, self.nonce, ha2));
+            header.push_str(&format!(", response=\"{}\"", response));
+        }
+
+        if let Some(opaque) = &self.opaque {
+            header.push_str(&format!(", opaque=\"{}\"", opaque));
+        }
+
+        header
+    }
+}
+
+/// Разбор списка *key=value* и *key="value"*, разделенного запятыми
+fn parse_params(input: &str) -> Vec<(String, String)> {
+    let mut params = vec![];
+    let mut chars = input.chars().peekable();
+
+    loop {
+        while chars.next_if(|c| c.is_whitespace() || *c == ',').is_some() {}
+
+        let key = chars
+            .by_ref()
+            .take_while(|c| *c != '=')
+            .collect::<String>()
+            .trim()
+            .to_string();
+
+        if key.is_empty() {
+            return params;
+        }
+
+        let value = if chars.next_if_eq(&'"').is_some() {
+            let mut value = String::new();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => value.extend(chars.next()),
+                    '"' => break,
+                    _ => value.push(c),
+                }
+            }
+            value
+        } else {
+            chars
+                .by_ref()
+                .take_while(|c| *c != ',')
+                .collect::<String>()
+                .trim()
+                .to_string()
+        };
+
+        params.push((key, value));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Пример из RFC 7616, раздел 3.9.1
+    const USERNAME: &str = "Mufasa";
+    const PASSWORD: &str = "Circle of Life";
+    const URI: &str = "/dir/index.html";
+    const CNONCE: &str = "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ";
+    const NONCE: &str = "7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v";
+    const OPAQUE: &str = "FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS";
+
+    fn challenge_header(algorithm: &str, qop: bool) -> String {
+        format!(
+            "Digest realm=\"http-auth@example.org\",{} algorithm={}, nonce=\"{}\", opaque=\"{}\"",
+            if qop { " qop=\"auth, auth-int\"," } else { "" },
+            algorithm,
+            NONCE,
+            OPAQUE
+        )
+    }
+
+    fn expected_authorization(algorithm: &str, qop: bool, response: &str) -> String {
+        format!(
+            "Digest username=\"{}\", realm=\"http-auth@example.org\", nonce=\"{}\", uri=\"{}\", algorithm={}{}, response=\"{}\", opaque=\"{}\"",
+            USERNAME,
+            NONCE,
+            URI,
+            algorithm,
+            if qop { format!(", qop=auth, nc=00000001, cnonce=\"{}\"", CNONCE) } else { String::new() },
+            response,
+            OPAQUE
+        )
+    }
+
+    #[test]
+    fn authorization_matches_rfc_7616_examples() {
+        // Для MD5 с qop ответ вычислен по формуле RFC, в тексте RFC 7616 последние цифры ответа опечатаны
+        let cases = [
+            ("MD5", true, "8ca523f5e9506fed4657c9700eebdbec"),
+            ("SHA-256", true, "753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1"),
+            ("MD5", false, "7b2cc3b30e75b4777ea31027084363fd"),
+            ("SHA-256", false, "a1306b0595a6c7fe96c448631fb5cfbd5107bd1fe1da729d978dd7446b812363"),
+        ];
+
+        for (algorithm, qop, response) in cases {
+            let challenge = DigestChallenge::parse(&challenge_header(algorithm, qop)).unwrap();
+
+            assert_eq!(challenge.realm, "http-auth@example.org");
+            assert_eq!(challenge.nonce, NONCE);
+            assert_eq!(challenge.opaque.as_deref(), Some(OPAQUE));
+            assert_eq!(challenge.qop_auth, qop);
+
+            assert_eq!(
+                challenge.authorization_with_cnonce(USERNAME, PASSWORD, "GET", URI, 1, CNONCE),
+                expected_authorization(algorithm, qop, response)
+            );
+        }
+    }
+
+    #[test]
+    fn parse_without_algorithm_uses_md5() {
+        let challenge = DigestChallenge::parse(r#"Digest realm="a\"b", nonce=abc, qop=auth"#).unwrap();
+
+        assert_eq!(challenge.realm, "a\"b");
+        assert_eq!(challenge.nonce, "abc");
+        assert!(challenge.opaque.is_none());
+        assert!(matches!(challenge.algorithm, DigestAlgorithm::Md5));
+    }
+
+    #[test]
+    fn parse_rejects_other_schemes_and_algorithms() {
+        assert!(DigestChallenge::parse(r#"Basic realm="http-auth@example.org""#).is_none());
+        assert!(DigestChallenge::parse(&challenge_header("SHA-512-256", true)).is_none());
+        assert!(DigestChallenge::parse(r#"Digest realm="http-auth@example.org""#).is_none());
+    }
+}
@@ -0,0 +1,579 @@
+mod digest;
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::cloud::error::CloudError;
+use crate::{BackendCapabilities, CloudBackend};
+
+use digest::DigestChallenge;
+
+/// Тело PROPFIND запроса, запрашивается только тип ресурса
+const PROPFIND_BODY: &str =
+    r#"<?xml version="1.0" encoding="utf-8"?><propfind xmlns="DAV:"><prop><resourcetype/></prop></propfind>"#;
+
+/// Способ авторизации на WebDAV сервере
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum WebDavAuth {
+    Basic,
+    Digest,
+}
+
+/// Настройки подключения к WebDAV серверу
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebDavOptions {
+    /// Адрес коллекции, в которой хранятся файлы, например *http://127.0.0.1:8080/recloud/*
+    pub url: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub auth: Option<WebDavAuth>,
+}
+
+/// Тело запроса, которое можно отправить повторно после обновления *Digest* авторизации
+enum Body<'a> {
+    Empty,
+    Xml(&'a str),
+    File(&'a Path),
+}
+
+/// Хранилище частей и сборочных файлов на WebDAV сервере (Nextcloud, *rclone serve webdav* и т.д.)
+#[derive(Debug)]
+pub struct WebDavBackend {
+    agent: ureq::Agent,
+    options: WebDavOptions,
+    digest_challenge: Mutex<Option<DigestChallenge>>,
+    nonce_count: AtomicU32,
+}
+
+impl WebDavBackend {
+
+    pub fn new(options: WebDavOptions) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new().build(),
+            options,
+            digest_challenge: Mutex::new(None),
+            nonce_count: AtomicU32::new(0),
+        }
+    }
+
+    /// Адрес коллекции, всегда оканчивающийся на `/`
+    fn collection_url(&self) -> String {
+        let url = self.options.url.trim_end_matches('/');
+        format!("{}/", url)
+    }
+
+    fn file_url(&self, file_name: &str) -> String {
+        format!("{}{}", self.collection_url(), encode_path_segment(file_name))
+    }
+
+    fn file_name(file_path: &Path) -> Result<String, CloudError> {
+        file_path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or(CloudError::IOError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Путь {} не содержит имени файла", file_path.display())
+            )))
+    }
+
+    /// Создание запроса с заголовком авторизации
+    fn authorized_request(&self, method: &str, url: &str) -> ureq::Request {
+        let request = self.agent.request(method, url);
+
+        let (Some(username), Some(password)) = (&self.options.username, &self.options.password) else {
+            return request;
+        };
+
+        match self.options.auth.unwrap_or(WebDavAuth::Basic) {
+            WebDavAuth::Basic => {
+                let credentials = base64::engine::general_purpose::STANDARD
+                    .encode(format!("{}:{}", username, password));
+
+                request.set("Authorization", &format!("Basic {}", credentials))
+            },
+
+            WebDavAuth::Digest => {
+                let challenge = self.digest_challenge.lock().unwrap();
+
+                match challenge.as_ref() {
+                    Some(challenge) => {
+                        let nonce_count = self.nonce_count.fetch_add(1, Ordering::SeqCst) + 1;
+                        let authorization = challenge.authorization(
+                            username,
+                            password,
+                            method,
+                            request_uri(url),
+                            nonce_count
+                        );
+
+                        request.set("Authorization", &authorization)
+                    },
+                    // Параметры Digest станут известны после первого ответа 401
+                    None => request,
+                }
+            },
+        }
+    }
+
+    /// Отправка запроса, при *Digest* авторизации запрос повторяется после ответа 401.
+    /// Ответ с любым кодом возвращается как есть, ошибкой считается только ошибка соединения
+    fn send(&self, method: &str, url: &str, headers: &[(&str, &str)], body: &Body) -> Result<ureq::Response, CloudError> {
+        let mut challenge_updated = false;
+
+        loop {
+            let mut request = self.authorized_request(method, url);
+
+            for (header, value) in headers {
+                request = request.set(header, value);
+            }
+
+            let result = match body {
+                Body::Empty => request.call(),
+                Body::Xml(xml) => request
+                    .set("Content-Type", "application/xml; charset=utf-8")
+                    .send_string(xml),
+                Body::File(file_path) => {
+                    let file = File::open(file_path)?;
+                    let file_len = file.metadata()?.len();
+
+                    request
+                        .set("Content-Length", &file_len.to_string())
+                        .send(file)
+                },
+            };
+
+            let response = match result {
+                Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+                Err(ureq::Error::Transport(transport)) => return Err(CloudError::BackendError {
+                    message: format!("Ошибка соединения с WebDAV сервером для {}: {}", url, transport)
+                }),
+            };
+
+            if response.status() != 401 || challenge_updated || self.options.auth != Some(WebDavAuth::Digest) {
+                return Ok(response);
+            }
+
+            let challenge = response
+                .all("WWW-Authenticate")
+                .into_iter()
+                .find_map(DigestChallenge::parse);
+
+            if challenge.is_none() {
+                return Ok(response);
+            }
+
+            *self.digest_challenge.lock().unwrap() = challenge;
+            self.nonce_count.store(0, Ordering::SeqCst);
+            challenge_updated = true;
+        }
+    }
+
+    /// Создание коллекции хранилища и всех родительских коллекций
+    fn create_collections(&self) -> Result<(), CloudError> {
+        let collection_url = self.collection_url();
+
+        let (scheme, rest) = collection_url
+            .split_once("://")
+            .unwrap_or(("https", &collection_url));
+        let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+
+        let segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .collect::<Vec<_>>();
+
+        for depth in 1..=segments.len() {
+            let url = format!("{}://{}/{}/", scheme, host, segments[..depth].join("/"));
+
+            let response = self.send("MKCOL", &url, &[], &Body::Empty)?;
+
+            match response.status() {
+                // 405 - коллекция уже существует
+                405 => {},
+                // Родительские коллекции могут быть недоступны для создания (например корень Nextcloud)
+                _ if depth < segments.len() => {},
+                _ => drop(Self::check_status(response, &url)?),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ответ с кодом ошибки превращается в *CloudError*
+    fn check_status(response: ureq::Response, file_name: &str) -> Result<ureq::Response, CloudError> {
+        match response.status() {
+            200..=299 => Ok(response),
+            404 => Err(CloudError::FileNotFound {
+                file_name: file_name.to_string()
+            }),
+            code => Err(CloudError::BackendError {
+                message: format!(
+                    "WebDAV сервер вернул код {} для {}: {}",
+                    code,
+                    file_name,
+                    response.into_string().unwrap_or_default()
+                )
+            }),
+        }
+    }
+}
+
+impl CloudBackend for WebDavBackend {
+    fn create(_input: impl Read, _output: impl Write) -> Self {
+
+        let options_json = fs::read_to_string("webdav.json")
+            .expect("Не найден файл настроек webdav.json");
+
+        let options = serde_json::from_str::<WebDavOptions>(&options_json)
+            .expect("webdav.json содержит невалидный json");
+
+        Self::new(options)
+    }
+
+    fn load(&self) -> Result<(), CloudError> {
+        self.create_collections()
+    }
+
+    fn upload_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let file_name = Self::file_name(file_path)?;
+        let url = self.file_url(&file_name);
+
+        let mut response = self.send("PUT", &url, &[], &Body::File(file_path))?;
+
+        // 409 - коллекция хранилища еще не создана
+        if response.status() == 409 {
+            self.create_collections()?;
+
+            response = self.send("PUT", &url, &[], &Body::File(file_path))?;
+        }
+
+        Self::check_status(response, &file_name).map(drop)
+    }
+
+    fn download_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let file_name = Self::file_name(file_path)?;
+
+        let response = Self::check_status(
+            self.send("GET", &self.file_url(&file_name), &[], &Body::Empty)?,
+            &file_name
+        )?;
+
+        let mut file = File::create(file_path)?;
+        io::copy(&mut response.into_reader(), &mut file)?;
+        file.flush()?;
+
+        Ok(())
+    }
+
+    fn download_file_range(&self, file_path: &Path, offset: u64, len: u64) -> Result<Vec<u8>, CloudError> {
+        if len == 0 {
+            return Ok(vec![]);
+        }
+
+        let file_name = Self::file_name(file_path)?;
+        let range = format!("bytes={}-{}", offset, offset + len - 1);
+
+        let response = self.send("GET", &self.file_url(&file_name), &[("Range", &range)], &Body::Empty)?;
+
+        // 416 - диапазон начинается за концом файла
+        if response.status() == 416 {
+            return Ok(vec![]);
+        }
+
+        let response = Self::check_status(response, &file_name)?;
+
+        // Сервер без поддержки диапазонов отвечает 200 и присылает файл целиком
+        let skip = match response.status() {
+            206 => 0,
+            _ => offset,
+        };
+
+        let mut reader = response.into_reader();
+        io::copy(&mut (&mut reader).take(skip), &mut io::sink())?;
+
+        let mut bytes = vec![];
+        reader.take(len).read_to_end(&mut bytes)?;
+
+        Ok(bytes)
+    }
+
+    fn remove_file(&self, file_path: &Path) -> Result<(), CloudError> {
+        let file_name = Self::file_name(file_path)?;
+
+        Self::check_status(
+            self.send("DELETE", &self.file_url(&file_name), &[], &Body::Empty)?,
+            &file_name
+        ).map(drop)
+    }
+
+    fn check_file(&self, file_name: &str) -> bool {
+        let response = self
+            .send("PROPFIND", &self.file_url(file_name), &[("Depth", "0")], &Body::Xml(PROPFIND_BODY))
+            .and_then(|response| Self::check_status(response, file_name));
+
+        match response {
+            // 207 Multi-Status, файл существует, если это не коллекция
+            Ok(response) => response
+                .into_string()
+                .map(|xml| !is_collection(&xml))
+                .unwrap_or(false),
+            Err(_) => false,
+        }
+    }
+
+    fn close(self) -> Result<(), CloudError> {
+        Ok(())
+    }
+
+    fn list_files(&self) -> Result<Vec<String>, CloudError> {
+        let url = self.collection_url();
+
+        let xml = Self::check_status(self.send("PROPFIND", &url, &[("Depth", "1")], &Body::Xml(PROPFIND_BODY))?, &url)?
+            .into_string()?;
+
+        Ok(propfind_file_names(&xml))
+    }
+
+    /// Ограничение размера файла зависит от сервера, поэтому не задается
+    fn capabilities(&self) -> BackendCapabilities {
+        BackendCapabilities {
+            ranged_reads: true,
+            ..Default::default()
+        }
+    }
+}
+
+/// Путь и параметры запроса из адреса, используются в *Digest* авторизации
+fn request_uri(url: &str) -> &str {
+    let without_scheme = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+
+    without_scheme
+        .find('/')
+        .map(|ind| &without_scheme[ind..])
+        .unwrap_or("/")
+}
+
+/// Содержит ли *resourcetype* в ответе PROPFIND признак коллекции
+fn is_collection(propfind_xml: &str) -> bool {
+    let Some(start) = propfind_xml.find("resourcetype") else {
+        return false;
+    };
+
+    let resource_type = &propfind_xml[start + "resourcetype".len()..];
+
+    // Пустой *<resourcetype/>* означает обычный файл
+    let end = resource_type
+        .find("resourcetype")
+        .unwrap_or(resource_type.len());
+
+    resource_type[..end].contains("collection")
+}
+
+/// Имена файлов из ответа PROPFIND с *Depth: 1*, сама коллекция и вложенные коллекции пропускаются
+fn propfind_file_names(propfind_xml: &str) -> Vec<String> {
+    // Между открывающим и закрывающим тегом *response* лежит описание одного ресурса
+    propfind_xml
+        .split("response>")
+        .filter(|response| !is_collection(response))
+        .filter_map(|response| {
+            let start = response.find("href>")? + "href>".len();
+            let end = start + response[start..].find('<')?;
+
+            let href = &response[start..end];
+            let file_name = href.trim_end_matches('/').rsplit('/').next()?;
+
+            (!file_name.is_empty()).then(|| decode_path_segment(file_name))
+        })
+        .collect()
+}
+
+/// Раскодирование имени файла из адреса
+fn decode_path_segment(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut ind = 0;
+
+    while ind < bytes.len() {
+        let hex = (bytes[ind] == b'%')
+            .then(|| segment.get(ind + 1..ind + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match hex {
+            Some(byte) => {
+                decoded.push(byte);
+                ind += 3;
+            },
+            None => {
+                decoded.push(bytes[ind]);
+                ind += 1;
+            },
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+/// Кодирование имени файла для подстановки в адрес
+fn encode_path_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' =>
+                (byte as char).to_string(),
+            _ => format!("%{:02X}", byte)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::test_utils::test_data;
+
+    /// Ответ Nextcloud на PROPFIND с *Depth: 1*: коллекция, два файла и вложенная коллекция
+    const NEXTCLOUD_PROPFIND: &str = concat!(
+        r#"<?xml version="1.0"?>"#,
+        r#"<d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns" xmlns:oc="http://owncloud.org/ns" xmlns:nc="http://nextcloud.org/ns">"#,
+        r#"<d:response><d:href>/remote.php/dav/files/admin/recloud/</d:href>"#,
+        r#"<d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>"#,
+        r#"<d:response><d:href>/remote.php/dav/files/admin/recloud/3f2a_0.part</d:href>"#,
+        r#"<d:propstat><d:prop><d:resourcetype/></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>"#,
+        r#"<d:response><d:href>/remote.php/dav/files/admin/recloud/%d0%be%d1%82%d1%87%d0%b5%d1%82%202024.meta</d:href>"#,
+        r#"<d:propstat><d:prop><d:resourcetype/></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>"#,
+        r#"<d:response><d:href>/remote.php/dav/files/admin/recloud/old/</d:href>"#,
+        r#"<d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype></d:prop><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response>"#,
+        r#"</d:multistatus>"#,
+    );
+
+    /// Ответ *rclone serve webdav* на PROPFIND с *Depth: 1*
+    const RCLONE_PROPFIND: &str = concat!(
+        r#"<?xml version="1.0" encoding="UTF-8"?><D:multistatus xmlns:D="DAV:">"#,
+        r#"<D:response><D:href>/recloud/</D:href><D:propstat><D:prop>"#,
+        r#"<D:resourcetype><D:collection xmlns:D="DAV:"/></D:resourcetype></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>"#,
+        r#"<D:response><D:href>/recloud/3f2a_0.part</D:href><D:propstat><D:prop>"#,
+        r#"<D:resourcetype></D:resourcetype></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>"#,
+        r#"<D:response><D:href>/recloud/%D0%BE%D1%82%D1%87%D0%B5%D1%82%202024.meta</D:href><D:propstat><D:prop>"#,
+        r#"<D:resourcetype></D:resourcetype></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>"#,
+        r#"<D:response><D:href>/recloud/old/</D:href><D:propstat><D:prop>"#,
+        r#"<D:resourcetype><D:collection xmlns:D="DAV:"/></D:resourcetype></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>"#,
+        r#"</D:multistatus>"#,
+    );
+
+    #[test]
+    fn propfind_lists_only_files() {
+        for propfind_xml in [NEXTCLOUD_PROPFIND, RCLONE_PROPFIND] {
+            assert_eq!(propfind_file_names(propfind_xml), vec!["3f2a_0.part", "отчет 2024.meta"]);
+        }
+    }
+
+    #[test]
+    fn propfind_depth_zero_detects_collections() {
+        for propfind_xml in [NEXTCLOUD_PROPFIND, RCLONE_PROPFIND] {
+            let responses = propfind_xml.split("response>").collect::<Vec<_>>();
+
+            // Первый ресурс ответа - коллекция, второй - файл
+            assert!(is_collection(responses[1]));
+            assert!(!is_collection(responses[3]));
+        }
+
+        assert!(!is_collection("<D:multistatus xmlns:D=\"DAV:\"></D:multistatus>"));
+    }
+
+    #[test]
+    fn path_segment_round_trip() {
+        for segment in ["3f2a_0.part", "отчет 2024.meta", "100%.chunk", "a+b&c=d#e?f.part", "~_-.", "%zz"] {
+            let encoded = encode_path_segment(segment);
+
+            assert!(encoded.bytes().all(|byte| byte.is_ascii_alphanumeric() || b"-_.~%".contains(&byte)), "{}", encoded);
+            assert_eq!(decode_path_segment(&encoded), segment);
+        }
+
+        assert_eq!(encode_path_segment("a b/c"), "a%20b%2Fc");
+        assert_eq!(decode_path_segment("a%20b%2fc"), "a b/c");
+        assert_eq!(decode_path_segment("100%"), "100%");
+    }
+
+    /// Заглушка WebDAV сервера, отдающая *body* на любой GET. При *ranged* диапазоны отдаются ответом 206,
+    /// иначе файл всегда присылается целиком с кодом 200. Возвращает адрес и заголовки *Range* запросов
+    fn start_stub(body: Vec<u8>, ranged: bool) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/recloud/", listener.local_addr().unwrap());
+        let ranges = Arc::new(Mutex::new(vec![]));
+
+        let stub_ranges = ranges.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else { break };
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut range = None;
+                loop {
+                    let mut line = String::new();
+                    if reader.read_line(&mut line).unwrap_or(0) == 0 || line == "\r\n" {
+                        break;
+                    }
+
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("Range") {
+                            range = Some(value.trim().to_string());
+                        }
+                    }
+                }
+
+                let bounds = range.as_deref()
+                    .and_then(|range| range.strip_prefix("bytes="))
+                    .and_then(|range| range.split_once('-'))
+                    .map(|(start, end)| (start.parse::<usize>().unwrap(), end.parse::<usize>().unwrap()));
+
+                let (status, bytes) = match bounds {
+                    Some((start, _)) if ranged && start >= body.len() => (416, &body[..0]),
+                    Some((start, end)) if ranged => (206, &body[start..(end + 1).min(body.len())]),
+                    _ => (200, body.as_slice()),
+                };
+
+                stub_ranges.lock().unwrap().extend(range);
+
+                let head = format!("HTTP/1.1 {} Stub\r\nConnection: close\r\nContent-Length: {}\r\n\r\n", status, bytes.len());
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(bytes).unwrap();
+            }
+        });
+
+        (url, ranges)
+    }
+
+    #[test]
+    fn download_range_from_server_with_and_without_range_support() {
+        let data = test_data(100_000);
+        // Диапазон возвращается байтами, файл по этому пути не создается
+        let file_path = Path::new("data.part");
+
+        for ranged in [true, false] {
+            let (url, ranges) = start_stub(data.clone(), ranged);
+            let backend = WebDavBackend::new(WebDavOptions { url, username: None, password: None, auth: None });
+
+            for (offset, len) in [(0, 10), (65_000, 2_000), (99_000, 5_000), (100_000, 10), (200_000, 10)] {
+                let start = (offset as usize).min(data.len());
+                let end = (offset + len).min(data.len() as u64) as usize;
+
+                let bytes = backend.download_file_range(file_path, offset, len).unwrap();
+                assert!(bytes == data[start..end], "{}+{}, диапазоны {}", offset, len, ranged);
+            }
+
+            assert!(backend.download_file_range(file_path, 10, 0).unwrap().is_empty());
+            assert_eq!(ranges.lock().unwrap()[1], "bytes=65000-66999");
+        }
+    }
+}